@@ -0,0 +1,86 @@
+use ::io_at::{ReadAt,WriteAt};
+use ::std::fmt;
+use super::{Error,Fs,Result};
+
+bitflags! {
+    /// The FileAttributes field of a File directory entry
+    ///
+    /// Bits 3 and 6-15 are reserved. They are not represented here, but are preserved when
+    /// attributes are changed through `Fs::set_attributes()`.
+    pub struct FileAttributes: u16 {
+        const READ_ONLY = 1 << 0;
+        const HIDDEN = 1 << 1;
+        const SYSTEM = 1 << 2;
+        const DIRECTORY = 1 << 4;
+        const ARCHIVE = 1 << 5;
+    }
+}
+
+impl fmt::Display for FileAttributes {
+    /// Formats as a fixed width string like `d-h-a`, in the order directory, read only, hidden,
+    /// system, archive, with `-` for any attribute that isn't set.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (FileAttributes::DIRECTORY, 'd'),
+            (FileAttributes::READ_ONLY, 'r'),
+            (FileAttributes::HIDDEN, 'h'),
+            (FileAttributes::SYSTEM, 's'),
+            (FileAttributes::ARCHIVE, 'a'),
+        ];
+        for &(a, c) in &flags {
+            write!(f, "{}", if self.contains(a) { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Change the attributes of the file or directory at `path`.
+    ///
+    /// `DIRECTORY` can't be changed, so it must match the entry's current attributes.
+    pub fn set_attributes(&self, path: &str, attributes: FileAttributes) -> Result<()> {
+        self.check_writable()?;
+        let (dir, mut set) = self.locate(path)?;
+        let old = set.attributes();
+        if old.contains(FileAttributes::DIRECTORY) != attributes.contains(FileAttributes::DIRECTORY) {
+            return Err(Error::InvalidInput("the directory attribute can not be changed"));
+        }
+
+        let reserved = set.attributes_raw() & !FileAttributes::all().bits();
+        set.set_attributes_raw(reserved | attributes.bits());
+        if set.attributes() == old {
+            return Ok(());
+        }
+        set.update_checksum();
+        dir.write_set(&set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+
+    #[test]
+    fn set_and_display() {
+        let mut img = Image::new();
+        img.add_file("f", b"abc", false, 3);
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.create_dir("d").unwrap();
+
+        assert_eq!(fs.lookup("f").unwrap().attributes(), FileAttributes::ARCHIVE);
+        assert_eq!(fs.lookup("d").unwrap().attributes(), FileAttributes::DIRECTORY);
+        assert_eq!(format!("{}", fs.lookup("d").unwrap().attributes()), "d----");
+
+        fs.set_attributes("f", FileAttributes::HIDDEN | FileAttributes::SYSTEM).unwrap();
+        let set = fs.lookup("f").unwrap();
+        assert_eq!(set.attributes(), FileAttributes::HIDDEN | FileAttributes::SYSTEM);
+        assert_eq!(format!("{}", set.attributes()), "--hs-");
+        assert_eq!(set.set_checksum(), set.compute_checksum());
+
+        assert!(fs.set_attributes("f", FileAttributes::DIRECTORY).is_err());
+        assert!(fs.set_attributes("d", FileAttributes::READ_ONLY).is_err());
+        fs.set_attributes("d", FileAttributes::DIRECTORY | FileAttributes::READ_ONLY).unwrap();
+        assert!(fs.lookup("d").unwrap().attributes().contains(FileAttributes::READ_ONLY));
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * Helpers shared by the command line tools. Not every tool uses all of them.
+ */
+#![allow(dead_code)]
+use ::exfat::{Fs,Timestamp};
+use ::exfat::partition::{open_exfat,PartitionStore};
+use ::io_at::{At,Take};
+use ::std::fs::{File,OpenOptions};
+
+pub type Store = PartitionStore<File>;
+
+/// The first exFAT volume in the image file, block device, or partitioned disk at `path`
+pub fn open_store(path: &str, write: bool) -> exfat::Result<Store> {
+    let f = OpenOptions::new().read(true).write(write).open(path)?;
+    open_exfat(f)
+}
+
+/// All of the image at `path`, for looking at a volume too damaged for `open_store()` to find
+pub fn open_whole(path: &str) -> exfat::Result<Store> {
+    let f = File::open(path)?;
+    let len = f.metadata()?.len();
+    Ok(Take::new(At::new(f, 0), len))
+}
+
+pub fn open_ro(path: &str) -> exfat::Result<Fs<Store>> {
+    Fs::from_ro(open_store(path, false)?)
+}
+
+pub fn open_rw(path: &str) -> exfat::Result<Fs<Store>> {
+    Fs::from_rw(open_store(path, true)?)
+}
+
+/// Format as `YYYY-MM-DD hh:mm:ss.mmm`, followed by the UTC offset if one is recorded
+pub fn fmt_timestamp(t: &Timestamp) -> String {
+    let mut s = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+                        t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second(), t.millisecond());
+    if let Some(o) = t.utc_offset() {
+        let sign = if o < 0 { '-' } else { '+' };
+        s.push_str(&format!(" {}{:02}{:02}", sign, o.abs() / 60, o.abs() % 60));
+    }
+    s
+}
+
+/// Print `data` as a hexdump, 16 bytes per line, labelling each line with it's offset plus
+/// `base`. Runs of identical lines are collapsed into a `*`.
+pub fn hexdump(data: &[u8], base: u64) {
+    let mut prev: Option<&[u8]> = None;
+    let mut starred = false;
+    for (i, line) in data.chunks(16).enumerate() {
+        if prev == Some(line) {
+            if !starred {
+                println!("*");
+                starred = true;
+            }
+            continue;
+        }
+        prev = Some(line);
+        starred = false;
+
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter()
+            .map(|b| if (0x20..0x7f).contains(b) { *b as char } else { '.' })
+            .collect();
+        println!("{:08x}  {:<47}  |{}|", base + i as u64 * 16, hex.join(" "), ascii);
+    }
+    println!("{:08x}", base + data.len() as u64);
+}
@@ -0,0 +1,75 @@
+/*
+ * Mount an exFAT volume with FUSE
+ */
+extern crate exfat;
+extern crate clap;
+extern crate io_at;
+
+mod common;
+
+use ::clap::{App,Arg};
+use ::exfat::fuse::MountOptions;
+use ::std::path::Path;
+use ::std::process;
+
+fn main() {
+    let matches = App::new("exfat-fuse")
+        .about("Mount an exFAT volume with FUSE, serving it until it is unmounted")
+        .arg(Arg::with_name("image")
+             .value_name("IMAGE")
+             .help("image file, block device, or partitioned disk containing the volume")
+             .required(true))
+        .arg(Arg::with_name("mountpoint")
+             .value_name("MOUNTPOINT")
+             .required(true))
+        .arg(Arg::with_name("read-only")
+             .short("r")
+             .long("read-only")
+             .help("mount read-only, without opening the image for writing"))
+        .arg(Arg::with_name("allow-other")
+             .long("allow-other")
+             .help("let other users access the volume"))
+        .arg(Arg::with_name("uid")
+             .long("uid")
+             .takes_value(true)
+             .requires("gid")
+             .help("owner shown for every file (default: the user mounting)"))
+        .arg(Arg::with_name("gid")
+             .long("gid")
+             .takes_value(true)
+             .requires("uid")
+             .help("group shown for every file (default: the user mounting)"))
+        .get_matches();
+
+    let image = matches.value_of("image").unwrap();
+    let read_only = matches.is_present("read-only");
+    let fs = match if read_only { common::open_ro(image) } else { common::open_rw(image) } {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("exfat-fuse: {}: {}", image, e);
+            process::exit(1);
+        },
+    };
+
+    let mut opts = MountOptions::new();
+    opts.read_only(read_only)
+        .allow_other(matches.is_present("allow-other"))
+        .fsname(image);
+    if let (Some(uid), Some(gid)) = (matches.value_of("uid"), matches.value_of("gid")) {
+        match (uid.parse(), gid.parse()) {
+            (Ok(uid), Ok(gid)) => {
+                opts.owner(uid, gid);
+            },
+            _ => {
+                eprintln!("exfat-fuse: --uid and --gid must be numbers");
+                process::exit(1);
+            },
+        }
+    }
+
+    let mountpoint = matches.value_of("mountpoint").unwrap();
+    if let Err(e) = opts.mount(&fs, Path::new(mountpoint)) {
+        eprintln!("exfat-fuse: {}: {}", mountpoint, e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,395 @@
+/*
+ * Dump the on-disk structures of an exFAT volume, for debugging damaged images by hand
+ */
+extern crate exfat;
+extern crate clap;
+extern crate fmt_extra;
+extern crate io_at;
+
+mod common;
+
+use ::clap::{App,AppSettings,Arg,ArgMatches,SubCommand};
+use ::exfat::{checksum32,entry_type,read_exact_at,BootSector,DirEntry,FatEntry,FileAttributes,Fs,Timestamp,UpcaseTable};
+use ::exfat::raw::{u16_at,u32_at,u64_at};
+use ::fmt_extra::{AsciiStr,Hs};
+use ::std::process;
+use common::{hexdump,Store};
+
+/// `2**shift`, which a damaged boot sector may make too large to compute
+fn pow2(shift: u32) -> String {
+    1u64.checked_shl(shift).map_or_else(|| "too large".to_owned(), |v| v.to_string())
+}
+
+fn print_boot_sector(bs: &BootSector) {
+    let flags = bs.volume_flags();
+    println!("  jump boot: {}", Hs(bs.jump_boot()));
+    println!("  magic: {}", AsciiStr(bs.magic()));
+    println!("  partition offset: {}", bs.partition_offs());
+    println!("  volume length: {}", bs.volume_len());
+    println!("  fat offset: {}", bs.fat_offs());
+    println!("  fat len: {}", bs.fat_len());
+    println!("  cluster heap offset: {}", bs.cluster_heap_offs());
+    println!("  cluster count: {}", bs.cluster_count());
+    println!("  first cluster of root directory: {}", bs.first_cluster_of_root_dir());
+    println!("  volume serial number: {:#010x}", bs.volume_serial_num());
+    println!("  file system revision: {}.{}", bs.file_system_rev() >> 8, bs.file_system_rev() & 0xff);
+    println!("  volume flags: {:#06x} (active fat {}{}{})", flags, flags & 1,
+             if flags & (1 << 1) != 0 { ", dirty" } else { "" },
+             if flags & (1 << 2) != 0 { ", media failure" } else { "" });
+    println!("  bytes per sector shift: {} ({})", bs.bytes_per_sector_shift(),
+             pow2(bs.bytes_per_sector_shift() as u32));
+    println!("  sectors per cluster shift: {} ({})", bs.sectors_per_cluster_shift(),
+             pow2(bs.sectors_per_cluster_shift() as u32));
+    println!("  bytes per cluster: {}",
+             pow2(bs.bytes_per_sector_shift() as u32 + bs.sectors_per_cluster_shift() as u32));
+    println!("  number of fats: {}", bs.number_of_fats());
+    println!("  drive select: {:#04x}", bs.drive_select());
+    println!("  percent in use: {}", bs.percent_in_use());
+    println!("  boot signature: {}", Hs(bs.boot_signature()));
+}
+
+/// Both boot regions, read directly so that damaged ones can still be shown
+fn bootsector(store: &Store) -> exfat::Result<()> {
+    let mut first = [0u8; 512];
+    read_exact_at(store, &mut first, 0)?;
+    let bps = if (9..=12).contains(&first[108]) { 1usize << first[108] } else { 512 };
+
+    for (i, name) in ["main", "backup"].iter().enumerate() {
+        let mut region = vec![0u8; 12 * bps];
+        read_exact_at(store, &mut region, (i * 12 * bps) as u64)?;
+        println!("{} boot region (sector {}):", name, i * 12);
+
+        let sum = exfat::boot_checksum(&region, bps);
+        let ok = region[(11 * bps)..].chunks(4).all(|c| u32_at(c, 0) == sum);
+        println!("  checksum: {} (computed {:#010x}, stored {:#010x})",
+                 if ok { "ok" } else { "MISMATCH" }, sum, u32_at(&region, 11 * bps));
+
+        let mut raw = [0u8; 512];
+        raw.copy_from_slice(&region[..512]);
+        match BootSector::from(raw) {
+            Ok(bs) => print_boot_sector(&bs),
+            Err(e) => println!("  invalid boot sector: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// The cluster chains which start within `count` clusters of `start`
+fn fat(fs: &Fs<Store>, start: u32, count: u32) -> exfat::Result<()> {
+    let bs = fs.boot_sector();
+    let fat = fs.fat();
+    let last = bs.cluster_count() as u64 + 2;
+    let end = ::std::cmp::min(start as u64 + count as u64, last) as u32;
+    let entry = |c: u32| fat.entry(FatEntry::from_val(c));
+    println!("FAT[0] = {:#010x}, FAT[1] = {:#010x}", entry(0).val(), entry(1).val());
+
+    /* a chain starts at a cluster no other cluster leads to */
+    let mut referenced = vec![false; last as usize];
+    for c in 2..(last as u32) {
+        let n = entry(c).val();
+        if bs.is_valid_cluster(n) {
+            referenced[n as usize] = true;
+        }
+    }
+
+    for c in ::std::cmp::max(start, 2)..end {
+        let e = entry(c);
+        if e.is_bad() {
+            println!("{}: bad cluster", c);
+            continue;
+        }
+        if e.val() == 0 || referenced[c as usize] {
+            continue;
+        }
+
+        let mut shown = Vec::new();
+        let mut n = 0;
+        let mut end = "end".to_owned();
+        for x in fat.chain(c) {
+            match x {
+                Ok(x) => {
+                    if n < 32 {
+                        shown.push(x.val().to_string());
+                    }
+                    n += 1;
+                },
+                Err(x) => {
+                    end = format!("BROKEN at {:#x}", x.val());
+                    break;
+                },
+            }
+        }
+        if n > 32 {
+            shown.push("...".to_owned());
+        }
+        println!("{}: {} ({}, {} clusters)", c, shown.join(" -> "), end, n);
+    }
+    Ok(())
+}
+
+fn bitmap(fs: &Fs<Store>, all: bool) -> exfat::Result<()> {
+    let cc = fs.boot_sector().cluster_count();
+    let b = fs.bitmap();
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for c in 2..(cc + 2) {
+        if b.is_allocated(c) {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(&mut (first, ref mut len)) if first + *len == c => *len += 1,
+            _ => runs.push((c, 1)),
+        }
+    }
+
+    println!("{} clusters, {} free in {} runs", cc, b.free_count(), runs.len());
+    if let Some(&(first, len)) = runs.iter().max_by_key(|r| r.1) {
+        println!("largest free run: {} clusters at {}", len, first);
+    }
+    let limit = if all { runs.len() } else { 20 };
+    for &(first, len) in runs.iter().take(limit) {
+        println!("  {}..{} ({} clusters)", first, first + len - 1, len);
+    }
+    if runs.len() > limit {
+        println!("  ... {} more (use --all to show them)", runs.len() - limit);
+    }
+    Ok(())
+}
+
+/// The contents of the stream at `first`, which is `len` bytes long and always FAT chained
+fn read_chain(fs: &Fs<Store>, first: u32, len: u64) -> exfat::Result<Vec<u8>> {
+    let cs = fs.boot_sector().bytes_per_cluster();
+    let mut v = vec![0u8; len as usize];
+    let clusters: Vec<u32> = fs.fat().chain(first).take(len.div_ceil(cs) as usize)
+        .map(|c| c.map(|c| c.val()).unwrap_or(0)).collect();
+    for (chunk, c) in v.chunks_mut(cs as usize).zip(clusters) {
+        if !fs.boot_sector().is_valid_cluster(c) {
+            return Err(exfat::Error::InvalidInput("cluster chain is broken"));
+        }
+        read_exact_at(&*fs.store(), chunk, fs.boot_sector().cluster_offs(c))?;
+    }
+    Ok(v)
+}
+
+fn upcase(fs: &Fs<Store>, all: bool) -> exfat::Result<()> {
+    for e in fs.root_dir()?.entries() {
+        let (i, e) = e?;
+        if e.entry_type() != entry_type::UPCASE_TABLE {
+            continue;
+        }
+        println!("up-case table entry {}: first cluster {}, {} bytes", i, e.first_cluster(), e.data_len());
+        if e.data_len() > UpcaseTable::MAX_RAW_LEN {
+            println!("  too large (a table is at most {} bytes)", UpcaseTable::MAX_RAW_LEN);
+            continue;
+        }
+        let raw = read_chain(fs, e.first_cluster(), e.data_len())?;
+        let sum = checksum32(0, &raw);
+        println!("  checksum: {} (computed {:#010x}, stored {:#010x})",
+                 if sum == e.table_checksum() { "ok" } else { "MISMATCH" }, sum, e.table_checksum());
+    }
+
+    let t = fs.upcase_table();
+    let mapped: Vec<u16> = (0..0x10000u32).map(|c| c as u16).filter(|c| t.upcase(*c) != *c).collect();
+    println!("{} code units are mapped, {}the same as the generated table", mapped.len(),
+             if *t == UpcaseTable::generate() { "" } else { "not " });
+    if all {
+        for c in mapped {
+            println!("  U+{:04X} -> U+{:04X}", c, t.upcase(c));
+        }
+    }
+    Ok(())
+}
+
+fn type_name(ty: u8) -> &'static str {
+    match ty {
+        entry_type::ALLOCATION_BITMAP => "allocation bitmap",
+        entry_type::UPCASE_TABLE => "up-case table",
+        entry_type::VOLUME_LABEL => "volume label",
+        entry_type::FILE => "file",
+        entry_type::VOLUME_GUID => "volume guid",
+        entry_type::STREAM_EXTENSION => "stream extension",
+        entry_type::FILE_NAME => "file name",
+        entry_type::VENDOR_EXTENSION => "vendor extension",
+        entry_type::VENDOR_ALLOCATION => "vendor allocation",
+        t if t & 0x80 == 0 => "unused",
+        _ => "unknown",
+    }
+}
+
+fn timestamp(r: &[u8], offs: usize, incr: Option<usize>, utc: usize) -> String {
+    let t = Timestamp::from_raw(u32_at(r, offs), incr.map(|i| r[i]).unwrap_or(0), r[utc]);
+    common::fmt_timestamp(&t)
+}
+
+/// Decode the fields of a File entry set
+fn print_file_set(fs: &Fs<Store>, set: &[DirEntry]) {
+    let f = set[0].raw();
+    println!("    attributes: {}", FileAttributes::from_bits_truncate(u16_at(f, 4)));
+    println!("    created: {}", timestamp(f, 8, Some(20), 22));
+    println!("    modified: {}", timestamp(f, 12, Some(21), 23));
+    println!("    accessed: {}", timestamp(f, 16, None, 24));
+
+    let s = match set.get(1) {
+        Some(s) if s.entry_type() == entry_type::STREAM_EXTENSION => s.raw(),
+        _ => {
+            println!("    no stream extension");
+            return;
+        },
+    };
+    let name_len = s[3] as usize;
+    let name: Vec<u16> = set[2..].iter().filter(|e| e.entry_type() == entry_type::FILE_NAME)
+        .flat_map(|e| (0..15).map(move |i| u16_at(e.raw(), 2 + i * 2)))
+        .take(name_len)
+        .collect();
+    let hash = fs.upcase_table().name_hash(&name);
+    println!("    name: {:?} ({} of {} characters present)", String::from_utf16_lossy(&name), name.len(), name_len);
+    println!("    name hash: {} (computed {:#06x}, stored {:#06x})",
+             if hash == u16_at(s, 4) { "ok" } else { "MISMATCH" }, hash, u16_at(s, 4));
+    println!("    flags: {:#04x} (allocation possible: {}, no fat chain: {})", s[1], s[1] & 1, (s[1] >> 1) & 1);
+    println!("    valid data length: {}", u64_at(s, 8));
+    println!("    first cluster: {}", u32_at(s, 20));
+    println!("    data length: {}", u64_at(s, 24));
+}
+
+fn dir(fs: &Fs<Store>, path: &str, all: bool) -> exfat::Result<()> {
+    let entries = fs.open_dir(path)?.entries().collect::<exfat::Result<Vec<_>>>()?;
+    let mut i = 0;
+    while i < entries.len() {
+        let (idx, p) = entries[i];
+        i += 1;
+        let k = p.kind();
+        if !k.in_use() || !k.is_primary() {
+            if all || k.in_use() {
+                let note = if k.in_use() { " (without a primary entry)" } else { "" };
+                println!("entry {}: {} {:#04x}{}", idx, type_name(p.entry_type()), p.entry_type(), note);
+                hexdump(p.raw(), 0);
+            }
+            continue;
+        }
+
+        let secondaries = match p.entry_type() {
+            entry_type::ALLOCATION_BITMAP | entry_type::UPCASE_TABLE | entry_type::VOLUME_LABEL => 0,
+            _ => p.secondary_count() as usize,
+        };
+        let mut set = vec![p];
+        while set.len() <= secondaries && i < entries.len()
+            && entries[i].1.kind().in_use() && !entries[i].1.kind().is_primary() {
+            set.push(entries[i].1);
+            i += 1;
+        }
+
+        println!("entry {}: {} {:#04x}, {} secondary entries", idx, type_name(p.entry_type()),
+                 p.entry_type(), set.len() - 1);
+        if set.len() <= secondaries {
+            println!("    MISSING {} secondary entries", secondaries + 1 - set.len());
+        }
+        if secondaries > 0 || !k.is_critical() {
+            let sum = exfat::set_checksum(&set);
+            println!("    set checksum: {} (computed {:#06x}, stored {:#06x})",
+                     if sum == p.set_checksum() { "ok" } else { "MISMATCH" }, sum, p.set_checksum());
+        }
+
+        match p.entry_type() {
+            entry_type::FILE => print_file_set(fs, &set),
+            entry_type::ALLOCATION_BITMAP | entry_type::UPCASE_TABLE => {
+                println!("    flags: {:#04x}", p.raw()[1]);
+                println!("    first cluster: {}", p.first_cluster());
+                println!("    data length: {}", p.data_len());
+            },
+            entry_type::VOLUME_LABEL => {
+                let n = ::std::cmp::min(p.raw()[1] as usize, 11);
+                let label: Vec<u16> = (0..n).map(|i| u16_at(p.raw(), 2 + i * 2)).collect();
+                println!("    label: {:?}", String::from_utf16_lossy(&label));
+            },
+            _ => {},
+        }
+        for (j, e) in set.iter().enumerate() {
+            println!("  [{}] {} {:#04x}", idx as usize + j, type_name(e.entry_type()), e.entry_type());
+            hexdump(e.raw(), 0);
+        }
+    }
+    Ok(())
+}
+
+fn cluster(fs: &Fs<Store>, c: u32) -> exfat::Result<()> {
+    let bs = fs.boot_sector();
+    if !bs.is_valid_cluster(c) {
+        return Err(exfat::Error::InvalidInput("cluster is outside of the cluster heap"));
+    }
+
+    let offs = bs.cluster_offs(c);
+    println!("cluster {} at byte {:#x}: {}, FAT entry {:#010x}", c, offs,
+             if fs.bitmap().is_allocated(c) { "allocated" } else { "free" },
+             fs.fat().entry(FatEntry::from_val(c)).val());
+    let mut buf = vec![0u8; bs.bytes_per_cluster() as usize];
+    read_exact_at(&*fs.store(), &mut buf, offs)?;
+    hexdump(&buf, offs);
+    Ok(())
+}
+
+fn number<T: ::std::str::FromStr>(m: &ArgMatches, name: &str, default: T) -> T {
+    match m.value_of(name) {
+        None => default,
+        Some(v) => v.parse().unwrap_or_else(|_| {
+            eprintln!("{} must be a number, not {:?}", name, v);
+            process::exit(1);
+        }),
+    }
+}
+
+fn main() {
+    let all = || Arg::with_name("all").long("all").short("a");
+    let matches = App::new("exfat-inspect")
+        .about("Show the on-disk structures of an exFAT volume")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("image")
+             .value_name("IMAGE")
+             .help("image file, block device, or partitioned disk containing the volume")
+             .required(true))
+        .subcommand(SubCommand::with_name("bootsector")
+                    .about("Show the main and backup boot sectors, and the checksum of each boot region"))
+        .subcommand(SubCommand::with_name("fat")
+                    .about("Show the cluster chains starting in a range of clusters")
+                    .arg(Arg::with_name("start").help("first cluster (default 2)"))
+                    .arg(Arg::with_name("count").help("number of clusters (default all)")))
+        .subcommand(SubCommand::with_name("bitmap")
+                    .about("Summarize the free clusters in the allocation bitmap")
+                    .arg(all().help("list every free run")))
+        .subcommand(SubCommand::with_name("upcase")
+                    .about("Show the up-case table")
+                    .arg(all().help("list every mapping")))
+        .subcommand(SubCommand::with_name("dir")
+                    .about("Show the raw entry sets of a directory")
+                    .arg(Arg::with_name("path").required(true).help("directory, \"/\" for the root"))
+                    .arg(all().help("include unused entries")))
+        .subcommand(SubCommand::with_name("cluster")
+                    .about("Hexdump a cluster")
+                    .arg(Arg::with_name("cluster").required(true)))
+        .get_matches();
+
+    let path = matches.value_of("image").unwrap();
+    let store = match (common::open_store(path, false), matches.subcommand_name()) {
+        /* the boot sectors are read directly, so are shown even if they're invalid */
+        (Err(exfat::Error::Corrupt { .. }), Some("bootsector")) => common::open_whole(path),
+        (r, _) => r,
+    };
+    let r = store.and_then(|store| match matches.subcommand() {
+        ("bootsector", _) => bootsector(&store),
+        (cmd, Some(m)) => {
+            let fs = Fs::from_ro(store)?;
+            match cmd {
+                "fat" => fat(&fs, number(m, "start", 2), number(m, "count", u32::MAX)),
+                "bitmap" => bitmap(&fs, m.is_present("all")),
+                "upcase" => upcase(&fs, m.is_present("all")),
+                "dir" => dir(&fs, m.value_of("path").unwrap(), m.is_present("all")),
+                "cluster" => cluster(&fs, number(m, "cluster", 0)),
+                _ => unreachable!(),
+            }
+        },
+        _ => unreachable!(),
+    });
+
+    if let Err(e) = r {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
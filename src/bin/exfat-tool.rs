@@ -0,0 +1,689 @@
+/*
+ * Work with the files in an exFAT image without mounting it, like mtools
+ *
+ * As with mtools, arguments to `cp` which name a path in the image start with `::`, and all
+ * others are paths on the host.
+ */
+extern crate exfat;
+extern crate clap;
+extern crate io_at;
+
+mod common;
+
+use ::clap::{App,AppSettings,Arg,ArgMatches,SubCommand};
+use ::exfat::{Confidence,DeletedFile,Error,Fs,ImageBuilder,Orphan,Result,TimeSource,Timestamp,WalkOrder};
+use ::exfat::fs::{self as xfs,Metadata};
+use ::std::fs as host;
+use ::std::io::{self,Read,Write};
+use ::std::path::Path;
+use ::std::process;
+use ::std::time::{Duration,UNIX_EPOCH};
+use common::Store;
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// The final component of `path`, in the image or on the host
+fn basename(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or("")
+}
+
+fn print_entry(name: &str, md: &Metadata, long: bool) {
+    let suffix = if md.is_dir() { "/" } else { "" };
+    if !long {
+        println!("{}{}", name, suffix);
+        return;
+    }
+    let modified = md.entry_set()
+        .map(|s| common::fmt_timestamp(&s.modified()))
+        .unwrap_or_default();
+    println!("{} {:>12} {:<29} {}{}", md.attributes(), md.len(), modified, name, suffix);
+}
+
+fn ls(fs: &Fs<Store>, paths: &[&str], long: bool) -> Result<()> {
+    for (i, &p) in paths.iter().enumerate() {
+        let md = xfs::metadata(fs, p)?;
+        if !md.is_dir() {
+            print_entry(p, &md, long);
+            continue;
+        }
+        if paths.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            println!("{}:", p);
+        }
+        for e in xfs::read_dir(fs, p)? {
+            let e = e?;
+            print_entry(&e.file_name(), &e.metadata()?, long);
+        }
+    }
+    Ok(())
+}
+
+fn cat(fs: &Fs<Store>, path: &str) -> Result<()> {
+    let mut f = fs.open(path)?;
+    let out = io::stdout();
+    io::copy(&mut f, &mut out.lock())?;
+    Ok(())
+}
+
+/// A path given to `cp`
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+enum Loc<'a> {
+    Image(&'a str),
+    Host(&'a str),
+}
+
+impl<'a> Loc<'a> {
+    fn parse(s: &'a str) -> Self {
+        if let Some(p) = s.strip_prefix("::") {
+            Loc::Image(p)
+        } else {
+            Loc::Host(s)
+        }
+    }
+
+    fn path(&self) -> &'a str {
+        match *self {
+            Loc::Image(p) | Loc::Host(p) => p,
+        }
+    }
+
+    fn join(&self, name: &str) -> String {
+        join(self.path(), name)
+    }
+
+    fn with_path<'b>(&self, path: &'b str) -> Loc<'b> {
+        match *self {
+            Loc::Image(_) => Loc::Image(path),
+            Loc::Host(_) => Loc::Host(path),
+        }
+    }
+
+    /// `Some(true)` for a directory, `None` if nothing exists at this path
+    fn is_dir(&self, fs: &Fs<Store>) -> Result<Option<bool>> {
+        let r = match *self {
+            Loc::Image(p) => xfs::metadata(fs, p).map(|m| m.is_dir()),
+            Loc::Host(p) => host::metadata(p).map(|m| m.is_dir()),
+        };
+        match r {
+            Ok(d) => Ok(Some(d)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The names of the entries in this directory, sorted on the host
+    fn list(&self, fs: &Fs<Store>) -> Result<Vec<String>> {
+        match *self {
+            Loc::Image(p) => xfs::read_dir(fs, p)?.map(|e| Ok(e?.file_name())).collect(),
+            Loc::Host(p) => {
+                let mut v = Vec::new();
+                for e in host::read_dir(p)? {
+                    v.push(e?.file_name().to_string_lossy().into_owned());
+                }
+                v.sort();
+                Ok(v)
+            },
+        }
+    }
+
+    fn create_dir_all(&self, fs: &Fs<Store>) -> Result<()> {
+        match *self {
+            Loc::Image(p) => xfs::create_dir_all(fs, p)?,
+            Loc::Host(p) => host::create_dir_all(p)?,
+        }
+        Ok(())
+    }
+
+    fn open<'f>(&self, fs: &'f Fs<Store>) -> Result<Box<dyn Read + 'f>> {
+        Ok(match *self {
+            Loc::Image(p) => Box::new(fs.open(p)?),
+            Loc::Host(p) => Box::new(host::File::open(p)?),
+        })
+    }
+
+    fn create<'f>(&self, fs: &'f Fs<Store>) -> Result<Box<dyn Write + 'f>> {
+        Ok(match *self {
+            Loc::Image(p) => Box::new(xfs::OpenOptions::new().write(true).create(true).truncate(true).open(fs, p)?),
+            Loc::Host(p) => Box::new(host::File::create(p)?),
+        })
+    }
+}
+
+/// The sources & destination of `cp`, the last of `paths`. At least one must be in the image.
+fn cp_locs<'a>(paths: &[&'a str]) -> Result<(Vec<Loc<'a>>, Loc<'a>)> {
+    let (dst, srcs) = paths.split_last().ok_or(Error::InvalidInput("no destination was given"))?;
+    let dst = Loc::parse(dst);
+    let srcs: Vec<Loc> = srcs.iter().map(|s| Loc::parse(s)).collect();
+    if let Loc::Host(_) = dst {
+        if srcs.iter().all(|s| matches!(*s, Loc::Host(_))) {
+            return Err(Error::InvalidInput("no path is in the image (prefix them with \"::\")"));
+        }
+    }
+    Ok((srcs, dst))
+}
+
+/// Where each of `srcs` is copied or moved to: into `dst` if it is a directory, otherwise to
+/// `dst` itself, which only works for a single source
+fn destinations(srcs: &[&str], dst: &str, dst_is_dir: bool) -> Result<Vec<String>> {
+    if srcs.len() > 1 && !dst_is_dir {
+        return Err(Error::NotADirectory);
+    }
+    Ok(srcs.iter().map(|s| if dst_is_dir { join(dst, basename(s)) } else { dst.to_owned() }).collect())
+}
+
+/// Whether the image path `dst` is the directory `src`, or somewhere inside of it
+fn is_within(fs: &Fs<Store>, src: &str, dst: &str) -> Result<bool> {
+    let c = match xfs::metadata(fs, src)?.entry_set() {
+        Some(s) => s.first_cluster(),
+        None => return Ok(true),
+    };
+    let mut p = String::new();
+    for part in dst.split('/').filter(|c| !c.is_empty()) {
+        p.push('/');
+        p.push_str(part);
+        match xfs::metadata(fs, &p) {
+            Ok(md) => if md.entry_set().map(|s| s.first_cluster()) == Some(c) {
+                return Ok(true);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(false)
+}
+
+fn copy(fs: &Fs<Store>, src: Loc, dst: Loc, recursive: bool) -> Result<()> {
+    if src.is_dir(fs)?.ok_or(Error::NotFound)? {
+        if !recursive {
+            return Err(Error::IsADirectory);
+        }
+        if let (Loc::Image(s), Loc::Image(d)) = (src, dst) {
+            if is_within(fs, s, d)? {
+                return Err(Error::InvalidInput("can not copy a directory into itself"));
+            }
+        }
+        let names = src.list(fs)?;
+        dst.create_dir_all(fs)?;
+        for name in names {
+            let (s, d) = (src.join(&name), dst.join(&name));
+            copy(fs, src.with_path(&s), dst.with_path(&d), recursive)?;
+        }
+        return Ok(());
+    }
+
+    if let (Loc::Image(s), Loc::Image(d)) = (src, dst) {
+        xfs::copy(fs, s, d)?;
+        return Ok(());
+    }
+    let mut r = src.open(fs)?;
+    let mut w = dst.create(fs)?;
+    io::copy(&mut r, &mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+fn stat(fs: &Fs<Store>, path: &str) -> Result<()> {
+    let md = xfs::metadata(fs, path)?;
+    println!("  path: {}", path);
+    println!("  type: {}", if md.is_dir() { "directory" } else { "file" });
+    println!("  size: {}", md.len());
+    println!("  attributes: {}", md.attributes());
+    let s = match md.entry_set() {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    println!("  valid data length: {}", s.valid_data_len());
+    let cs = fs.boot_sector().bytes_per_cluster();
+    println!("  first cluster: {} ({} clusters, {})", s.first_cluster(), s.data_len().div_ceil(cs),
+             if s.no_fat_chain() { "contiguous" } else { "FAT chain" });
+    println!("  created: {}", common::fmt_timestamp(&s.created()));
+    println!("  modified: {}", common::fmt_timestamp(&s.modified()));
+    println!("  accessed: {}", common::fmt_timestamp(&s.accessed()));
+    Ok(())
+}
+
+fn df(fs: &Fs<Store>) -> Result<()> {
+    let bs = fs.boot_sector();
+    let cs = bs.bytes_per_cluster();
+    let total = bs.cluster_count() as u64;
+    let free = fs.bitmap().free_count() as u64;
+    let used = total - free;
+    println!("{:>14} {:>14} {:>14} {:>5}  label", "size", "used", "available", "use%");
+    println!("{:>14} {:>14} {:>14} {:>4}%  {}", total * cs, used * cs, free * cs,
+             (used * 100).div_ceil(total.max(1)), fs.volume_label()?);
+    println!("{} clusters of {} bytes, {} free", total, cs, free);
+    Ok(())
+}
+
+fn tree(fs: &Fs<Store>, path: &str) -> Result<()> {
+    println!("{}", path);
+    let (mut dirs, mut files) = (0, 0);
+    for e in fs.walk(path)?.order(WalkOrder::DepthFirst) {
+        let e = e?;
+        let md = e.metadata();
+        let name = basename(e.path());
+        println!("{}{}{}", "    ".repeat(e.depth()), name, if md.is_dir() { "/" } else { "" });
+        if md.is_dir() {
+            dirs += 1;
+        } else {
+            files += 1;
+        }
+    }
+    println!();
+    println!("{} directories, {} files", dirs, files);
+    Ok(())
+}
+
+/// A number of bytes, with an optional K, M, G, or T suffix (as powers of 1024)
+fn parse_size(s: &str) -> Option<u64> {
+    let (n, shift) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 30),
+        (i, 'T') | (i, 't') => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    n.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn build(image: &str, m: &ArgMatches) -> Result<()> {
+    let bad = |msg| Error::InvalidInput(msg);
+    let size = parse_size(m.value_of("size").unwrap()).ok_or(bad("--size must be a number of bytes"))?;
+    let mut b = ImageBuilder::new(size);
+    if let Some(cs) = m.value_of("cluster-size") {
+        b.cluster_size(parse_size(cs).ok_or(bad("--cluster-size must be a number of bytes"))?);
+    }
+    if let Some(serial) = m.value_of("serial") {
+        let serial = serial.trim_start_matches("0x");
+        b.serial(u32::from_str_radix(serial, 16).map_err(|_| bad("--serial must be a 32 bit hex number"))?);
+    }
+    if let Some(label) = m.value_of("label") {
+        b.label(label);
+    }
+
+    /* as in the reproducible builds convention, SOURCE_DATE_EPOCH is the default time */
+    let epoch = m.value_of("timestamp").map(|s| s.to_owned())
+        .or_else(|| ::std::env::var("SOURCE_DATE_EPOCH").ok());
+    if m.is_present("source-times") {
+        b.times(TimeSource::Modified);
+    } else if let Some(secs) = epoch {
+        let secs = secs.parse().map_err(|_| bad("timestamp must be a number of seconds since 1970"))?;
+        b.times(TimeSource::Fixed(Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(secs), Some(0))?));
+    }
+
+    b.build_file(Path::new(m.value_of("source").unwrap()), Path::new(image))
+}
+
+fn extract(fs: &Fs<Store>, dest: &str, m: &ArgMatches) -> Result<bool> {
+    let r = fs.extract_to(Path::new(dest), m.is_present("keep-going"))?;
+    for f in r.failures() {
+        eprintln!("exfat-tool: {}", f);
+    }
+    if let Some(list) = m.value_of("failures") {
+        let mut out = io::BufWriter::new(host::File::create(list)?);
+        for f in r.failures() {
+            writeln!(out, "{}", f)?;
+        }
+        out.flush()?;
+    }
+    println!("{} files, {} directories, {} bytes extracted; {} failures",
+             r.files(), r.dirs(), r.bytes(), r.failures().len());
+    Ok(r.is_complete())
+}
+
+/// A host path for `f` below `dest` which isn't taken, adding a number to the name if needed
+fn unused_path(dest: &str, f: &DeletedFile) -> ::std::path::PathBuf {
+    let p = Path::new(dest).join(f.path().trim_start_matches('/'));
+    if f.is_dir() {
+        return p;
+    }
+    let mut unused = p.clone();
+    let mut n = 0;
+    while host::symlink_metadata(&unused).is_ok() {
+        n += 1;
+        unused = p.with_file_name(format!("{}~{}", f.name(), n));
+    }
+    unused
+}
+
+fn extract_deleted(fs: &Fs<Store>, f: &DeletedFile, dest: &str) -> Result<()> {
+    let to = unused_path(dest, f);
+    if f.is_dir() {
+        host::create_dir_all(&to)?;
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        host::create_dir_all(parent)?;
+    }
+    let mut out = io::BufWriter::new(host::File::create(&to)?);
+    fs.read_deleted(f, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// List, restore, or extract the deleted files at (or below) `paths`, or every one if there are
+/// none
+fn undelete(image: &str, paths: &[&str], m: &ArgMatches) -> Result<bool> {
+    let restore = m.is_present("restore");
+    let fs = if restore { common::open_rw(image)? } else { common::open_ro(image)? };
+    let found = fs.deleted()?;
+    let paths: Vec<String> = paths.iter().map(|p| format!("/{}", p.trim_matches('/'))).collect();
+    let below = |f: &DeletedFile, p: &str| {
+        f.path() == p || f.path().strip_prefix(p).is_some_and(|rest| rest.starts_with('/'))
+    };
+
+    let mut ok = true;
+    for p in &paths {
+        if !found.iter().any(|f| below(f, p)) {
+            eprintln!("exfat-tool: {}: no deleted file or directory has this path", p);
+            ok = false;
+        }
+    }
+
+    for f in found.iter().filter(|f| paths.is_empty() || paths.iter().any(|p| below(f, p))) {
+        let confidence = match f.confidence() {
+            Confidence::Certain => "certain",
+            Confidence::Likely => "likely",
+            Confidence::Damaged => "damaged",
+        };
+        let r = if restore {
+            fs.undelete(f)
+        } else if let Some(dest) = m.value_of("extract") {
+            extract_deleted(&fs, f, dest)
+        } else {
+            Ok(())
+        };
+        match r {
+            Ok(()) => println!("{:<7} {:>12} {}{}", confidence, f.len(), f.path(), if f.is_dir() { "/" } else { "" }),
+            Err(e) => {
+                eprintln!("exfat-tool: {}: {}", f.path(), e);
+                ok = false;
+            },
+        }
+    }
+    Ok(ok)
+}
+
+fn print_orphan(o: &Orphan, depth: usize) {
+    let suffix = if o.is_dir() { "/" } else { "" };
+    println!("{:>12} {:width$}{}{}", o.len(), "", o.name(), suffix, width = depth * 2);
+    for c in o.children() {
+        print_orphan(c, depth + 1);
+    }
+}
+
+/// List the trees of files & directories found in lost clusters, and optionally link them into
+/// `/FOUND.000`
+fn orphans(image: &str, relink: bool) -> Result<()> {
+    let fs = if relink { common::open_rw(image)? } else { common::open_ro(image)? };
+    let dirs = fs.find_orphans()?;
+    for d in &dirs {
+        let clusters: Vec<String> = d.clusters().iter().map(|c| c.to_string()).collect();
+        println!("found in cluster {}:", clusters.join(", "));
+        for o in d.entries() {
+            print_orphan(o, 1);
+        }
+    }
+    if dirs.is_empty() {
+        println!("no orphaned files or directories found");
+    } else if relink {
+        for p in fs.relink_orphans(&dirs)? {
+            println!("linked as {}", p);
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` on each of `paths`, reporting (but carrying on after) failures. Returns true if
+/// there were none.
+fn each<'a, F: FnMut(&'a str) -> Result<()>>(paths: &[&'a str], mut f: F) -> bool {
+    let mut ok = true;
+    for &p in paths {
+        if let Err(e) = f(p) {
+            eprintln!("exfat-tool: {}: {}", p, e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Returns false if the command failed for some, but not all, of it's paths
+fn run(image: &str, cmd: &str, m: &ArgMatches) -> Result<bool> {
+    let paths: Vec<&str> = m.values_of("path").map(|v| v.collect()).unwrap_or_default();
+    let open_rw = || common::open_rw(image);
+    match cmd {
+        "ls" => {
+            let paths = if paths.is_empty() { vec!["/"] } else { paths };
+            ls(&common::open_ro(image)?, &paths, m.is_present("long")).map(|_| true)
+        },
+        "cat" => {
+            let fs = common::open_ro(image)?;
+            Ok(each(&paths, |p| cat(&fs, p)))
+        },
+        "cp" => {
+            let (srcs, dst) = cp_locs(&paths)?;
+            let fs = if let Loc::Image(_) = dst { open_rw()? } else { common::open_ro(image)? };
+
+            let src_paths: Vec<&str> = srcs.iter().map(|s| s.path()).collect();
+            let dests = destinations(&src_paths, dst.path(), dst.is_dir(&fs)? == Some(true))?;
+            let mut ok = true;
+            for (s, d) in srcs.into_iter().zip(dests) {
+                if let Err(e) = copy(&fs, s, dst.with_path(&d), m.is_present("recursive")) {
+                    eprintln!("exfat-tool: {}: {}", s.path(), e);
+                    ok = false;
+                }
+            }
+            Ok(ok)
+        },
+        "mkdir" => {
+            let fs = open_rw()?;
+            let parents = m.is_present("parents");
+            Ok(each(&paths, |p| if parents { Ok(xfs::create_dir_all(&fs, p)?) } else { fs.create_dir(p).map(|_| ()) }))
+        },
+        "rm" => {
+            let fs = open_rw()?;
+            let recursive = m.is_present("recursive");
+            Ok(each(&paths, |p| {
+                if !xfs::metadata(&fs, p)?.is_dir() {
+                    fs.remove_file(p)
+                } else if recursive {
+                    Ok(xfs::remove_dir_all(&fs, p)?)
+                } else {
+                    fs.remove_dir(p)
+                }
+            }))
+        },
+        "mv" => {
+            let fs = open_rw()?;
+            let (dst, srcs) = paths.split_last().unwrap();
+            let into_dir = xfs::metadata(&fs, dst).map(|m| m.is_dir()).unwrap_or(false);
+            let mut dests = destinations(srcs, dst, into_dir)?.into_iter();
+            Ok(each(srcs, |s| fs.rename(s, &dests.next().unwrap())))
+        },
+        "stat" => {
+            let fs = common::open_ro(image)?;
+            Ok(each(&paths, |p| stat(&fs, p)))
+        },
+        "df" => df(&common::open_ro(image)?).map(|_| true),
+        "label" => {
+            if m.is_present("clear") {
+                return open_rw()?.set_volume_label("").map(|_| true);
+            }
+            match m.value_of("label") {
+                Some(l) => open_rw()?.set_volume_label(l).map(|_| true),
+                None => {
+                    println!("{}", common::open_ro(image)?.volume_label()?);
+                    Ok(true)
+                },
+            }
+        },
+        "extract" => extract(&common::open_ro(image)?, m.value_of("dest").unwrap(), m),
+        "undelete" => undelete(image, &paths, m),
+        "orphans" => orphans(image, m.is_present("relink")).map(|_| true),
+        "build" => build(image, m).map(|_| true),
+        "tree" => tree(&common::open_ro(image)?, m.value_of("path").unwrap_or("/")).map(|_| true),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let paths = |required| Arg::with_name("path").multiple(true).required(required);
+    let matches = App::new("exfat-tool")
+        .about("Work with the files in an exFAT image without mounting it")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("image")
+             .value_name("IMAGE")
+             .help("image file, block device, or partitioned disk containing the volume")
+             .required(true))
+        .subcommand(SubCommand::with_name("ls")
+                    .about("List directories")
+                    .arg(Arg::with_name("long").short("l").help("show attributes, sizes, and modification times"))
+                    .arg(paths(false)))
+        .subcommand(SubCommand::with_name("cat")
+                    .about("Write files to standard output")
+                    .arg(paths(true)))
+        .subcommand(SubCommand::with_name("cp")
+                    .about("Copy files; paths in the image start with \"::\", as in mtools")
+                    .arg(Arg::with_name("recursive").short("r").help("copy directories and their contents"))
+                    .arg(paths(true).min_values(2).value_name("SOURCE... DEST")))
+        .subcommand(SubCommand::with_name("mkdir")
+                    .about("Create directories")
+                    .arg(Arg::with_name("parents").short("p").help("create missing parents, and accept existing directories"))
+                    .arg(paths(true)))
+        .subcommand(SubCommand::with_name("rm")
+                    .about("Remove files, and empty directories")
+                    .arg(Arg::with_name("recursive").short("r").help("remove directories and their contents"))
+                    .arg(paths(true)))
+        .subcommand(SubCommand::with_name("mv")
+                    .about("Rename, or move into a directory")
+                    .arg(paths(true).min_values(2).value_name("SOURCE... DEST")))
+        .subcommand(SubCommand::with_name("stat")
+                    .about("Show the details of files and directories")
+                    .arg(paths(true)))
+        .subcommand(SubCommand::with_name("df")
+                    .about("Show the space used and available"))
+        .subcommand(SubCommand::with_name("label")
+                    .about("Show or change the volume label")
+                    .arg(Arg::with_name("clear").short("c").long("clear").help("remove the label"))
+                    .arg(Arg::with_name("label").conflicts_with("clear")))
+        .subcommand(SubCommand::with_name("extract")
+                    .about("Copy everything in the image to a directory, keeping times and the read-only attribute")
+                    .arg(Arg::with_name("dest").required(true).help("directory to copy into, created if needed"))
+                    .arg(Arg::with_name("keep-going").short("k").long("keep-going")
+                         .help("carry on past corrupt files and directories, copying as much as possible"))
+                    .arg(Arg::with_name("failures").long("failures").takes_value(true).value_name("FILE")
+                         .requires("keep-going")
+                         .help("write the paths that couldn't be copied, and why, to FILE")))
+        .subcommand(SubCommand::with_name("undelete")
+                    .about("List deleted files and directories, with how much of each is likely intact, or recover them")
+                    .arg(Arg::with_name("restore").short("r").long("restore").requires("path")
+                         .help("restore them in place; a directory must be restored before its contents"))
+                    .arg(Arg::with_name("extract").short("x").long("extract").takes_value(true).value_name("DIR")
+                         .conflicts_with("restore")
+                         .help("copy their data into DIR, leaving the image alone"))
+                    .arg(paths(false)))
+        .subcommand(SubCommand::with_name("orphans")
+                    .about("Find files and directories whose directory was lost, by scanning the lost clusters")
+                    .arg(Arg::with_name("relink").long("relink")
+                         .help("link what was found into the tree, as directories in /FOUND.000")))
+        .subcommand(SubCommand::with_name("build")
+                    .about("Create IMAGE, containing a copy of a directory. The same input gives an identical image.")
+                    .arg(Arg::with_name("source").required(true).help("directory to copy into the image"))
+                    .arg(Arg::with_name("size").long("size").short("s").takes_value(true).required(true)
+                         .help("size of the image, in bytes or with a K, M, G, or T suffix"))
+                    .arg(Arg::with_name("cluster-size").long("cluster-size").takes_value(true)
+                         .help("bytes per cluster (default: depends on the size)"))
+                    .arg(Arg::with_name("serial").long("serial").takes_value(true)
+                         .help("volume serial number, in hex (default: 0)"))
+                    .arg(Arg::with_name("label").long("label").takes_value(true))
+                    .arg(Arg::with_name("timestamp").long("timestamp").takes_value(true)
+                         .help("seconds since 1970 to use for every timestamp (default: $SOURCE_DATE_EPOCH, or 1980-01-01)"))
+                    .arg(Arg::with_name("source-times").long("source-times").conflicts_with("timestamp")
+                         .help("use the modification time of each file instead")))
+        .subcommand(SubCommand::with_name("tree")
+                    .about("List the contents of a directory recursively")
+                    .arg(Arg::with_name("path")))
+        .get_matches();
+
+    let image = matches.value_of("image").unwrap();
+    let (cmd, m) = matches.subcommand();
+    match run(image, cmd, m.unwrap()) {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("exfat-tool: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("8M"), Some(8 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size("1t"), Some(1 << 40));
+        assert_eq!(parse_size("16777216T"), None);
+        for s in &["", "K", "1.5M", "-1", "10X", "M10"] {
+            assert_eq!(parse_size(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn cp_paths() {
+        assert_eq!(Loc::parse("::/a/b"), Loc::Image("/a/b"));
+        assert_eq!(Loc::parse("::"), Loc::Image(""));
+        assert_eq!(Loc::parse("a::b"), Loc::Host("a::b"));
+        assert_eq!(Loc::parse(":x"), Loc::Host(":x"));
+        assert_eq!(Loc::Image("/d/").join("f"), "/d/f");
+        assert_eq!(Loc::Host("d").with_path("e"), Loc::Host("e"));
+
+        assert_eq!(cp_locs(&["a", "::b", "::/d"]).unwrap(),
+                   (vec![Loc::Host("a"), Loc::Image("b")], Loc::Image("/d")));
+        assert_eq!(cp_locs(&["::/f", "out"]).unwrap(), (vec![Loc::Image("/f")], Loc::Host("out")));
+        assert!(matches!(cp_locs(&["a", "b"]), Err(Error::InvalidInput(_))));
+        assert!(matches!(cp_locs(&[]), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn dests() {
+        /* a single source can be renamed, or put in a directory */
+        assert_eq!(destinations(&["/a/f"], "/g", false).unwrap(), vec!["/g"]);
+        assert_eq!(destinations(&["/a/f"], "/d", true).unwrap(), vec!["/d/f"]);
+        assert_eq!(destinations(&["/a/sub/", "x"], "/d/", true).unwrap(), vec!["/d/sub", "/d/x"]);
+        /* several sources need a directory to go in */
+        assert!(matches!(destinations(&["a", "b"], "/g", false), Err(Error::NotADirectory)));
+    }
+
+    #[test]
+    fn copy_into_itself() {
+        let mut data = vec![0u8; 4 << 20];
+        ImageBuilder::new(4 << 20).format(&mut data).unwrap();
+        let path = ::std::env::temp_dir().join(format!("exfat-tool-cp-{}.img", process::id()));
+        host::write(&path, &data).unwrap();
+        let fs = common::open_rw(path.to_str().unwrap()).unwrap();
+        xfs::create_dir_all(&fs, "/a/sub").unwrap();
+
+        for d in &["/a", "/a/b", "/A/sub/c", "/a/sub"] {
+            assert!(matches!(copy(&fs, Loc::Image("/a"), Loc::Image(d), true), Err(Error::InvalidInput(_))), "{}", d);
+        }
+        assert!(matches!(copy(&fs, Loc::Image("/"), Loc::Image("/x"), true), Err(Error::InvalidInput(_))));
+        assert!(xfs::metadata(&fs, "/a/b").is_err());
+        assert_eq!(Loc::Image("/a").list(&fs).unwrap(), vec!["sub"]);
+
+        /* a sibling whose name starts the same way is fine */
+        copy(&fs, Loc::Image("/a"), Loc::Image("/ab"), true).unwrap();
+        assert!(xfs::metadata(&fs, "/ab/sub").unwrap().is_dir());
+        drop(fs);
+        host::remove_file(&path).unwrap();
+    }
+}
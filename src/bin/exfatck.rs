@@ -0,0 +1,292 @@
+/*
+ * Check (and optionally repair) an exFAT volume, like fsck
+ */
+extern crate exfat;
+extern crate clap;
+use ::clap::{App,Arg,ArgMatches,ErrorKind};
+use ::exfat::{CheckReport,Fixes,Fs,RepairReport,Severity};
+use ::std::fs::OpenOptions;
+use ::std::process;
+
+/* exit codes, as used by fsck(8). They are or-ed together. */
+const EXIT_OK: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_OPERATIONAL: i32 = 8;
+const EXIT_USAGE: i32 = 16;
+
+struct Outcome {
+    check: CheckReport,
+    /// The repairs made, and a check of the volume afterwards
+    repair: Option<(RepairReport, CheckReport)>,
+}
+
+impl Outcome {
+    fn exit_code(&self) -> i32 {
+        let has_errors = |r: &CheckReport| r.worst() == Some(Severity::Error);
+        match self.repair {
+            None => if has_errors(&self.check) { EXIT_UNCORRECTED } else { EXIT_OK },
+            Some((ref r, ref after)) => {
+                let mut code = EXIT_OK;
+                if !r.repairs().is_empty() {
+                    code |= EXIT_CORRECTED;
+                }
+                if has_errors(after) {
+                    code |= EXIT_UNCORRECTED;
+                }
+                code
+            },
+        }
+    }
+}
+
+/// Check the first exFAT volume in `path`, repairing it if `fixes` is given
+fn run(path: &str, fixes: Option<Fixes>) -> exfat::Result<Outcome> {
+    let file = OpenOptions::new().read(true).write(fixes.is_some()).open(path)?;
+    let fixes = match fixes {
+        None => {
+            let fs = Fs::from_ro(exfat::partition::open_exfat(&file)?)?;
+            return Ok(Outcome { check: fs.check()?, repair: None });
+        },
+        Some(f) => f,
+    };
+
+    let r = {
+        let mut fs = Fs::from_rw(exfat::partition::open_exfat(&file)?)?;
+        fs.repair(fixes, false)?
+    };
+
+    /* open the volume again, so everything is read back from disk */
+    let after = Fs::from_ro(exfat::partition::open_exfat(&file)?)?.check()?;
+    Ok(Outcome { check: r.check().clone(), repair: Some((r, after)) })
+}
+
+fn summary(r: &CheckReport) -> String {
+    let count = |s| r.findings().iter().filter(|f| f.severity() == s).count();
+    if r.is_clean() {
+        return "no problems".to_owned();
+    }
+    format!("{} errors, {} warnings", count(Severity::Error), count(Severity::Warning))
+}
+
+fn print_human(path: &str, o: &Outcome) {
+    let c = &o.check;
+    println!("{}: {} files, {} directories, {} clusters in use",
+             path, c.files(), c.directories(), c.clusters_in_use());
+    for f in c.findings() {
+        println!("{}", f);
+    }
+
+    match o.repair {
+        None => println!("{}: {} found", path, summary(c)),
+        Some((ref r, ref after)) => {
+            for fix in r.repairs() {
+                println!("fixed: {}", fix);
+            }
+            println!("{}: {} repairs made, {} remaining", path, r.repairs().len(), summary(after));
+        },
+    }
+}
+
+/// `s` as a JSON string literal
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_opt(s: Option<String>) -> String {
+    s.map(|s| json_str(&s)).unwrap_or_else(|| "null".to_owned())
+}
+
+fn json_findings(r: &CheckReport) -> String {
+    let v: Vec<String> = r.findings().iter().map(|f| {
+        format!("{{\"severity\":{},\"kind\":{},\"message\":{},\"path\":{},\"location\":{}}}",
+                json_str(&f.severity().to_string()), json_str(f.problem().kind()), json_str(&f.problem().to_string()),
+                json_opt(f.path().map(|p| p.to_owned())), json_opt(f.location().map(|l| l.to_string())))
+    }).collect();
+    format!("[{}]", v.join(","))
+}
+
+/// The report printed by `--json`
+fn json(path: &str, o: &Outcome) -> String {
+    let c = &o.check;
+    let mut fields = vec![
+        format!("\"device\":{}", json_str(path)),
+        format!("\"files\":{}", c.files()),
+        format!("\"directories\":{}", c.directories()),
+        format!("\"clusters_in_use\":{}", c.clusters_in_use()),
+        format!("\"findings\":{}", json_findings(c)),
+    ];
+    if let Some((ref r, ref after)) = o.repair {
+        let v: Vec<String> = r.repairs().iter().map(|fix| {
+            format!("{{\"fix\":{},\"action\":{},\"path\":{}}}",
+                    json_str(&format!("{:?}", fix.fix())), json_str(fix.action()),
+                    json_opt(fix.finding().path().map(|p| p.to_owned())))
+        }).collect();
+        fields.push(format!("\"repairs\":[{}]", v.join(",")));
+        fields.push(format!("\"remaining\":{}", json_findings(after)));
+    }
+    fields.push(format!("\"exit_code\":{}", o.exit_code()));
+    format!("{{{}}}", fields.join(","))
+}
+
+/// The report printed by `--json` when the volume can't be checked
+fn json_error(path: &str, e: &exfat::Error) -> String {
+    format!("{{\"device\":{},\"error\":{},\"exit_code\":{}}}",
+            json_str(path), json_str(&e.to_string()), EXIT_OPERATIONAL)
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("exfatck")
+        .about("Check an exFAT volume (in an image, device, or partitioned disk) for consistency")
+        .arg(Arg::with_name("device")
+             .value_name("DEVICE")
+             .help("image file or block device to check")
+             .required(true))
+        .arg(Arg::with_name("no")
+             .short("n")
+             .help("check only, opening the volume read-only (the default)"))
+        .arg(Arg::with_name("preen")
+             .short("p")
+             .help("automatically make repairs which can't lose data")
+             .conflicts_with("no"))
+        .arg(Arg::with_name("yes")
+             .short("y")
+             .help("make every available repair, saving lost clusters in /FOUND.000")
+             .conflicts_with_all(&["no", "preen"]))
+        .arg(Arg::with_name("json")
+             .long("json")
+             .help("print the results as JSON"))
+}
+
+/// The exit code for a command line that couldn't be parsed (or asked for `--help`)
+fn usage_exit_code(e: &clap::Error) -> i32 {
+    match e.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => EXIT_OK,
+        _ => EXIT_USAGE,
+    }
+}
+
+/// The repairs the `-p` & `-y` options ask for, if any
+fn fixes(matches: &ArgMatches) -> Option<Fixes> {
+    if matches.is_present("yes") {
+        Some(Fixes::all())
+    } else if matches.is_present("preen") {
+        Some(Fixes::SAFE)
+    } else {
+        None
+    }
+}
+
+fn main() {
+    let matches = app().get_matches_safe().unwrap_or_else(|e| {
+        let code = usage_exit_code(&e);
+        if code == EXIT_OK {
+            println!("{}", e.message);
+        } else {
+            eprintln!("{}", e.message);
+        }
+        process::exit(code);
+    });
+
+    let path = matches.value_of("device").unwrap();
+    match run(path, fixes(&matches)) {
+        Ok(o) => {
+            if matches.is_present("json") {
+                println!("{}", json(path, &o));
+            } else {
+                print_human(path, &o);
+            }
+            process::exit(o.exit_code());
+        },
+        Err(e) => {
+            if matches.is_present("json") {
+                println!("{}", json_error(path, &e));
+            } else {
+                eprintln!("{}: {}", path, e);
+            }
+            process::exit(EXIT_OPERATIONAL);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::exfat::ImageBuilder;
+    use ::std::fs;
+    use ::std::path::{Path,PathBuf};
+
+    /// A new volume in a file, with the main boot region damaged if `damaged`
+    fn image(name: &str, damaged: bool) -> PathBuf {
+        let mut data = vec![0u8; 8 << 20];
+        ImageBuilder::new(8 << 20).format(&mut data).unwrap();
+        if damaged {
+            /* the extended boot sectors are only covered by the checksum */
+            data[512] = 1;
+        }
+        let path = ::std::env::temp_dir().join(format!("exfatck-{}-{}.img", name, process::id()));
+        fs::write(&path, &data).unwrap();
+        path
+    }
+
+    fn exit_code(path: &Path, fixes: Option<Fixes>) -> i32 {
+        run(path.to_str().unwrap(), fixes).unwrap().exit_code()
+    }
+
+    #[test]
+    fn exit_codes() {
+        let clean = image("clean", false);
+        assert_eq!(exit_code(&clean, None), EXIT_OK);
+        assert_eq!(exit_code(&clean, Some(Fixes::SAFE)), EXIT_OK);
+
+        let damaged = image("damaged", true);
+        assert_eq!(exit_code(&damaged, None), EXIT_UNCORRECTED);
+        assert_eq!(exit_code(&damaged, Some(Fixes::empty())), EXIT_UNCORRECTED);
+        assert_eq!(exit_code(&damaged, Some(Fixes::SAFE)), EXIT_CORRECTED);
+        assert_eq!(exit_code(&damaged, None), EXIT_OK);
+        fs::remove_file(&clean).unwrap();
+        fs::remove_file(&damaged).unwrap();
+
+        assert!(run("/nonexistent/exfatck.img", None).is_err());
+
+        let usage = |args: &[&str]| app().get_matches_from_safe(args).map(|_| EXIT_OK)
+            .unwrap_or_else(|e| usage_exit_code(&e));
+        assert_eq!(usage(&["exfatck", "-p", "disk.img"]), EXIT_OK);
+        assert_eq!(usage(&["exfatck", "--help"]), EXIT_OK);
+        assert_eq!(usage(&["exfatck"]), EXIT_USAGE);
+        assert_eq!(usage(&["exfatck", "-n", "-y", "disk.img"]), EXIT_USAGE);
+    }
+
+    #[test]
+    fn json_report() {
+        assert_eq!(json_str("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+        assert_eq!(json_error("x", &exfat::Error::NotFound),
+                   format!("{{\"device\":\"x\",\"error\":{},\"exit_code\":8}}",
+                           json_str(&exfat::Error::NotFound.to_string())));
+
+        let path = image("json", true);
+        let p = path.to_str().unwrap();
+        let j = json(p, &run(p, None).unwrap());
+        assert!(j.starts_with(&format!("{{\"device\":{},\"files\":0,\"directories\":1,", json_str(p))), "{}", j);
+        assert!(j.contains("{\"severity\":\"error\",\"kind\":\"BootChecksum\",\"message\":\"main boot region checksum mismatch\",\"path\":null,\"location\":\"sector 11\"}"), "{}", j);
+        assert!(!j.contains("\"repairs\""));
+        assert!(j.ends_with(",\"exit_code\":4}"), "{}", j);
+
+        let j = json(p, &run(p, Some(Fixes::SAFE)).unwrap());
+        assert!(j.contains("\"repairs\":[{\"fix\":\"RESTORE_BOOT_REGION\","), "{}", j);
+        assert!(j.contains(",\"remaining\":[],\"exit_code\":1}"), "{}", j);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use ::io_at::{ReadAt,WriteAt};
+use super::{EntrySet,Error,FatEntry,Fs,Result,Stream};
+
+/// The allocation bitmap records which clusters in the cluster heap are in use.
+///
+/// Each bit corresponds to a cluster, starting with cluster 2 as the lowest bit of the first byte.
+/// The bitmap (and not the FAT) is the authority on which clusters are free.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Bitmap {
+    v: Vec<u8>,
+    cluster_count: u32,
+    /// Kept up to date by `set_allocated()`, so `free_count()` doesn't scan the bitmap
+    free: u32,
+}
+
+impl Bitmap {
+    pub fn from(v: Vec<u8>, cluster_count: u32) -> Self {
+        let mut b = Bitmap { v, cluster_count, free: 0 };
+        b.free = (2..(cluster_count + 2)).filter(|c| !b.is_allocated(*c)).count() as u32;
+        b
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.v
+    }
+
+    /// Clusters outside of the cluster heap are always considered allocated
+    pub fn is_allocated(&self, cluster: u32) -> bool {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return true;
+        }
+        let i = cluster - 2;
+        self.v[(i / 8) as usize] & (1 << (i % 8)) != 0
+    }
+
+    pub fn set_allocated(&mut self, cluster: u32, allocated: bool) {
+        if self.is_allocated(cluster) == allocated {
+            return;
+        }
+        let i = cluster - 2;
+        let b = &mut self.v[(i / 8) as usize];
+        if allocated {
+            *b |= 1 << (i % 8);
+            self.free -= 1;
+        } else {
+            *b &= !(1 << (i % 8));
+            self.free += 1;
+        }
+    }
+
+    /// Find the first free cluster at or after `from`, wrapping around to the start of the cluster
+    /// heap if needed
+    pub fn find_free(&self, from: u32) -> Option<u32> {
+        let last = self.cluster_count + 2;
+        let from = if from < 2 || from >= last { 2 } else { from };
+        (from..last).chain(2..from).find(|c| !self.is_allocated(*c))
+    }
+
+    /// Find the first run of `len` free clusters
+    pub fn find_free_run(&self, len: u32) -> Option<u32> {
+        let mut start = 2;
+        let mut run = 0;
+        for c in 2..(self.cluster_count + 2) {
+            if self.is_allocated(c) {
+                run = 0;
+                start = c + 1;
+            } else {
+                run += 1;
+                if run == len {
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+
+    /// Number of clusters not in use
+    pub fn free_count(&self) -> u32 {
+        self.free
+    }
+}
+
+pub(crate) fn no_space() -> Error {
+    Error::NoSpace("no free clusters remain on the volume")
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Mark a cluster as allocated or free, both in memory & on disk
+    pub(crate) fn mark_cluster(&self, cluster: u32, allocated: bool) -> Result<()> {
+        self.bitmap.borrow_mut().set_allocated(cluster, allocated);
+
+        let byte = ((cluster - 2) / 8) as u64;
+        let cs = self.boot_sector().bytes_per_cluster();
+        let c = self.stream_cluster(&self.bitmap_stream, byte / cs, None)?;
+        let v = self.bitmap.borrow().raw()[byte as usize];
+        self.write_at(&[v], self.boot_sector().cluster_offs(c) + byte % cs)
+    }
+
+    /// Allocate `n` contiguous clusters, returning the first. `None` if no run is long enough.
+    pub(crate) fn alloc_run(&self, n: u32) -> Result<Option<u32>> {
+        let first = match self.bitmap.borrow().find_free_run(n) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+
+        for c in first..(first + n) {
+            self.mark_cluster(c, true)?;
+        }
+        Ok(Some(first))
+    }
+
+    /// Extend a contiguous allocation ending at `last` by `n` clusters, if the clusters following
+    /// it are free.
+    pub(crate) fn alloc_after(&self, last: u32, n: u32) -> Result<bool> {
+        let free = {
+            let b = self.bitmap.borrow();
+            (1..(n as u64 + 1)).all(|i| {
+                last as u64 + i <= u32::MAX as u64 && !b.is_allocated(last + i as u32)
+            })
+        };
+        if !free {
+            return Ok(false);
+        }
+
+        for c in (last + 1)..(last + 1 + n) {
+            self.mark_cluster(c, true)?;
+        }
+        Ok(true)
+    }
+
+    /// Allocate a new stream of `len` bytes, contiguous if possible
+    pub(crate) fn alloc_stream(&self, len: u64) -> Result<Stream> {
+        let n = len.div_ceil(self.boot_sector().bytes_per_cluster());
+        if n > u32::MAX as u64 {
+            return Err(no_space());
+        }
+
+        let (first_cluster, no_fat_chain) = if n == 0 {
+            (0, false)
+        } else if let Some(first) = self.alloc_run(n as u32)? {
+            (first, true)
+        } else {
+            (self.alloc_chain(n as u32, None)?[0], false)
+        };
+        Ok(Stream { first_cluster, no_fat_chain, data_len: len, valid_data_len: len })
+    }
+
+    /// Allocate `n` clusters and link them into a FAT chain. If `prev` is given, it is linked to
+    /// the first of the new clusters.
+    pub(crate) fn alloc_chain(&self, n: u32, prev: Option<u32>) -> Result<Vec<u32>> {
+        if self.bitmap.borrow().free_count() < n {
+            return Err(no_space());
+        }
+
+        let mut v = Vec::with_capacity(n as usize);
+        let mut hint = prev.map(|p| p + 1).unwrap_or(2);
+        for _ in 0..n {
+            let c = self.bitmap.borrow().find_free(hint).ok_or_else(no_space)?;
+            self.mark_cluster(c, true)?;
+            v.push(c);
+            hint = c + 1;
+        }
+
+        let mut p = prev;
+        for c in &v {
+            if let Some(p) = p {
+                self.set_fat(p, FatEntry::from_val(*c))?;
+            }
+            p = Some(*c);
+        }
+        if let Some(l) = v.last() {
+            self.set_fat(*l, FatEntry::from_val(0xFFFF_FFFF))?;
+        }
+        Ok(v)
+    }
+
+    /// Record the clusters of a contiguous stream in the FAT, so that it may be extended with
+    /// non-contiguous clusters.
+    pub(crate) fn chain_contiguous(&self, first: u32, n: u32) -> Result<()> {
+        for c in first..(first + n) {
+            let next = if c + 1 == first + n { 0xFFFF_FFFF } else { c + 1 };
+            self.set_fat(c, FatEntry::from_val(next))?;
+        }
+        Ok(())
+    }
+
+    /// Allocate clusters so that the stream described by `set` is `len` bytes long, updating
+    /// `set` (but not writing it to disk). `last` is a known (index, cluster) pair within the
+    /// stream, if any.
+    pub(crate) fn grow_set(&self, set: &mut EntrySet, len: u64, last: Option<(u64, u32)>) -> Result<()> {
+        let cs = self.boot_sector().bytes_per_cluster();
+        let have = set.data_len().div_ceil(cs);
+        let need = len.div_ceil(cs);
+        if need > have {
+            if need > u32::MAX as u64 {
+                return Err(no_space());
+            }
+
+            let n = (need - have) as u32;
+            let stream = set.stream();
+            if have == 0 {
+                /* prefer a contiguous allocation, which doesn't need the FAT */
+                if let Some(first) = self.alloc_run(n)? {
+                    set.set_first_cluster(first);
+                    set.set_no_fat_chain(true);
+                } else {
+                    let v = self.alloc_chain(n, None)?;
+                    set.set_first_cluster(v[0]);
+                    set.set_no_fat_chain(false);
+                }
+            } else {
+                let last = self.stream_cluster(&stream, have - 1, last)?;
+                if !stream.no_fat_chain {
+                    self.alloc_chain(n, Some(last))?;
+                } else if !self.alloc_after(last, n)? {
+                    /* can't stay contiguous, switch to using the FAT */
+                    self.chain_contiguous(stream.first_cluster, have as u32)?;
+                    self.alloc_chain(n, Some(last))?;
+                    set.set_no_fat_chain(false);
+                }
+            }
+        }
+
+        set.set_data_len(len);
+        Ok(())
+    }
+
+    /// Check that `stream`'s clusters are all in the cluster heap, so that `free_clusters()`
+    /// won't fail part way through a change
+    pub(crate) fn check_stream(&self, stream: &Stream) -> Result<()> {
+        let n = stream.data_len.div_ceil(self.boot_sector().bytes_per_cluster());
+        if n > 0 {
+            self.stream_cluster(stream, n - 1, None)?;
+        }
+        Ok(())
+    }
+
+    /// Free every cluster of `stream` after the first `keep` clusters. `stream.data_len` must
+    /// reflect the current allocation.
+    pub(crate) fn free_clusters(&self, stream: &Stream, keep: u64) -> Result<()> {
+        let cs = self.boot_sector().bytes_per_cluster();
+        let have = stream.data_len.div_ceil(cs);
+        if have <= keep {
+            return Ok(());
+        }
+
+        if stream.no_fat_chain {
+            /* the run's ends are in the heap, so everything between them is too */
+            let last = self.stream_cluster(stream, have - 1, None)?;
+            for c in (stream.first_cluster + keep as u32)..=last {
+                self.mark_cluster(c, false)?;
+            }
+            return Ok(());
+        }
+
+        let mut last = None;
+        let mut clusters = Vec::new();
+        for i in keep.saturating_sub(1)..have {
+            let c = self.stream_cluster(stream, i, last)?;
+            last = Some((i, c));
+            clusters.push(c);
+        }
+
+        if keep > 0 {
+            self.set_fat(clusters.remove(0), FatEntry::from_val(0xFFFF_FFFF))?;
+        }
+        for c in clusters {
+            self.mark_cluster(c, false)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_free() {
+        let mut b = Bitmap::from(vec![0b1011_0111, 0], 12);
+        assert_eq!(b.find_free(2), Some(5));
+        assert_eq!(b.find_free(6), Some(8));
+        assert_eq!(b.find_free_run(3), Some(10));
+        assert_eq!(b.find_free_run(5), None);
+        assert_eq!(b.free_count(), 6);
+        b.set_allocated(5, true);
+        assert!(b.is_allocated(5));
+        assert!(b.is_allocated(14));
+        /* the count follows changes, but not repeated ones */
+        b.set_allocated(5, true);
+        assert_eq!(b.free_count(), 5);
+        b.set_allocated(2, false);
+        b.set_allocated(2, false);
+        assert_eq!(b.free_count(), 6);
+    }
+}
@@ -0,0 +1,276 @@
+/*
+ * Access to stores (such as raw block devices opened with O_DIRECT) which only permit I/O of
+ * whole, aligned, logical blocks.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::io_block::BlockSize;
+use ::std::{cmp,io,slice};
+use super::{Error,Fs,Result};
+use super::io_util::write_all_at;
+
+/// Largest block size supported, which is also the alignment of the buffers used for I/O
+const MAX_BLOCK_SIZE: u64 = 4096;
+
+/// Most bytes transferred to or from the underlying store in a single call
+const MAX_TRANSFER: u64 = 128 << 10;
+
+#[repr(C, align(4096))]
+#[derive(Clone,Copy)]
+struct Page([u8; MAX_BLOCK_SIZE as usize]);
+
+/// A buffer of `len` bytes, aligned for any supported block size
+struct Bounce {
+    pages: Vec<Page>,
+    len: usize,
+}
+
+impl Bounce {
+    fn new(len: u64) -> Self {
+        let n = len.div_ceil(MAX_BLOCK_SIZE) as usize;
+        Bounce { pages: vec![Page([0; MAX_BLOCK_SIZE as usize]); n], len: len as usize }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.pages.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+/// Wraps a store so that every read & write it receives is made of whole logical blocks, at
+/// block aligned offsets, into block aligned memory. Smaller writes become read-modify-write
+/// cycles of the blocks they touch.
+///
+/// Use with `Fs::from_block_ro()` or `Fs::from_block_rw()`.
+pub struct BlockStore<S> {
+    inner: S,
+    block_size: u64,
+}
+
+impl<S> BlockStore<S> {
+    /// `block_size` must be a power of two between 512 and 4096 bytes
+    pub fn new(inner: S, block_size: u64) -> Result<Self> {
+        if !block_size.is_power_of_two() || !(512..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(Error::Unsupported("block size must be a power of two from 512 to 4096"));
+        }
+        Ok(BlockStore { inner, block_size })
+    }
+
+    /// Use the logical block size reported by `dev`, which is usually the device `inner` accesses
+    pub fn with_block_size_of<B: BlockSize>(inner: S, dev: &B) -> Result<Self> {
+        BlockStore::new(inner, dev.block_size_logical()?)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// The block aligned range covering `len` bytes at `offs`, limited to `MAX_TRANSFER` bytes
+    fn span(&self, offs: u64, len: usize) -> io::Result<(u64, u64)> {
+        let bs = self.block_size;
+        let start = offs / bs * bs;
+        let end = offs.checked_add(len as u64).and_then(|e| e.div_ceil(bs).checked_mul(bs))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset is past the end of any store"))?;
+        Ok((start, cmp::min(end, start.saturating_add(MAX_TRANSFER))))
+    }
+
+    fn is_aligned(&self, buf: &[u8], offs: u64) -> bool {
+        let bs = self.block_size;
+        offs.is_multiple_of(bs) && (buf.len() as u64).is_multiple_of(bs)
+            && (buf.as_ptr() as u64).is_multiple_of(bs)
+    }
+}
+
+impl BlockStore<::std::fs::File> {
+    /// Use a block device (for example `/dev/sdb1`), querying it's logical block size from the
+    /// operating system. Open the device with `O_DIRECT` to bypass the page cache.
+    pub fn from_device(file: ::std::fs::File) -> Result<Self> {
+        let dev = ::io_block::os::BlockDev::from_file(file.try_clone()?)?;
+        BlockStore::with_block_size_of(file, &dev)
+    }
+}
+
+/// Read as much of `buf` as `s` has, stopping early only at the end of the store
+fn read_available<S: ReadAt>(s: &S, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match s.read_at(&mut buf[done..], offs + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+impl<S: ReadAt> ReadAt for BlockStore<S> {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.is_aligned(buf, offs) {
+            return self.inner.read_at(buf, offs);
+        }
+
+        let (start, end) = self.span(offs, buf.len())?;
+        let mut b = Bounce::new(end - start);
+        let got = read_available(&self.inner, b.bytes(), start)? as u64;
+        let skip = offs - start;
+        if got <= skip {
+            return Ok(0);
+        }
+
+        let n = cmp::min(buf.len() as u64, got - skip) as usize;
+        buf[..n].copy_from_slice(&b.bytes()[skip as usize..(skip as usize + n)]);
+        Ok(n)
+    }
+}
+
+impl<S: ReadAt + WriteAt> WriteAt for BlockStore<S> {
+    fn write_at(&mut self, buf: &[u8], offs: u64) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.is_aligned(buf, offs) {
+            return self.inner.write_at(buf, offs);
+        }
+
+        let bs = self.block_size;
+        let (start, end) = self.span(offs, buf.len())?;
+        let mut b = Bounce::new(end - start);
+        let skip = (offs - start) as usize;
+        let n = cmp::min(buf.len(), (end - offs) as usize);
+
+        /* keep the existing contents of partially written blocks (none past the store's end) */
+        if skip != 0 {
+            read_available(&self.inner, &mut b.bytes()[..bs as usize], start)?;
+        }
+        if !((skip + n) as u64).is_multiple_of(bs) {
+            let last = (end - start - bs) as usize;
+            if last != 0 || skip == 0 {
+                read_available(&self.inner, &mut b.bytes()[last..], end - bs)?;
+            }
+        }
+
+        b.bytes()[skip..(skip + n)].copy_from_slice(&buf[..n]);
+        write_all_at(&mut self.inner, b.bytes(), start)?;
+        Ok(n)
+    }
+}
+
+impl<S: ReadAt> Fs<BlockStore<S>> {
+    /// Like `from_ro()`, failing if the volume's sectors are smaller than the store's blocks
+    pub fn from_block_ro(store: BlockStore<S>) -> Result<Self> {
+        let fs = Fs::from_ro(store)?;
+        check_sector_size(&fs)?;
+        Ok(fs)
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<BlockStore<S>> {
+    /// Like `from_rw()`, failing if the volume's sectors are smaller than the store's blocks
+    pub fn from_block_rw(store: BlockStore<S>) -> Result<Self> {
+        let fs = Fs::from_rw(store)?;
+        check_sector_size(&fs)?;
+        Ok(fs)
+    }
+}
+
+/// A volume formatted for smaller sectors than the device has can't keep sector sized structures
+/// (such as the boot region) independent of each other
+fn check_sector_size<S: ReadAt>(fs: &Fs<BlockStore<S>>) -> Result<()> {
+    if fs.boot_sector().bytes_per_sector() < fs.store().block_size() {
+        return Err(Error::Unsupported(
+            "volume sector size is smaller than the device's logical block size"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::io_util::read_exact_at;
+    use ::std::cell::Cell;
+    use ::testutil::Image;
+
+    /// Fails any access that isn't of whole, aligned, 512 byte blocks
+    struct Strict<'a> {
+        data: &'a mut Vec<u8>,
+        calls: Cell<usize>,
+    }
+
+    impl<'a> Strict<'a> {
+        fn check(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+            self.calls.set(self.calls.get() + 1);
+            if !offs.is_multiple_of(512) || !buf.len().is_multiple_of(512)
+                || !(buf.as_ptr() as usize).is_multiple_of(512) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "unaligned access"));
+            }
+            Ok(())
+        }
+    }
+
+    impl<'a> ReadAt for Strict<'a> {
+        fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+            self.check(buf, offs)?;
+            self.data.read_at(buf, offs)
+        }
+    }
+
+    impl<'a> WriteAt for Strict<'a> {
+        fn write_at(&mut self, buf: &[u8], offs: u64) -> io::Result<usize> {
+            self.check(buf, offs)?;
+            self.data.write_at(buf, offs)
+        }
+    }
+
+    #[test]
+    fn read_modify_write() {
+        let mut v = vec![1u8; 2048];
+        {
+            let mut s = BlockStore::new(Strict { data: &mut v, calls: Cell::new(0) }, 512).unwrap();
+            write_all_at(&mut s, &[2; 600], 1000).unwrap();
+            let mut b = [0u8; 700];
+            read_exact_at(&s, &mut b, 950).unwrap();
+            assert_eq!(&b[..50], &[1; 50][..]);
+            assert_eq!(&b[50..650], &[2; 600][..]);
+            assert_eq!(&b[650..], &[1; 50][..]);
+
+            /* a read past the end returns what there is */
+            assert_eq!(s.read_at(&mut b, 2000).unwrap(), 48);
+            assert_eq!(s.read_at(&mut b, u64::MAX - 10).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(s.write_at(&b, u64::MAX - 10).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert!(BlockStore::new(Vec::<u8>::new(), 768).is_err());
+        }
+        assert_eq!(v.len(), 2048);
+        assert_eq!(v.iter().filter(|b| **b == 2).count(), 600);
+    }
+
+    #[test]
+    fn filesystem_on_block_store() {
+        let mut img = Image::new();
+        {
+            let s = BlockStore::new(Strict { data: &mut img.data, calls: Cell::new(0) }, 512).unwrap();
+            let fs = Fs::from_block_rw(s).unwrap();
+            ::fs::create_dir(&fs, "d").unwrap();
+            ::fs::write(&fs, "d/f", b"block aligned").unwrap();
+            fs.set_volume_label("blocks").unwrap();
+            assert!(fs.store().get_ref().calls.get() > 0);
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        assert_eq!(::fs::read(&fs, "d/f").unwrap(), b"block aligned");
+        assert_eq!(fs.volume_label().unwrap(), "blocks");
+
+        /* the test image has 512 byte sectors */
+        let s = BlockStore::new(&img.data, 4096).unwrap();
+        assert!(matches!(Fs::from_block_ro(s), Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,372 @@
+/*
+ * Creating volumes: formatting, and building reproducible images from a directory on the host
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::fs as host;
+use ::std::io::{self,BufWriter,Write};
+use ::std::path::Path;
+use ::std::time::{Duration,UNIX_EPOCH};
+use io_util::write_all_at;
+use super::{checksum32,entry_type,Error,FileAttributes,Fs,Result,Timestamp,UpcaseTable};
+use super::check::boot_checksum;
+
+const BYTES_PER_SECTOR_SHIFT: u8 = 9;
+const BYTES_PER_SECTOR: u64 = 1 << BYTES_PER_SECTOR_SHIFT;
+/// Sectors before the FAT: both boot regions
+const FAT_OFFS: u64 = 24;
+/// Largest ClusterCount the spec allows
+const MAX_CLUSTER_COUNT: u64 = 0xFFFF_FFF5;
+
+/// 1980-01-01 00:00:00 UTC, the earliest time a timestamp can hold
+fn start_of_1980() -> Timestamp {
+    Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(315_532_800), Some(0)).unwrap_or_default()
+}
+
+/// Where the timestamps of files & directories added by `ImageBuilder::build()` come from
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum TimeSource {
+    /// Every timestamp is the same
+    Fixed(Timestamp),
+    /// Each timestamp is the last modification time of the file or directory on the host. Times
+    /// before 1980 are recorded as the start of 1980.
+    Modified,
+}
+
+/// Formats volumes, and builds them from a directory on the host.
+///
+/// Everything that would otherwise vary between runs is fixed: the volume serial number, the
+/// timestamps (see `TimeSource`), the order entries are added in (sorted by name), and so the
+/// clusters allocated to each. Given the same directory, the same options, and a store which is
+/// initially zeroed, the resulting image is byte for byte identical. The up-case table is
+/// `UpcaseTable::generate()`, so this holds between builds using the same unicode version.
+#[derive(Clone,Debug)]
+pub struct ImageBuilder {
+    size: u64,
+    cluster_size: Option<u64>,
+    serial: u32,
+    label: String,
+    times: TimeSource,
+}
+
+impl ImageBuilder {
+    /// A volume `size` bytes long, with a serial number of 0, no label, and every timestamp set
+    /// to 1980-01-01 00:00:00 UTC
+    pub fn new(size: u64) -> Self {
+        ImageBuilder {
+            size,
+            cluster_size: None,
+            serial: 0,
+            label: String::new(),
+            times: TimeSource::Fixed(start_of_1980()),
+        }
+    }
+
+    /// Bytes per cluster, a power of two from 512 bytes to 32MiB. By default this depends on the
+    /// size of the volume, as recommended by Microsoft.
+    pub fn cluster_size(&mut self, cluster_size: u64) -> &mut Self {
+        self.cluster_size = Some(cluster_size);
+        self
+    }
+
+    pub fn serial(&mut self, serial: u32) -> &mut Self {
+        self.serial = serial;
+        self
+    }
+
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = label.to_owned();
+        self
+    }
+
+    pub fn times(&mut self, times: TimeSource) -> &mut Self {
+        self.times = times;
+        self
+    }
+
+    /// (sectors per cluster shift, FAT length, cluster heap offset, cluster count). Lengths &
+    /// offsets are in sectors.
+    fn layout(&self) -> Result<(u8, u64, u64, u64)> {
+        let cs = self.cluster_size.unwrap_or(match self.size {
+            s if s <= 256 << 20 => 4 << 10,
+            s if s <= 32 << 30 => 32 << 10,
+            _ => 128 << 10,
+        });
+        if !cs.is_power_of_two() || !(BYTES_PER_SECTOR..=(32 << 20)).contains(&cs) {
+            return Err(Error::InvalidInput("cluster size must be a power of two from 512 bytes to 32MiB"));
+        }
+        let spc = cs / BYTES_PER_SECTOR;
+        let sectors = self.size / BYTES_PER_SECTOR;
+        if sectors < (1 << 20) / BYTES_PER_SECTOR {
+            return Err(Error::InvalidInput("volume must be at least 1MiB"));
+        }
+
+        /* the FAT's length depends on the cluster count, which depends on the FAT's length */
+        let mut cc = sectors.saturating_sub(FAT_OFFS) / spc;
+        loop {
+            let fat_len = ((cc + 2) * 4).div_ceil(BYTES_PER_SECTOR);
+            let heap = (FAT_OFFS + fat_len).next_multiple_of(spc);
+            let fit = sectors.saturating_sub(heap) / spc;
+            if fit >= cc {
+                break;
+            }
+            cc = fit;
+        }
+
+        let fat_len = ((cc + 2) * 4).div_ceil(BYTES_PER_SECTOR);
+        let heap = (FAT_OFFS + fat_len).next_multiple_of(spc);
+        if cc > MAX_CLUSTER_COUNT {
+            return Err(Error::InvalidInput("volume is too large for the cluster size"));
+        }
+        Ok((spc.trailing_zeros() as u8, fat_len, heap, cc))
+    }
+
+    /// Write an empty volume to `store`, and open it.
+    ///
+    /// Only the boot regions, FAT, allocation bitmap, up-case table, and root directory are
+    /// written. The rest of `store` is left as it is.
+    pub fn format<S: ReadAt + WriteAt>(&self, mut store: S) -> Result<Fs<S>> {
+        let (spc_shift, fat_len, heap, cc) = self.layout()?;
+        let cs = BYTES_PER_SECTOR << spc_shift;
+        let upcase = UpcaseTable::generate().to_raw();
+        let bitmap_len = cc.div_ceil(8);
+
+        /* the bitmap, up-case table, and root directory fill the start of the cluster heap */
+        let mut next = 2u32;
+        let mut fat = vec![0u8; (fat_len * BYTES_PER_SECTOR) as usize];
+        write_num_bytes!(u32, 4, &mut fat[0..], 0xFFFF_FFF8);
+        write_num_bytes!(u32, 4, &mut fat[4..], 0xFFFF_FFFF);
+        let mut chain = |len: u64| {
+            let first = next;
+            let n = len.div_ceil(cs) as u32;
+            for c in first..(first + n) {
+                let v = if c + 1 == first + n { 0xFFFF_FFFF } else { c + 1 };
+                write_num_bytes!(u32, 4, &mut fat[(c as usize * 4)..], v);
+            }
+            next += n;
+            first
+        };
+        let bitmap_first = chain(bitmap_len);
+        let upcase_first = chain(upcase.len() as u64);
+        let root = chain(cs);
+        if next as u64 - 2 > cc {
+            return Err(Error::InvalidInput("volume is too small"));
+        }
+
+        let mut boot = vec![0u8; (12 * BYTES_PER_SECTOR) as usize];
+        {
+            let bs = &mut boot[..(BYTES_PER_SECTOR as usize)];
+            bs[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+            bs[3..11].copy_from_slice(b"EXFAT   ");
+            write_num_bytes!(u64, 8, &mut bs[72..], self.size / BYTES_PER_SECTOR);
+            write_num_bytes!(u32, 4, &mut bs[80..], FAT_OFFS as u32);
+            write_num_bytes!(u32, 4, &mut bs[84..], fat_len as u32);
+            write_num_bytes!(u32, 4, &mut bs[88..], heap as u32);
+            write_num_bytes!(u32, 4, &mut bs[92..], cc as u32);
+            write_num_bytes!(u32, 4, &mut bs[96..], root);
+            write_num_bytes!(u32, 4, &mut bs[100..], self.serial);
+            write_num_bytes!(u16, 2, &mut bs[104..], 0x0100);
+            bs[108] = BYTES_PER_SECTOR_SHIFT;
+            bs[109] = spc_shift;
+            bs[110] = 1;
+            bs[111] = 0x80;
+            /* PercentInUse isn't maintained as files are added, so it is left as unknown */
+            bs[112] = 0xFF;
+            /* boot code: halt */
+            for b in &mut bs[120..510] {
+                *b = 0xF4;
+            }
+            bs[510] = 0x55;
+            bs[511] = 0xAA;
+        }
+        /* the extended boot sectors only need their signature */
+        for s in 1..9 {
+            let end = ((s + 1) * BYTES_PER_SECTOR) as usize;
+            boot[(end - 2)..end].copy_from_slice(&[0x55, 0xAA]);
+        }
+        let sum = boot_checksum(&boot, BYTES_PER_SECTOR as usize);
+        for c in boot[((11 * BYTES_PER_SECTOR) as usize)..].chunks_mut(4) {
+            write_num_bytes!(u32, 4, c, sum);
+        }
+        write_all_at(&mut store, &boot, 0)?;
+        write_all_at(&mut store, &boot, 12 * BYTES_PER_SECTOR)?;
+        write_all_at(&mut store, &fat, FAT_OFFS * BYTES_PER_SECTOR)?;
+
+        let cluster_offs = |c: u32| (heap + ((c - 2) as u64) * (cs / BYTES_PER_SECTOR)) * BYTES_PER_SECTOR;
+        let mut bitmap = vec![0u8; (bitmap_len.div_ceil(cs) * cs) as usize];
+        for i in 0..(next - 2) as usize {
+            bitmap[i / 8] |= 1 << (i % 8);
+        }
+        write_all_at(&mut store, &bitmap, cluster_offs(bitmap_first))?;
+        let mut table = upcase.clone();
+        table.resize((upcase.len() as u64).next_multiple_of(cs) as usize, 0);
+        write_all_at(&mut store, &table, cluster_offs(upcase_first))?;
+
+        let mut dir = vec![0u8; cs as usize];
+        dir[0] = entry_type::ALLOCATION_BITMAP;
+        write_num_bytes!(u32, 4, &mut dir[20..], bitmap_first);
+        write_num_bytes!(u64, 8, &mut dir[24..], bitmap_len);
+        dir[32] = entry_type::UPCASE_TABLE;
+        write_num_bytes!(u32, 4, &mut dir[36..], checksum32(0, &upcase));
+        write_num_bytes!(u32, 4, &mut dir[52..], upcase_first);
+        write_num_bytes!(u64, 8, &mut dir[56..], upcase.len() as u64);
+        write_all_at(&mut store, &dir, cluster_offs(root))?;
+
+        let fs = Fs::from_rw(store)?;
+        if !self.label.is_empty() {
+            fs.set_volume_label(&self.label)?;
+        }
+        Ok(fs)
+    }
+
+    /// Format `store`, then copy the files & directories within `source` into it. Anything else
+    /// (including a symbolic link) is an `Error::Unsupported`.
+    pub fn build<S: ReadAt + WriteAt>(&self, store: S, source: &Path) -> Result<Fs<S>> {
+        let fs = self.format(store)?;
+        self.add_dir(&fs, source, "")?;
+        Ok(fs)
+    }
+
+    /// Build an image file at `path`, replacing any existing file
+    pub fn build_file(&self, source: &Path, path: &Path) -> Result<()> {
+        self.layout()?;
+        let f = host::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        f.set_len(self.size)?;
+        self.build(f, source)?;
+        Ok(())
+    }
+
+    fn add_dir<S: ReadAt + WriteAt>(&self, fs: &Fs<S>, source: &Path, dir: &str) -> Result<()> {
+        let mut entries = host::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries {
+            let r = e.file_name().into_string()
+                .map_err(|_| Error::Name("file name is not valid unicode"))
+                .and_then(|name| self.add(fs, &e.path(), &format!("{}/{}", dir, name)));
+            if let Err(error) = r {
+                return Err(match error {
+                    Error::Path { .. } => error,
+                    error => Error::Path { path: e.path().display().to_string(), error: Box::new(error) },
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn add<S: ReadAt + WriteAt>(&self, fs: &Fs<S>, source: &Path, path: &str) -> Result<()> {
+        /* a link may lead outside of the source directory, or back into it */
+        let md = host::symlink_metadata(source)?;
+        if md.file_type().is_symlink() {
+            return Err(Error::Unsupported("symbolic links can't be added"));
+        } else if md.is_dir() {
+            fs.create_dir(path)?;
+            self.add_dir(fs, source, path)?;
+        } else if md.is_file() {
+            let mut f = BufWriter::with_capacity(1 << 20, fs.create_file(path)?);
+            io::copy(&mut host::File::open(source)?, &mut f)?;
+            f.flush()?;
+        } else {
+            return Err(Error::Unsupported("only files and directories can be added"));
+        }
+
+        if md.permissions().readonly() {
+            let attrs = fs.lookup(path)?.attributes();
+            fs.set_attributes(path, attrs | FileAttributes::READ_ONLY)?;
+        }
+        let t = match self.times {
+            TimeSource::Fixed(t) => t,
+            TimeSource::Modified => {
+                let m = md.modified()?;
+                match Timestamp::from_system_time(m, Some(0)) {
+                    Ok(t) => t,
+                    Err(_) if m < start_of_1980().to_system_time().unwrap_or(UNIX_EPOCH) => start_of_1980(),
+                    Err(e) => return Err(e),
+                }
+            },
+        };
+        fs.set_times(path, Some(t), Some(t), Some(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::fs as xfs;
+    use ::testutil::temp_dir;
+
+    #[test]
+    fn format_empty() {
+        let mut data = vec![0u8; 8 << 20];
+        let fs = ImageBuilder::new(8 << 20).label("EMPTY").format(&mut data).unwrap();
+        assert_eq!(fs.volume_label().unwrap(), "EMPTY");
+        assert_eq!(fs.boot_sector().bytes_per_cluster(), 4096);
+        assert_eq!(*fs.upcase_table(), UpcaseTable::generate());
+        assert!(fs.check().unwrap().is_clean());
+
+        assert!(ImageBuilder::new(16 << 10).format(vec![0u8; 16 << 10]).is_err());
+        /* nothing is written to a volume that is too small */
+        let mut small = vec![0u8; 512 << 10];
+        assert!(matches!(ImageBuilder::new(512 << 10).format(&mut small), Err(Error::InvalidInput(_))));
+        assert!(small.iter().all(|b| *b == 0));
+        assert!(ImageBuilder::new(8 << 20).cluster_size(3000).format(&mut data).is_err());
+    }
+
+    #[test]
+    fn reproducible() {
+        let src = temp_dir("build");
+        host::create_dir_all(src.join("b/c")).unwrap();
+        host::write(src.join("a.txt"), b"first").unwrap();
+        host::write(src.join("b/big"), vec![7u8; 100_000]).unwrap();
+        host::write(src.join("b/c/z"), b"").unwrap();
+        host::write(src.join("b/B2"), b"ro").unwrap();
+        let mut perms = host::metadata(src.join("b/B2")).unwrap().permissions();
+        perms.set_readonly(true);
+        host::set_permissions(src.join("b/B2"), perms).unwrap();
+
+        let build = || {
+            let mut data = vec![0u8; 4 << 20];
+            ImageBuilder::new(4 << 20).cluster_size(1024).serial(0xC0FFEE).build(&mut data, &src).unwrap();
+            data
+        };
+        let img = build();
+        assert!(img == build());
+
+        let fs = Fs::from_ro(&img).unwrap();
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.volume_serial_num(), 0xC0FFEE);
+        assert_eq!(xfs::read(&fs, "a.txt").unwrap(), b"first");
+        assert_eq!(xfs::read(&fs, "b/big").unwrap(), vec![7u8; 100_000]);
+        let names: Vec<String> = xfs::read_dir(&fs, "b").unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec!["B2", "big", "c"]);
+        assert!(fs.lookup("b/B2").unwrap().attributes().contains(FileAttributes::READ_ONLY));
+        /* nothing records the time the image was built */
+        let t = start_of_1980();
+        let mut n = 0;
+        for e in fs.walk("").unwrap() {
+            let e = e.unwrap();
+            let set = e.metadata().entry_set().unwrap();
+            assert_eq!((set.created(), set.modified(), set.accessed()), (t, t, t), "{}", e.path());
+            n += 1;
+        }
+        assert_eq!(n, 6);
+
+        host::remove_dir_all(&src).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_rejected() {
+        let src = temp_dir("build-symlink");
+        host::create_dir_all(src.join("d")).unwrap();
+        ::std::os::unix::fs::symlink("..", src.join("d/up")).unwrap();
+
+        let mut data = vec![0u8; 4 << 20];
+        match ImageBuilder::new(4 << 20).build(&mut data, &src) {
+            Err(Error::Path { ref path, ref error }) => {
+                assert!(path.ends_with("up"), "{}", path);
+                assert!(matches!(**error, Error::Unsupported(_)), "{:?}", error);
+            },
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+        host::remove_dir_all(&src).unwrap();
+    }
+}
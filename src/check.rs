@@ -0,0 +1,738 @@
+/*
+ * Offline consistency checking: every structure on the volume is cross-validated, and problems
+ * are collected into a report instead of failing the first time one is found.
+ */
+use ::io_at::ReadAt;
+use ::std::collections::VecDeque;
+use ::std::fmt;
+use super::{checksum32,entry_type,BootSector,Dir,DirEntry,EntrySet,Error,FatEntry,Fs,Location,Result,Stream};
+use super::dir::MAX_DIR_LEN;
+
+/// How serious a `Finding` is
+#[derive(Clone,Copy,Debug,Eq,PartialEq,Ord,PartialOrd,Hash)]
+pub enum Severity {
+    /// The volume is usable, but something is stale or wasted
+    Warning,
+    /// Data may be unreadable, or may be damaged by further writes
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found by `Fs::check()`
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum Problem {
+    /// The checksum sector of a boot region doesn't match the rest of the region
+    BootChecksum { backup: bool },
+    /// The backup boot sector's fields are invalid, so it can't be used in place of the main one.
+    /// (A main boot sector like this can't be opened at all.)
+    BackupBootSector(&'static str),
+    /// The backup boot region isn't a copy of the main boot region
+    BootRegionsDiffer,
+    /// VolumeDirty is set, so the volume was not cleanly unmounted
+    VolumeDirty,
+    /// MediaFailure is set, so the device has reported failures
+    MediaFailure,
+    /// The first two FAT entries are not the media type & 0xFFFFFFFF
+    FatMediaEntries,
+    /// PercentInUse doesn't agree with the allocation bitmap
+    PercentInUse { recorded: u8, actual: u8 },
+    /// The up-case table doesn't match it's checksum
+    UpcaseChecksum,
+    /// An in-use secondary entry doesn't follow a primary entry
+    OrphanSecondary,
+    /// A critical primary entry of a type this library doesn't know
+    UnknownCriticalEntry,
+    /// The directory ends (or another entry set starts) before all the secondary entries
+    MissingSecondaries,
+    /// The SetChecksum field doesn't match the entry set
+    EntrySetChecksum { computed: u16 },
+    /// A File entry set doesn't have the secondary entries it requires
+    MalformedFileSet(&'static str),
+    /// The NameHash field doesn't match the name
+    NameHash { computed: u16 },
+    ValidDataLenTooLarge,
+    DirectoryTooLarge,
+    /// A cluster chain leads outside of the cluster heap (or to a bad cluster). `next` is the
+    /// offending value.
+    ChainOutOfRange { next: u32 },
+    /// A cluster chain leads back into itself
+    ChainLoop,
+    /// A cluster chain ends before DataLength is reached
+    ChainTooShort,
+    /// A cluster chain continues past DataLength
+    ChainTooLong,
+    /// `cluster` also belongs to `other`
+    CrossLinked { cluster: u32, other: String },
+    /// `count` clusters starting at `first` are in use, but free in the allocation bitmap
+    UsedButFree { first: u32, count: u32 },
+    /// `count` clusters starting at `first` are allocated in the allocation bitmap, but nothing
+    /// uses them (they are "lost")
+    AllocatedButUnused { first: u32, count: u32 },
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match *self {
+            Problem::BootChecksum { backup: true } | Problem::BackupBootSector(_) | Problem::BootRegionsDiffer
+                | Problem::VolumeDirty | Problem::MediaFailure | Problem::FatMediaEntries | Problem::PercentInUse { .. }
+                | Problem::OrphanSecondary | Problem::ChainTooLong | Problem::AllocatedButUnused { .. }
+                => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// The name of the problem, without any details, for example `"ChainLoop"`
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Problem::BootChecksum { .. } => "BootChecksum",
+            Problem::BackupBootSector(_) => "BackupBootSector",
+            Problem::BootRegionsDiffer => "BootRegionsDiffer",
+            Problem::VolumeDirty => "VolumeDirty",
+            Problem::MediaFailure => "MediaFailure",
+            Problem::FatMediaEntries => "FatMediaEntries",
+            Problem::PercentInUse { .. } => "PercentInUse",
+            Problem::UpcaseChecksum => "UpcaseChecksum",
+            Problem::OrphanSecondary => "OrphanSecondary",
+            Problem::UnknownCriticalEntry => "UnknownCriticalEntry",
+            Problem::MissingSecondaries => "MissingSecondaries",
+            Problem::EntrySetChecksum { .. } => "EntrySetChecksum",
+            Problem::MalformedFileSet(_) => "MalformedFileSet",
+            Problem::NameHash { .. } => "NameHash",
+            Problem::ValidDataLenTooLarge => "ValidDataLenTooLarge",
+            Problem::DirectoryTooLarge => "DirectoryTooLarge",
+            Problem::ChainOutOfRange { .. } => "ChainOutOfRange",
+            Problem::ChainLoop => "ChainLoop",
+            Problem::ChainTooShort => "ChainTooShort",
+            Problem::ChainTooLong => "ChainTooLong",
+            Problem::CrossLinked { .. } => "CrossLinked",
+            Problem::UsedButFree { .. } => "UsedButFree",
+            Problem::AllocatedButUnused { .. } => "AllocatedButUnused",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BootChecksum { backup } =>
+                write!(f, "{} boot region checksum mismatch", if backup { "backup" } else { "main" }),
+            Problem::BackupBootSector(msg) => write!(f, "backup {}", msg),
+            Problem::BootRegionsDiffer => write!(f, "backup boot region differs from the main boot region"),
+            Problem::VolumeDirty => write!(f, "volume is marked dirty"),
+            Problem::MediaFailure => write!(f, "volume is marked as having media failures"),
+            Problem::FatMediaEntries => write!(f, "first two FAT entries are invalid"),
+            Problem::PercentInUse { recorded, actual } =>
+                write!(f, "percent in use is {}, but {}% of clusters are allocated", recorded, actual),
+            Problem::UpcaseChecksum => write!(f, "up-case table checksum mismatch"),
+            Problem::OrphanSecondary => write!(f, "secondary entry without a primary entry"),
+            Problem::UnknownCriticalEntry => write!(f, "unknown critical primary entry"),
+            Problem::MissingSecondaries => write!(f, "entry set is missing secondary entries"),
+            Problem::EntrySetChecksum { computed } =>
+                write!(f, "entry set checksum mismatch (computed {:#06x})", computed),
+            Problem::MalformedFileSet(msg) => write!(f, "{}", msg),
+            Problem::NameHash { computed } => write!(f, "name hash mismatch (computed {:#06x})", computed),
+            Problem::ValidDataLenTooLarge => write!(f, "valid data length is larger than the data length"),
+            Problem::DirectoryTooLarge => write!(f, "directory is larger than the maximum directory size"),
+            Problem::ChainOutOfRange { next } => write!(f, "cluster chain leads to invalid cluster {:#x}", next),
+            Problem::ChainLoop => write!(f, "cluster chain loops"),
+            Problem::ChainTooShort => write!(f, "cluster chain is shorter than the data length"),
+            Problem::ChainTooLong => write!(f, "cluster chain is longer than the data length"),
+            Problem::CrossLinked { cluster, ref other } =>
+                write!(f, "cluster {} is also used by {}", cluster, other),
+            Problem::UsedButFree { first, count } =>
+                write!(f, "{} clusters starting at {} are in use but marked free", count, first),
+            Problem::AllocatedButUnused { first, count } =>
+                write!(f, "{} clusters starting at {} are allocated but not used", count, first),
+        }
+    }
+}
+
+/// A single problem, with where it was found
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Finding {
+    problem: Problem,
+    location: Option<Location>,
+    path: Option<String>,
+    /// The directory containing the entry set a problem was found in, for problems that
+    /// `Fs::repair()` fixes by rewriting the entry set
+    pub(crate) dir: Option<Stream>,
+}
+
+impl Finding {
+    pub fn severity(&self) -> Severity {
+        self.problem.severity()
+    }
+
+    pub fn problem(&self) -> &Problem {
+        &self.problem
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        self.location
+    }
+
+    /// The file or directory affected, if the problem concerns one
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(|p| &p[..])
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.severity())?;
+        if let Some(ref p) = self.path {
+            write!(f, "{}: ", p)?;
+        }
+        write!(f, "{}", self.problem)?;
+        if let Some(l) = self.location {
+            write!(f, " (at {})", l)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of `Fs::check()`
+#[derive(Clone,Debug,Default)]
+pub struct CheckReport {
+    findings: Vec<Finding>,
+    files: u64,
+    directories: u64,
+    clusters_in_use: u32,
+}
+
+impl CheckReport {
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// True if nothing at all was found
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// The severity of the most serious finding
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity()).max()
+    }
+
+    /// Number of files found (not counting directories)
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Number of directories found, including the root directory
+    pub fn directories(&self) -> u64 {
+        self.directories
+    }
+
+    /// Number of clusters used by files, directories, and the volume's own structures
+    pub fn clusters_in_use(&self) -> u32 {
+        self.clusters_in_use
+    }
+}
+
+/// The checksum of a boot region (the first 11 sectors of `region`), which skips the
+/// VolumeFlags and PercentInUse fields. Every u32 of the 12th sector should hold this value.
+pub fn boot_checksum(region: &[u8], bytes_per_sector: usize) -> u32 {
+    let mut sum = 0;
+    for (i, b) in region[..(11 * bytes_per_sector)].iter().enumerate() {
+        if i == 106 || i == 107 || i == 112 {
+            continue;
+        }
+        sum = checksum32(sum, &[*b]);
+    }
+    sum
+}
+
+/// State built up while checking a volume
+pub(crate) struct Checker<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    pub(crate) report: CheckReport,
+    /// For each cluster: 0 if unused, otherwise an index into `owners` plus 1
+    pub(crate) used: Vec<u32>,
+    /// Names of the files & structures claiming clusters
+    owners: Vec<String>,
+}
+
+impl<'a, S: ReadAt + 'a> Checker<'a, S> {
+    /// For each cluster: true if it is allocated (and not marked bad), but not used by anything
+    pub(crate) fn lost(&self) -> Vec<bool> {
+        let bitmap = self.fs.bitmap();
+        let fat = self.fs.fat();
+        self.used.iter().enumerate().map(|(i, u)| {
+            let i = i as u32;
+            *u == 0 && self.fs.boot_sector().is_valid_cluster(i) && bitmap.is_allocated(i)
+                && !fat.entry(FatEntry::from_val(i)).is_bad()
+        }).collect()
+    }
+
+    fn push(&mut self, problem: Problem, location: Option<Location>, path: Option<&str>) {
+        self.report.findings.push(Finding { problem, location, path: path.map(|p| p.to_owned()), dir: None });
+    }
+
+    /// Like `push()`, for a problem with the entry set at `at` in the directory `dir`
+    fn push_in(&mut self, problem: Problem, at: Location, path: &str, dir: Stream) {
+        self.push(problem, Some(at), Some(path));
+        self.report.findings.last_mut().unwrap().dir = Some(dir);
+    }
+
+    fn check_boot_regions(&mut self) -> Result<()> {
+        let bs = self.fs.boot_sector();
+        let bps = bs.bytes_per_sector() as usize;
+        let mut regions = [vec![0u8; 12 * bps], vec![0u8; 12 * bps]];
+        for (i, r) in regions.iter_mut().enumerate() {
+            self.fs.read_exact_at(r, (i * 12 * bps) as u64)?;
+            let sum = boot_checksum(r, bps);
+            if r[(11 * bps)..].chunks(4).any(|c| read_num_bytes!(u32, 4, c) != sum) {
+                self.push(Problem::BootChecksum { backup: i == 1 }, Some(Location::Sector(i as u64 * 12 + 11)), None);
+            }
+        }
+
+        if let Err(Error::Corrupt { msg, .. }) = BootSector::from(*index_fixed!(&regions[1]; .. 512)) {
+            self.push(Problem::BackupBootSector(msg), Some(Location::Sector(12)), None);
+        }
+
+        /* the backup's VolumeFlags & PercentInUse are not kept up to date */
+        let differs = regions[0].iter().zip(regions[1].iter()).enumerate()
+            .any(|(i, (a, b))| a != b && i != 106 && i != 107 && i != 112);
+        if differs {
+            self.push(Problem::BootRegionsDiffer, Some(Location::Sector(12)), None);
+        }
+
+        if bs.volume_flags() & (1 << 1) != 0 {
+            self.push(Problem::VolumeDirty, Some(Location::Sector(0)), None);
+        }
+        if bs.volume_flags() & (1 << 2) != 0 {
+            self.push(Problem::MediaFailure, Some(Location::Sector(0)), None);
+        }
+        Ok(())
+    }
+
+    fn check_fat(&mut self) {
+        let (e0, e1) = {
+            let fat = self.fs.fat();
+            (u32::from_le(fat.v[0]), u32::from_le(fat.v[1]))
+        };
+        if e0 != 0xFFFF_FFF8 || e1 != 0xFFFF_FFFF {
+            let s = self.fs.boot_sector().active_fat_offs() / self.fs.boot_sector().bytes_per_sector();
+            self.push(Problem::FatMediaEntries, Some(Location::Sector(s)), None);
+        }
+    }
+
+    /// Record `name` as the owner of the clusters of a stream starting at `first`, which has `len`
+    /// clusters (or, if `None`, however many the FAT chain has). Returns the number of clusters if
+    /// the stream is intact and no other stream uses them.
+    fn claim(&mut self, name: &str, first: u32, contiguous: bool, len: Option<u64>, at: Location) -> Option<u64> {
+        if len == Some(0) {
+            return Some(0);
+        }
+
+        self.owners.push(name.to_owned());
+        let owner = self.owners.len() as u32;
+        let mut c = first;
+        let mut n = 0;
+        loop {
+            if !self.fs.boot_sector().is_valid_cluster(c) {
+                self.push(Problem::ChainOutOfRange { next: c }, Some(at), Some(name));
+                return None;
+            }
+
+            match self.used[c as usize] {
+                0 => self.used[c as usize] = owner,
+                o if o == owner => {
+                    self.push(Problem::ChainLoop, Some(at), Some(name));
+                    return None;
+                },
+                o => {
+                    let other = self.owners[o as usize - 1].clone();
+                    self.push(Problem::CrossLinked { cluster: c, other }, Some(at), Some(name));
+                    return None;
+                },
+            }
+            n += 1;
+
+            if contiguous {
+                if Some(n) == len {
+                    return Some(n);
+                }
+                c += 1;
+                continue;
+            }
+
+            let next = self.fs.fat().entry(::FatEntry::from_val(c));
+            if Some(n) == len {
+                if !next.is_last() {
+                    self.push(Problem::ChainTooLong, Some(at), Some(name));
+                }
+                return Some(n);
+            }
+            if next.is_last() {
+                if len.is_some() {
+                    self.push(Problem::ChainTooShort, Some(at), Some(name));
+                    return None;
+                }
+                return Some(n);
+            }
+            c = next.val();
+        }
+    }
+
+    /// Claim the clusters of `stream`, which belongs to the entry set at `at`
+    fn claim_stream(&mut self, name: &str, stream: &Stream, at: Location) -> Option<u64> {
+        let cs = self.fs.boot_sector().bytes_per_cluster();
+        let n = stream.data_len.div_ceil(cs);
+        self.claim(name, stream.first_cluster, stream.no_fat_chain, Some(n), at)
+    }
+
+    /// Group the entries of `dir` into entry sets, reporting any that are malformed
+    fn entry_sets(&mut self, dir: &Dir<'a, S>, path: &str) -> Result<Vec<EntrySet>> {
+        let first = dir.stream().first_cluster;
+        let entries = dir.entries().collect::<Result<Vec<_>>>()?;
+        let mut sets = Vec::new();
+        let mut i = 0;
+        while i < entries.len() {
+            let (idx, p) = entries[i];
+            let at = Location::Entry { dir: first, index: idx };
+            i += 1;
+
+            let k = p.kind();
+            if !k.in_use() {
+                continue;
+            }
+            if !k.is_primary() {
+                self.push(Problem::OrphanSecondary, Some(at), Some(path));
+                continue;
+            }
+
+            let secondaries = match p.entry_type() {
+                entry_type::ALLOCATION_BITMAP | entry_type::UPCASE_TABLE | entry_type::VOLUME_LABEL => 0,
+                entry_type::FILE => p.secondary_count() as usize,
+                _ if k.is_critical() => {
+                    self.push(Problem::UnknownCriticalEntry, Some(at), Some(path));
+                    continue;
+                },
+                _ => p.secondary_count() as usize,
+            };
+
+            let mut set: Vec<DirEntry> = vec![p];
+            while set.len() <= secondaries && i < entries.len()
+                && entries[i].1.kind().in_use() && !entries[i].1.kind().is_primary() {
+                set.push(entries[i].1);
+                i += 1;
+            }
+            if set.len() <= secondaries {
+                self.push(Problem::MissingSecondaries, Some(at), Some(path));
+                continue;
+            }
+
+            let set = EntrySet::from_raw(idx, set);
+            let name = if set.is_file() && set.check_structure().is_ok() {
+                format!("{}/{}", path.trim_end_matches('/'), set.name())
+            } else {
+                path.to_owned()
+            };
+            if set.has_checksum() && set.set_checksum() != set.compute_checksum() {
+                self.push_in(Problem::EntrySetChecksum { computed: set.compute_checksum() }, at, &name, dir.stream());
+            }
+            match set.check_structure() {
+                Ok(()) => sets.push(set),
+                Err(Error::Corrupt { msg, .. }) => self.push(Problem::MalformedFileSet(msg), Some(at), Some(&name)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sets)
+    }
+
+    /// Check every directory, starting from the root, claiming the clusters of everything found
+    fn check_tree(&mut self) -> Result<()> {
+        let fs = self.fs;
+        let cs = fs.boot_sector().bytes_per_cluster();
+        let root = fs.boot_sector().first_cluster_of_root_dir();
+        let n = match self.claim("root directory", root, false, None, Location::Cluster(root)) {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+
+        let mut pending = VecDeque::new();
+        pending.push_back(("/".to_owned(), Stream {
+            first_cluster: root,
+            no_fat_chain: false,
+            data_len: n * cs,
+            valid_data_len: n * cs,
+        }));
+
+        while let Some((path, stream)) = pending.pop_front() {
+            self.report.directories += 1;
+            let dir = Dir::from_stream(fs, stream);
+            let is_root = path == "/";
+            for set in self.entry_sets(&dir, &path)? {
+                let at = Location::Entry { dir: stream.first_cluster, index: set.index() };
+                let p = *set.primary();
+                match set.entry_type() {
+                    entry_type::FILE => {
+                        let name = format!("{}/{}", path.trim_end_matches('/'), set.name());
+                        if let Some(d) = self.check_file(&name, &set, stream, at) {
+                            pending.push_back((name, d));
+                        }
+                    },
+                    entry_type::ALLOCATION_BITMAP if is_root => {
+                        let n = p.data_len().div_ceil(cs);
+                        self.claim("allocation bitmap", p.first_cluster(), false, Some(n), at);
+                    },
+                    entry_type::UPCASE_TABLE if is_root => {
+                        let n = p.data_len().div_ceil(cs);
+                        if self.claim("up-case table", p.first_cluster(), false, Some(n), at).is_some() {
+                            let raw = fs.read_stream(&Stream {
+                                first_cluster: p.first_cluster(),
+                                no_fat_chain: false,
+                                data_len: p.data_len(),
+                                valid_data_len: p.data_len(),
+                            })?;
+                            if checksum32(0, &raw) != p.table_checksum() {
+                                self.push(Problem::UpcaseChecksum, Some(at), None);
+                            }
+                        }
+                    },
+                    /* benign primaries using the generic layout may have an allocation */
+                    _ if !p.kind().is_critical() && p.raw()[4] & 1 != 0 => {
+                        let s = Stream {
+                            first_cluster: p.first_cluster(),
+                            no_fat_chain: p.raw()[4] & (1 << 1) != 0,
+                            data_len: p.data_len(),
+                            valid_data_len: p.data_len(),
+                        };
+                        let name = format!("{} (entry type {:#04x})", path, p.entry_type());
+                        self.claim_stream(&name, &s, at);
+                    },
+                    _ => {},
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a File entry set, returning the directory's stream if it is a directory that can be
+    /// descended into
+    fn check_file(&mut self, name: &str, set: &EntrySet, parent: Stream, at: Location) -> Option<Stream> {
+        let computed = self.fs.upcase_table().name_hash(&set.name_utf16());
+        if computed != set.name_hash() {
+            self.push(Problem::NameHash { computed }, Some(at), Some(name));
+        }
+        if set.valid_data_len() > set.data_len() {
+            self.push(Problem::ValidDataLenTooLarge, Some(at), Some(name));
+        }
+
+        for s in set.secondary_allocations() {
+            self.claim_stream(&format!("{} (vendor allocation)", name), &s, at);
+        }
+
+        let stream = set.stream();
+        let before = self.report.findings.len();
+        let intact = self.claim_stream(name, &stream, at).is_some();
+        for f in &mut self.report.findings[before..] {
+            f.dir = Some(parent);
+        }
+        if !set.is_dir() {
+            self.report.files += 1;
+            return None;
+        }
+        if stream.data_len > MAX_DIR_LEN {
+            self.push(Problem::DirectoryTooLarge, Some(at), Some(name));
+            return None;
+        }
+        if intact { Some(stream) } else { None }
+    }
+
+    /// Compare the clusters found to be in use with the allocation bitmap
+    fn check_bitmap(&mut self) {
+        let fs = self.fs;
+        let cc = fs.boot_sector().cluster_count();
+        let mut runs: Vec<Problem> = Vec::new();
+        {
+            let bitmap = fs.bitmap();
+            let fat = fs.fat();
+            for c in 2..(cc + 2) {
+                let used = self.used[c as usize] != 0;
+                let allocated = bitmap.is_allocated(c);
+                let bad = fat.entry(::FatEntry::from_val(c)).is_bad();
+                if used {
+                    self.report.clusters_in_use += 1;
+                }
+
+                match (runs.last_mut(), used, allocated && !bad) {
+                    (_, true, true) | (_, false, false) => continue,
+                    (Some(&mut Problem::UsedButFree { first, ref mut count }), true, false)
+                        | (Some(&mut Problem::AllocatedButUnused { first, ref mut count }), false, true)
+                        if first + *count == c => {
+                        *count += 1;
+                        continue;
+                    },
+                    _ => {},
+                }
+                runs.push(if used {
+                    Problem::UsedButFree { first: c, count: 1 }
+                } else {
+                    Problem::AllocatedButUnused { first: c, count: 1 }
+                });
+            }
+        }
+
+        for p in runs {
+            let first = match p {
+                Problem::UsedButFree { first, .. } | Problem::AllocatedButUnused { first, .. } => first,
+                _ => unreachable!(),
+            };
+            self.push(p, Some(Location::Cluster(first)), None);
+        }
+
+        let recorded = fs.percent_in_use();
+        let allocated = (cc - fs.bitmap().free_count()) as u64;
+        let floor = (allocated * 100 / cc as u64) as u8;
+        let ceil = (allocated * 100).div_ceil(cc as u64) as u8;
+        if recorded != 0xFF && recorded != floor && recorded != ceil {
+            self.push(Problem::PercentInUse { recorded, actual: floor }, Some(Location::Sector(0)), None);
+        }
+    }
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// Check the consistency of the whole volume: the boot regions, the FAT, every directory
+    /// entry set and the cluster chain it refers to, and the allocation bitmap.
+    ///
+    /// Problems with the volume are returned as findings in the report. Errors are only returned
+    /// if the volume can't be read.
+    pub fn check(&self) -> Result<CheckReport> {
+        Ok(self.checker()?.report)
+    }
+
+    /// Run every check, keeping the state needed to repair what was found
+    pub(crate) fn checker(&self) -> Result<Checker<'_, S>> {
+        let cc = self.boot_sector().cluster_count();
+        let mut c = Checker {
+            fs: self,
+            report: CheckReport::default(),
+            used: vec![0; cc as usize + 2],
+            owners: Vec::new(),
+        };
+
+        c.check_boot_regions()?;
+        c.check_fat();
+        c.check_tree()?;
+        c.check_bitmap();
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::{put,Image};
+
+    fn problems(r: &CheckReport) -> Vec<(Option<&str>, &Problem)> {
+        r.findings().iter().map(|f| (f.path(), f.problem())).collect()
+    }
+
+    #[test]
+    fn clean_after_writes() {
+        let mut img = Image::new();
+        img.add_file("contiguous", &[1; 1500], true, 1500);
+        img.add_file("chained", &[2; 1500], false, 1500);
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            ::fs::create_dir_all(&fs, "a/b").unwrap();
+            ::fs::write(&fs, "a/b/f", &[3; 5000]).unwrap();
+            ::fs::write(&fs, "a/g", b"g").unwrap();
+            ::fs::write(&fs, "contiguous", &[4; 3000]).unwrap();
+            ::fs::remove_file(&fs, "a/g").unwrap();
+            for i in 0..20 {
+                ::fs::write(&fs, &format!("{:040}", i), b"x").unwrap();
+            }
+            fs.set_volume_label("clean").unwrap();
+        }
+
+        /* PercentInUse isn't maintained, so writing marked it as unknown */
+        let fs = Fs::from_ro(&img.data).unwrap();
+        assert_eq!(fs.boot_sector().percent_in_use(), 0xFF);
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+        assert_eq!((r.files(), r.directories()), (23, 3));
+        assert_eq!(r.clusters_in_use(), img.cluster_count() - fs.bitmap().free_count());
+
+        img.data[112] = 50;
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let r = fs.check().unwrap();
+        assert_eq!(problems(&r), vec![(None, &Problem::PercentInUse { recorded: 50, actual: 2 })]);
+        assert_eq!(r.worst(), Some(Severity::Warning));
+    }
+
+    #[test]
+    fn invalid_backup_boot_sector() {
+        let mut img = Image::new();
+        img.data[12 * 512 + 109] = 60;
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let r = fs.check().unwrap();
+        assert_eq!(&problems(&r)[..3], &[
+            (None, &Problem::BootChecksum { backup: true }),
+            (None, &Problem::BackupBootSector("boot sector cluster size is larger than 32MiB")),
+            (None, &Problem::BootRegionsDiffer),
+        ]);
+    }
+
+    #[test]
+    fn finds_problems() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+
+        /* "a" & "b" share a cluster, and "c" claims one more cluster than it's chain has */
+        let shared = img.alloc(2 * cs, false);
+        img.push_root(&Image::file_set("a", 0x20, 0b01, shared[0], 2 * cs, 2 * cs));
+        img.push_root(&Image::file_set("b", 0x20, 0b01, shared[1], cs, cs));
+        let short = img.alloc(2 * cs, false);
+        img.push_root(&Image::file_set("c", 0x20, 0b01, short[0], 3 * cs, 3 * cs));
+
+        /* a bad checksum and a bad name hash */
+        let mut d = Image::file_set("d", 0x20, 0, 0, 0, 0);
+        d[0][8] ^= 1;
+        img.push_root(&d);
+        let mut e = Image::file_set("e", 0x20, 0, 0, 0, 0);
+        put(&mut e[1], 4, 0x1234, 2);
+        Image::seal(&mut e);
+        img.push_root(&e);
+
+        /* a lost cluster, and a cluster in use that is free in the bitmap */
+        let lost = img.alloc(cs, true)[0];
+        let free = img.alloc(cs, true)[0];
+        let o = img.cluster_offs(2) + (free as usize - 2) / 8;
+        img.data[o] &= !(1 << ((free - 2) % 8));
+        img.push_root(&Image::file_set("f", 0x20, 0b11, free, cs, cs));
+
+        /* a damaged extended boot sector, which only the checksum covers */
+        img.data[512] = 1;
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let r = fs.check().unwrap();
+        let p = problems(&r);
+        assert_eq!(p, vec![
+            (None, &Problem::BootChecksum { backup: false }),
+            (None, &Problem::BootRegionsDiffer),
+            (Some("/d"), &Problem::EntrySetChecksum { computed: EntrySet::from_raw(0,
+                d.iter().map(|e| DirEntry::from(*e)).collect()).compute_checksum() }),
+            (Some("/b"), &Problem::CrossLinked { cluster: shared[1], other: "/a".to_owned() }),
+            (Some("/c"), &Problem::ChainTooShort),
+            (Some("/e"), &Problem::NameHash { computed: fs.upcase_table().name_hash(&[b'e' as u16]) }),
+            (None, &Problem::AllocatedButUnused { first: lost, count: 1 }),
+            (None, &Problem::UsedButFree { first: free, count: 1 }),
+        ]);
+        assert_eq!(r.worst(), Some(Severity::Error));
+        assert_eq!(p[3].1.kind(), "CrossLinked");
+        assert_eq!(r.findings()[4].location(), Some(Location::Entry { dir: fs.boot_sector().first_cluster_of_root_dir(), index: 8 }));
+    }
+}
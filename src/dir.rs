@@ -0,0 +1,327 @@
+use ::io_at::{ReadAt,WriteAt};
+use ::std::iter::Peekable;
+use super::{entry_type,DirEntry,EntrySet,Error,Fs,Location,Result,Stream};
+
+/// Directories may not be larger than this many bytes
+pub(crate) const MAX_DIR_LEN: u64 = 256 << 20;
+
+/// A series of `DirEntry`s stored in a cluster chain
+///
+/// Each entry is 32 bytes
+pub struct Dir<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    stream: Stream,
+    /// For every directory other than the root: the directory containing this one, and the entry
+    /// set describing this directory within it
+    owner: Option<(Stream, EntrySet)>,
+}
+
+impl<'a, S: ReadAt + 'a> Dir<'a, S> {
+    pub(crate) fn from_stream(fs: &'a Fs<S>, stream: Stream) -> Self {
+        Dir { fs, stream, owner: None }
+    }
+
+    /// The directory described by `set`, which is located in the directory `parent`
+    pub(crate) fn from_set(fs: &'a Fs<S>, parent: Stream, set: EntrySet) -> Result<Self> {
+        if !set.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        Ok(Dir { fs, stream: set.stream(), owner: Some((parent, set)) })
+    }
+
+    pub(crate) fn stream(&self) -> Stream {
+        self.stream
+    }
+
+    /// The entry set describing this directory, or `None` for the root directory
+    pub fn entry_set(&self) -> Option<&EntrySet> {
+        self.owner.as_ref().map(|o| &o.1)
+    }
+
+    /// True if there are no in-use entries in this directory
+    pub fn is_empty(&self) -> Result<bool> {
+        for e in self.entries() {
+            if e?.1.kind().in_use() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Every directory entry up to the end-of-directory marker, along with the index of each
+    pub fn entries(&self) -> DirEntries<'a, S> {
+        DirEntries {
+            fs: self.fs,
+            stream: self.stream,
+            idx: 0,
+            buf: Vec::new(),
+            buf_cluster: None,
+            done: false,
+            stop_at_end: true,
+        }
+    }
+
+    /// Like `entries()`, but continue past the end-of-directory marker
+    fn all_entries(&self) -> DirEntries<'a, S> {
+        DirEntries { stop_at_end: false, .. self.entries() }
+    }
+
+    /// Every in-use entry set
+    pub fn entry_sets(&self) -> EntrySets<'a, S> {
+        EntrySets { dir: self.stream.first_cluster, entries: self.entries().peekable() }
+    }
+
+    /// Look up the File entry set with the given name, ignoring case as the up-case table directs
+    pub fn find(&self, name: &str) -> Result<Option<EntrySet>> {
+        let upcase = self.fs.upcase_table();
+        let name: Vec<u16> = name.encode_utf16().collect();
+        let hash = upcase.name_hash(&name);
+        for set in self.entry_sets() {
+            /* damaged entry sets can't be the one being looked for (see `Fs::check()`) */
+            let set = match set {
+                Ok(set) => set,
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) => continue,
+            };
+            if set.is_file() && set.name_hash() == hash && upcase.names_eq(&set.name_utf16(), &name) {
+                return Ok(Some(set));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a, S: ReadAt + WriteAt + 'a> Dir<'a, S> {
+    /// Overwrite the entries starting at entry index `index`
+    pub(crate) fn write_entries(&self, index: u32, entries: &[DirEntry]) -> Result<()> {
+        let bs = self.fs.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let mut last = None;
+        for (i, e) in entries.iter().enumerate() {
+            let offs = (index as u64 + i as u64) * 32;
+            let ci = offs / cs;
+            let c = self.fs.stream_cluster(&self.stream, ci, last)?;
+            last = Some((ci, c));
+            self.fs.write_at(e.raw(), bs.cluster_offs(c) + offs % cs)?;
+        }
+        Ok(())
+    }
+
+    /// Write `set` over it's existing entries, which it must be the same size as
+    pub(crate) fn write_set(&self, set: &EntrySet) -> Result<()> {
+        set.check_secondaries()?;
+        self.write_entries(set.index(), set.entries())
+    }
+
+    /// Mark every entry in `set` as no longer in use
+    pub(crate) fn remove_set(&self, set: &EntrySet) -> Result<()> {
+        set.check_secondaries()?;
+        self.write_unused(set.index(), set.entries())
+    }
+
+    /// Write `entries` at `index` with their InUse bits cleared
+    fn write_unused(&self, index: u32, entries: &[DirEntry]) -> Result<()> {
+        let entries: Vec<DirEntry> = entries.iter().map(|e| {
+            let mut e = *e;
+            e.raw_mut()[0] &= !(1 << 7);
+            e
+        }).collect();
+        self.write_entries(index, &entries)
+    }
+
+    /// Replace `old` (an entry set in this directory) with `new`. `new` is written in place if it
+    /// is no longer than `old`, and is otherwise moved to a new run of entries. The index of `new`
+    /// is updated.
+    pub(crate) fn replace_set(&mut self, old: &EntrySet, new: &mut EntrySet) -> Result<()> {
+        let n = new.entries().len();
+        if n > old.entries().len() {
+            /* add the new entries before removing the old ones, so a failure can't lose them */
+            self.insert_set(new)?;
+            return self.remove_set(old);
+        }
+
+        new.set_index(old.index());
+        self.write_set(new)?;
+        self.write_unused(old.index() + n as u32, &old.entries()[n..])
+    }
+
+    /// Write `set` into a run of unused entries, growing the directory if needed. The index of
+    /// `set` is updated to reflect where it was placed.
+    pub(crate) fn insert_set(&mut self, set: &mut EntrySet) -> Result<()> {
+        set.check_secondaries()?;
+        let n = set.entries().len() as u32;
+        let mut start = 0;
+        let mut run = 0;
+        for e in self.all_entries() {
+            let (i, e) = e?;
+            if e.kind().in_use() {
+                run = 0;
+                start = i + 1;
+            } else {
+                run += 1;
+                if run == n {
+                    break;
+                }
+            }
+        }
+
+        if run < n {
+            let cs = self.fs.boot_sector().bytes_per_cluster();
+            let need = (start as u64 + n as u64) * 32;
+            self.grow(need.div_ceil(cs) * cs)?;
+        }
+
+        set.set_index(start);
+        self.write_entries(start, set.entries())
+    }
+
+    /// Grow the directory to `len` bytes, zeroing the new clusters
+    fn grow(&mut self, len: u64) -> Result<()> {
+        let fs = self.fs;
+        let cs = fs.boot_sector().bytes_per_cluster();
+        let have = self.stream.data_len / cs;
+        if len > MAX_DIR_LEN {
+            return Err(Error::NoSpace("directory is at its maximum size"));
+        }
+
+        match self.owner {
+            None => {
+                /* the root directory is always FAT chained & has no entry recording its length */
+                let last = fs.stream_cluster(&self.stream, have - 1, None)?;
+                fs.alloc_chain((len / cs - have) as u32, Some(last))?;
+            },
+            Some((parent, ref mut set)) => {
+                set.check_secondaries()?;
+                fs.grow_set(set, len, None)?;
+                set.set_valid_data_len(len);
+                set.update_checksum();
+                Dir::from_stream(fs, parent).write_set(set)?;
+                self.stream = set.stream();
+            },
+        }
+        self.stream.data_len = len;
+        self.stream.valid_data_len = len;
+
+        let zeros = vec![0u8; cs as usize];
+        let mut last = None;
+        for i in have..(len / cs) {
+            let c = fs.stream_cluster(&self.stream, i, last)?;
+            last = Some((i, c));
+            fs.write_at(&zeros, fs.boot_sector().cluster_offs(c))?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the raw entries in a directory. See `Dir::entries()`.
+pub struct DirEntries<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    stream: Stream,
+    idx: u32,
+    buf: Vec<u8>,
+    /// (index of the cluster in the stream, cluster) for the data in `buf`
+    buf_cluster: Option<(u64, u32)>,
+    done: bool,
+    stop_at_end: bool,
+}
+
+impl<'a, S: ReadAt + 'a> Iterator for DirEntries<'a, S> {
+    type Item = Result<(u32, DirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offs = self.idx as u64 * 32;
+        if self.done || offs >= self.stream.data_len {
+            return None;
+        }
+
+        let bs = self.fs.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let ci = offs / cs;
+        if self.buf_cluster.map(|(i, _)| i) != Some(ci) {
+            let c = match self.fs.stream_cluster(&self.stream, ci, self.buf_cluster) {
+                Ok(c) => c,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+            self.buf.resize(cs as usize, 0);
+            if let Err(e) = self.fs.read_exact_at(&mut self.buf, bs.cluster_offs(c)) {
+                self.done = true;
+                return Some(Err(e));
+            }
+            self.buf_cluster = Some((ci, c));
+        }
+
+        let i = (offs % cs) as usize;
+        let e = DirEntry::from(*index_fixed!(&self.buf[i..]; .. 32));
+        if self.stop_at_end && e.entry_type() == entry_type::END_OF_DIRECTORY {
+            self.done = true;
+            return None;
+        }
+
+        self.idx += 1;
+        Some(Ok((self.idx - 1, e)))
+    }
+}
+
+/// Iterator over the in-use entry sets in a directory. See `Dir::entry_sets()`.
+///
+/// Malformed entry sets are returned as errors, and iteration continues after them.
+pub struct EntrySets<'a, S: ReadAt + 'a> {
+    /// First cluster of the directory, used to locate errors
+    dir: u32,
+    entries: Peekable<DirEntries<'a, S>>,
+}
+
+impl<'a, S: ReadAt + 'a> Iterator for EntrySets<'a, S> {
+    type Item = Result<EntrySet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (idx, p) = match self.entries.next()? {
+                Ok(v) => v,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let k = p.kind();
+            if !k.in_use() || !k.is_primary() {
+                /* unused entries, and secondary entries without a primary, are skipped */
+                continue;
+            }
+
+            let secondaries = match p.entry_type() {
+                entry_type::ALLOCATION_BITMAP | entry_type::UPCASE_TABLE | entry_type::VOLUME_LABEL => 0,
+                entry_type::FILE => p.secondary_count(),
+                _ if k.is_critical() => {
+                    return Some(Err(Error::Unsupported("unknown critical primary directory entry")));
+                },
+                _ => p.secondary_count(),
+            };
+
+            let at = Location::Entry { dir: self.dir, index: idx };
+            let mut entries = vec![p];
+            for _ in 0..secondaries {
+                /* don't consume entries that can't be part of this set: they may start another */
+                let ok = match self.entries.peek() {
+                    Some(&Ok((_, ref s))) => s.kind().in_use() && !s.kind().is_primary(),
+                    Some(&Err(_)) => true,
+                    None => false,
+                };
+                if !ok {
+                    return Some(Err(Error::corrupt_at("entry set is missing secondary entries", at)));
+                }
+
+                match self.entries.next() {
+                    Some(Ok((_, s))) => entries.push(s),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => unreachable!(),
+                }
+            }
+
+            return Some(EntrySet::from_entries(idx, entries).map_err(|e| e.at(at)));
+        }
+    }
+}
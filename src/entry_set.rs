@@ -0,0 +1,511 @@
+use ::std::cmp;
+use super::{entry_type,DirEntry,Error,FileAttributes,Result,Stream,Timestamp};
+
+/// Number of UTF-16 code units stored in each File Name entry
+pub const NAME_CHARS_PER_ENTRY: usize = 15;
+
+/// Longest permitted file name, in UTF-16 code units
+pub const MAX_NAME_LEN: usize = 255;
+
+/// An entry set may have at most 255 secondary entries
+const MAX_SET_LEN: usize = 256;
+
+fn too_many_entries() -> Error {
+    Error::InvalidInput("entry set would have too many secondary entries")
+}
+
+/// Check that `name` is allowed as a file name, returning it encoded as UTF-16.
+///
+/// Names may not be empty, "." or "..", longer than `MAX_NAME_LEN`, or contain control characters
+/// or any of `"*/:<>?\|`.
+pub fn validate_name(name: &str) -> Result<Vec<u16>> {
+    let invalid = |msg| Err(Error::Name(msg));
+    if name.is_empty() || name == "." || name == ".." {
+        return invalid("file name is empty or reserved");
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return invalid("file name contains an invalid character");
+    }
+
+    let v: Vec<u16> = name.encode_utf16().collect();
+    if v.len() > MAX_NAME_LEN {
+        return invalid("file name is too long");
+    }
+    Ok(v)
+}
+
+/// Checksum covering every entry in an entry set, skipping the `SetChecksum` field of the primary
+/// entry.
+pub fn set_checksum(entries: &[DirEntry]) -> u16 {
+    let mut sum = 0u16;
+    for (i, e) in entries.iter().enumerate() {
+        for (j, b) in e.raw().iter().enumerate() {
+            if i == 0 && (j == 2 || j == 3) {
+                continue;
+            }
+            sum = sum.rotate_right(1).wrapping_add(*b as u16);
+        }
+    }
+    sum
+}
+
+/// A primary directory entry along with all of it's secondary entries.
+///
+/// For File entry sets (the only kind most users will care about), the first secondary entry is
+/// always a Stream Extension, followed by File Name entries.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct EntrySet {
+    index: u32,
+    entries: Vec<DirEntry>,
+}
+
+impl EntrySet {
+    /// A new entry set (at index zero) made up of `entries`, with the checksum computed
+    pub(crate) fn new(entries: Vec<DirEntry>) -> Self {
+        let mut set = EntrySet { index: 0, entries };
+        set.update_checksum();
+        set
+    }
+
+    /// A new, empty, File entry set with the given name (see `validate_name()`), name hash, and
+    /// attributes, with every timestamp set to the current time. The checksum is computed, but the
+    /// index is left as zero.
+    pub(crate) fn new_file(name: &[u16], name_hash: u16, attributes: FileAttributes) -> Self {
+        let mut f = [0u8;32];
+        f[0] = entry_type::FILE;
+        write_num_bytes!(u16, 2, &mut f[4..], attributes.bits());
+
+        let mut s = [0u8;32];
+        s[0] = entry_type::STREAM_EXTENSION;
+        /* AllocationPossible */
+        s[1] = 1;
+
+        let mut set = EntrySet {
+            index: 0,
+            entries: vec![DirEntry::from(f), DirEntry::from(s)],
+        };
+        let now = Timestamp::now();
+        set.set_created(now);
+        set.set_modified(now);
+        set.set_accessed(now);
+        /* the name was validated, and there are no other secondaries, so this can't fail */
+        let _ = set.set_name(name, name_hash);
+        set
+    }
+
+    /// Validate the structure (and checksum, if the primary entry has one) of an entry set read
+    /// from a directory at entry index `index`.
+    pub fn from_entries(index: u32, entries: Vec<DirEntry>) -> Result<Self> {
+        let set = EntrySet::from_raw(index, entries);
+        if !set.has_checksum() {
+            return Ok(set);
+        }
+
+        if set.primary().set_checksum() != set.compute_checksum() {
+            return Err(Error::corrupt("entry set checksum mismatch"));
+        }
+
+        set.check_structure()?;
+        Ok(set)
+    }
+
+    /// An entry set at `index` made up of `entries`, without any validation
+    pub(crate) fn from_raw(index: u32, entries: Vec<DirEntry>) -> Self {
+        EntrySet { index, entries }
+    }
+
+    /// Check that a File entry set has a Stream Extension and enough File Name entries
+    pub(crate) fn check_structure(&self) -> Result<()> {
+        if self.entry_type() == entry_type::FILE {
+            if self.entries.len() < 3 || self.entries[1].entry_type() != entry_type::STREAM_EXTENSION {
+                return Err(Error::corrupt("file entry set has no stream extension"));
+            }
+
+            let n = self.name_entry_count();
+            if self.name_len() == 0 || self.entries.len() < 2 + n
+                || self.entries[2..(2 + n)].iter().any(|e| e.entry_type() != entry_type::FILE_NAME) {
+                return Err(Error::corrupt("file entry set has too few file name entries"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the primary entry within the containing directory
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// All the entries in the set, starting with the primary entry
+    pub fn entries(&self) -> &[DirEntry] {
+        &self.entries
+    }
+
+    pub fn primary(&self) -> &DirEntry {
+        &self.entries[0]
+    }
+
+    pub fn entry_type(&self) -> u8 {
+        self.primary().entry_type()
+    }
+
+    /// The critical primary entries other than File have no secondary entries & no SetChecksum
+    /// field
+    pub(crate) fn has_checksum(&self) -> bool {
+        !self.primary().kind().is_critical() || self.is_file()
+    }
+
+    /// The SetChecksum stored in the primary entry
+    pub fn set_checksum(&self) -> u16 {
+        self.primary().set_checksum()
+    }
+
+    /// Calculate the SetChecksum the entries in this set should have
+    pub fn compute_checksum(&self) -> u16 {
+        set_checksum(&self.entries)
+    }
+
+    /// True if this is a File entry set (which describes either a file or a directory)
+    pub fn is_file(&self) -> bool {
+        self.entry_type() == entry_type::FILE
+    }
+
+    /// File attributes, ignoring any reserved bits
+    pub fn attributes(&self) -> FileAttributes {
+        FileAttributes::from_bits_truncate(self.attributes_raw())
+    }
+
+    /// File attributes, including reserved bits
+    ///
+    /// 0 = read only, 1 = hidden, 2 = system, 4 = directory, 5 = archive
+    ///
+    /// File entry offset: 4, size: 2
+    pub fn attributes_raw(&self) -> u16 {
+        read_num_bytes!(u16, 2, &self.primary().raw()[4..])
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_file() && self.attributes().contains(FileAttributes::DIRECTORY)
+    }
+
+    fn timestamp(&self, offs: usize, increment_offs: Option<usize>, utc_offs: usize) -> Timestamp {
+        let p = self.primary().raw();
+        Timestamp::from_raw(read_num_bytes!(u32, 4, &p[offs..]),
+                            increment_offs.map(|i| p[i]).unwrap_or(0),
+                            p[utc_offs])
+    }
+
+    /// File entry offsets: 8 (timestamp), 20 (10ms increment), 22 (UTC offset)
+    pub fn created(&self) -> Timestamp {
+        self.timestamp(8, Some(20), 22)
+    }
+
+    /// File entry offsets: 12 (timestamp), 21 (10ms increment), 23 (UTC offset)
+    pub fn modified(&self) -> Timestamp {
+        self.timestamp(12, Some(21), 23)
+    }
+
+    /// File entry offsets: 16 (timestamp), 24 (UTC offset)
+    pub fn accessed(&self) -> Timestamp {
+        self.timestamp(16, None, 24)
+    }
+
+    fn stream_ext(&self) -> &DirEntry {
+        &self.entries[1]
+    }
+
+    /// Flags from the Stream Extension entry
+    ///
+    /// 0 = allocation possible, 1 = no FAT chain
+    ///
+    /// Stream Extension offset: 1, size: 1
+    pub fn general_secondary_flags(&self) -> u8 {
+        self.stream_ext().raw()[1]
+    }
+
+    /// If true, the file's clusters are contiguous and are not recorded in the FAT
+    pub fn no_fat_chain(&self) -> bool {
+        self.general_secondary_flags() & (1 << 1) != 0
+    }
+
+    /// Length of the name in UTF-16 code units
+    ///
+    /// Stream Extension offset: 3, size: 1
+    pub fn name_len(&self) -> u8 {
+        self.stream_ext().raw()[3]
+    }
+
+    /// Hash of the up-cased name (see `UpcaseTable::name_hash()`)
+    ///
+    /// Stream Extension offset: 4, size: 2
+    pub fn name_hash(&self) -> u16 {
+        read_num_bytes!(u16, 2, &self.stream_ext().raw()[4..])
+    }
+
+    /// How much of the data has been written. Reads past this (but before `data_len()`) return
+    /// zeros.
+    ///
+    /// Stream Extension offset: 8, size: 8
+    pub fn valid_data_len(&self) -> u64 {
+        read_num_bytes!(u64, 8, &self.stream_ext().raw()[8..])
+    }
+
+    /// Stream Extension offset: 20, size: 4
+    pub fn first_cluster(&self) -> u32 {
+        self.stream_ext().first_cluster()
+    }
+
+    /// Stream Extension offset: 24, size: 8
+    pub fn data_len(&self) -> u64 {
+        self.stream_ext().data_len()
+    }
+
+    fn name_entry_count(&self) -> usize {
+        (self.name_len() as usize).div_ceil(NAME_CHARS_PER_ENTRY)
+    }
+
+    /// The name as stored on disk
+    pub fn name_utf16(&self) -> Vec<u16> {
+        let mut n = Vec::with_capacity(self.name_len() as usize);
+        for e in &self.entries[2..(2 + self.name_entry_count())] {
+            for c in e.raw()[2..].chunks(2) {
+                n.push(read_num_bytes!(u16, 2, c));
+            }
+        }
+        n.truncate(self.name_len() as usize);
+        n
+    }
+
+    /// The name, with any invalid UTF-16 replaced
+    pub fn name(&self) -> String {
+        String::from_utf16_lossy(&self.name_utf16())
+    }
+
+    fn stream_ext_mut(&mut self) -> &mut [u8;32] {
+        self.entries[1].raw_mut()
+    }
+
+    pub(crate) fn set_valid_data_len(&mut self, v: u64) {
+        write_num_bytes!(u64, 8, &mut self.stream_ext_mut()[8..], v);
+    }
+
+    pub(crate) fn set_data_len(&mut self, v: u64) {
+        write_num_bytes!(u64, 8, &mut self.stream_ext_mut()[24..], v);
+    }
+
+    pub(crate) fn set_first_cluster(&mut self, v: u32) {
+        write_num_bytes!(u32, 4, &mut self.stream_ext_mut()[20..], v);
+    }
+
+    pub(crate) fn set_no_fat_chain(&mut self, v: bool) {
+        let f = &mut self.stream_ext_mut()[1];
+        if v {
+            *f |= 1 << 1;
+        } else {
+            *f &= !(1 << 1);
+        }
+    }
+
+    /// Recalculate and store the SetChecksum, which must be done after any modification
+    pub(crate) fn update_checksum(&mut self) {
+        if !self.has_checksum() {
+            return;
+        }
+        let sum = self.compute_checksum();
+        write_num_bytes!(u16, 2, &mut self.entries[0].raw_mut()[2..], sum);
+    }
+
+    pub(crate) fn set_attributes_raw(&mut self, v: u16) {
+        write_num_bytes!(u16, 2, &mut self.entries[0].raw_mut()[4..], v);
+    }
+
+    fn set_timestamp(&mut self, ts: Timestamp, offs: usize, increment_offs: Option<usize>, utc_offs: usize) {
+        let p = self.entries[0].raw_mut();
+        write_num_bytes!(u32, 4, &mut p[offs..], ts.dos());
+        if let Some(i) = increment_offs {
+            p[i] = ts.increment_10ms();
+        }
+        p[utc_offs] = ts.utc_offset_raw();
+    }
+
+    pub(crate) fn set_created(&mut self, ts: Timestamp) {
+        self.set_timestamp(ts, 8, Some(20), 22);
+    }
+
+    pub(crate) fn set_modified(&mut self, ts: Timestamp) {
+        self.set_timestamp(ts, 12, Some(21), 23);
+    }
+
+    /// The 10ms increment of `ts` is discarded, as there is no field to store it in
+    pub(crate) fn set_accessed(&mut self, ts: Timestamp) {
+        self.set_timestamp(ts.without_increment(), 16, None, 24);
+    }
+
+    pub(crate) fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+
+    /// Replace the name of a File entry set, keeping any secondary entries following the names.
+    /// The checksum is updated.
+    pub(crate) fn set_name(&mut self, name: &[u16], name_hash: u16) -> Result<()> {
+        let old = if self.entries.len() > 2 { self.name_entry_count() } else { 0 };
+        if self.entries.len() - old + name.len().div_ceil(NAME_CHARS_PER_ENTRY) > MAX_SET_LEN {
+            return Err(too_many_entries());
+        }
+
+        let names = name.chunks(NAME_CHARS_PER_ENTRY).map(|chunk| {
+            let mut n = [0u8;32];
+            n[0] = entry_type::FILE_NAME;
+            for (i, c) in chunk.iter().enumerate() {
+                write_num_bytes!(u16, 2, &mut n[(2 + i * 2)..], *c);
+            }
+            DirEntry::from(n)
+        });
+        let end = cmp::min(2 + old, self.entries.len());
+        self.entries.splice(2..end, names);
+
+        {
+            let s = self.stream_ext_mut();
+            s[3] = name.len() as u8;
+            write_num_bytes!(u16, 2, &mut s[4..], name_hash);
+        }
+        self.entries[0].raw_mut()[1] = (self.entries.len() - 1) as u8;
+        self.update_checksum();
+        Ok(())
+    }
+
+    /// Add a secondary entry at the end of the set. The checksum is updated.
+    pub(crate) fn push_secondary(&mut self, e: DirEntry) -> Result<()> {
+        if self.entries.len() >= MAX_SET_LEN {
+            return Err(too_many_entries());
+        }
+        self.entries.push(e);
+        self.entries[0].raw_mut()[1] = (self.entries.len() - 1) as u8;
+        self.update_checksum();
+        Ok(())
+    }
+
+    /// Replace the secondary entry at position `i` within the set (where the primary is 0). The
+    /// checksum is updated.
+    pub(crate) fn replace_secondary(&mut self, i: usize, e: DirEntry) {
+        self.entries[i] = e;
+        self.update_checksum();
+    }
+
+    /// Remove the secondary entry at position `i` within the set (where the primary is 0). The
+    /// checksum is updated.
+    pub(crate) fn remove_secondary(&mut self, i: usize) -> DirEntry {
+        let e = self.entries.remove(i);
+        self.entries[0].raw_mut()[1] = (self.entries.len() - 1) as u8;
+        self.update_checksum();
+        e
+    }
+
+    /// Check that this set may be rewritten: every critical secondary entry must be one this
+    /// library understands.
+    ///
+    /// Benign secondary entries (whether or not they are understood) are always carried along
+    /// unmodified, but a critical one could have a meaning that a rewrite would break.
+    pub(crate) fn check_secondaries(&self) -> Result<()> {
+        for e in &self.entries[1..] {
+            let known = self.is_file()
+                && (e.entry_type() == entry_type::STREAM_EXTENSION || e.entry_type() == entry_type::FILE_NAME);
+            if e.kind().is_critical() && !known {
+                return Err(Error::Unsupported("entry set has an unknown critical secondary entry"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Cluster allocations described by benign secondary entries (such as Vendor Allocation
+    /// entries), which use the generic secondary entry layout
+    pub(crate) fn secondary_allocations(&self) -> Vec<Stream> {
+        self.entries[1..].iter()
+            .filter(|e| !e.kind().is_critical() && e.raw()[1] & 1 != 0 && e.first_cluster() != 0)
+            .map(|e| Stream {
+                first_cluster: e.first_cluster(),
+                no_fat_chain: e.raw()[1] & (1 << 1) != 0,
+                data_len: e.data_len(),
+                valid_data_len: e.data_len(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn stream(&self) -> Stream {
+        Stream {
+            first_cluster: self.first_cluster(),
+            no_fat_chain: self.no_fat_chain(),
+            data_len: self.data_len(),
+            valid_data_len: self.valid_data_len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DirEntry,Error,Fs,Result};
+    use ::testutil::Image;
+    use ::io_at::WriteAt;
+    use ::std::io::Read;
+
+    /// A file named `name` containing "data", with a synthetic secondary entry of type `ty` after
+    /// the name
+    fn image_with_secondary(name: &str, ty: u8) -> (Image, [u8;32]) {
+        let mut img = Image::new();
+        let c = img.alloc(4, true);
+        img.write_clusters(&c, b"data");
+
+        let mut extra = [0u8;32];
+        for (i, b) in extra.iter_mut().enumerate() {
+            *b = (i * 37) as u8;
+        }
+        extra[0] = ty;
+        /* no AllocationPossible, so the rest of the entry is opaque */
+        extra[1] = 0;
+
+        let mut set = Image::file_set(name, 0x20, 0b11, c[0], 4, 4);
+        set.push(extra);
+        Image::seal(&mut set);
+        img.push_root(&set);
+        (img, extra)
+    }
+
+    #[test]
+    fn unknown_benign_secondary_is_preserved() {
+        let (mut img, extra) = image_with_secondary("f", 0xE5);
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.rename("f", "a name long enough for two name entries").unwrap();
+        fs.open("a name long enough for two name entries").unwrap().write_all_at(b"more", 4).unwrap();
+        fs.rename("a name long enough for two name entries", "g").unwrap();
+        fs.set_attributes("g", ::FileAttributes::HIDDEN).unwrap();
+
+        let set = fs.lookup("g").unwrap();
+        assert_eq!(set.entries().last(), Some(&DirEntry::from(extra)));
+        assert_eq!(set.entries().len(), 4);
+        assert_eq!(set.set_checksum(), set.compute_checksum());
+
+        let mut r = Vec::new();
+        fs.open("g").unwrap().read_to_end(&mut r).unwrap();
+        assert_eq!(r, b"datamore");
+    }
+
+    #[test]
+    fn unknown_critical_secondary_blocks_rewrites() {
+        let (mut img, _) = image_with_secondary("f", 0xC5);
+        let orig = img.data.clone();
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            let mut r = Vec::new();
+            fs.open("f").unwrap().read_to_end(&mut r).unwrap();
+            assert_eq!(r, b"data");
+
+            let unsupported = |r: Result<()>| matches!(r, Err(Error::Unsupported(_)));
+            assert!(unsupported(fs.rename("f", "g")));
+            assert!(unsupported(fs.rename("f", "a much longer name for the same file")));
+            assert!(unsupported(fs.set_attributes("f", ::FileAttributes::HIDDEN)));
+            assert!(unsupported(fs.remove_file("f")));
+            assert!(unsupported(fs.open("f").unwrap().write_all_at(b"x", 0).map_err(Error::from)));
+            assert!(unsupported(fs.open("f").unwrap().set_len(5000)));
+        }
+        assert!(img.data == orig);
+    }
+}
@@ -0,0 +1,169 @@
+use ::std::{error,fmt,io,result};
+
+/// Where on the volume a problem was found
+#[derive(Clone,Copy,Debug,Eq,PartialEq,Hash)]
+pub enum Location {
+    /// A byte offset from the start of the volume, used before the sector size is known
+    Offset(u64),
+    /// A volume relative sector
+    Sector(u64),
+    Cluster(u32),
+    /// A directory entry, identified by the first cluster of the directory containing it and it's
+    /// index within that directory
+    Entry { dir: u32, index: u32 },
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Offset(o) => write!(f, "byte offset {}", o),
+            Location::Sector(s) => write!(f, "sector {}", s),
+            Location::Cluster(c) => write!(f, "cluster {}", c),
+            Location::Entry { dir, index } => write!(f, "entry {} of the directory at cluster {}", index, dir),
+        }
+    }
+}
+
+/// Errors returned by this crate
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying store returned an error
+    Io(io::Error),
+    /// Something on the volume is inconsistent or out of range
+    Corrupt { msg: &'static str, location: Option<Location> },
+    /// The volume uses a feature this library doesn't implement
+    Unsupported(&'static str),
+    /// A file name (or volume label) is not allowed
+    Name(&'static str),
+    /// There isn't enough free space (or room in a directory)
+    NoSpace(&'static str),
+    /// No file or directory exists at the given path
+    NotFound,
+    /// A file or directory already exists at the given path
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// The filesystem was not opened for writing
+    ReadOnly,
+    /// An argument was out of range or otherwise invalid
+    InvalidInput(&'static str),
+    /// `error` occurred while working on the file or directory at `path`
+    Path { path: String, error: Box<Error> },
+}
+
+/// Result type used throughout this crate
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn corrupt(msg: &'static str) -> Self {
+        Error::Corrupt { msg, location: None }
+    }
+
+    pub(crate) fn corrupt_at(msg: &'static str, location: Location) -> Self {
+        Error::Corrupt { msg, location: Some(location) }
+    }
+
+    /// Record where a corruption was found, unless a (more precise) location is already known
+    pub(crate) fn at(self, l: Location) -> Self {
+        match self {
+            Error::Corrupt { msg, location: None } => Error::corrupt_at(msg, l),
+            e => e,
+        }
+    }
+
+    /// The `io::ErrorKind` used when converting to an `io::Error`
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Io(ref e) => e.kind(),
+            Error::Corrupt { .. } => io::ErrorKind::InvalidData,
+            Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            Error::Name(_) | Error::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            Error::NoSpace(_) => io::ErrorKind::StorageFull,
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::NotADirectory => io::ErrorKind::NotADirectory,
+            Error::IsADirectory => io::ErrorKind::IsADirectory,
+            Error::DirectoryNotEmpty => io::ErrorKind::DirectoryNotEmpty,
+            Error::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+            Error::Path { ref error, .. } => error.io_kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Corrupt { msg, location: Some(l) } => write!(f, "corrupt volume: {} (at {})", msg, l),
+            Error::Corrupt { msg, location: None } => write!(f, "corrupt volume: {}", msg),
+            Error::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            Error::Name(msg) => write!(f, "invalid name: {}", msg),
+            Error::NoSpace(msg) => write!(f, "no space: {}", msg),
+            Error::NotFound => write!(f, "no such file or directory"),
+            Error::AlreadyExists => write!(f, "an entry with that name already exists"),
+            Error::NotADirectory => write!(f, "not a directory"),
+            Error::IsADirectory => write!(f, "is a directory"),
+            Error::DirectoryNotEmpty => write!(f, "directory is not empty"),
+            Error::ReadOnly => write!(f, "filesystem is opened read-only"),
+            Error::InvalidInput(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Path { ref path, ref error } => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Path { ref error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// An `io::Error` which was itself converted from an `Error` is unwrapped, so the conversion
+    /// round trips.
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().map(|i| i.is::<Error>()).unwrap_or(false) {
+            if let Some(Ok(inner)) = e.into_inner().map(|i| i.downcast::<Error>()) {
+                return *inner;
+            }
+            unreachable!();
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.io_kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::error::Error as StdError;
+
+    #[test]
+    fn io_round_trip() {
+        let e = Error::corrupt_at("bad", Location::Entry { dir: 5, index: 3 });
+        assert_eq!(e.to_string(), "corrupt volume: bad (at entry 3 of the directory at cluster 5)");
+        let io: io::Error = e.into();
+        assert_eq!(io.kind(), io::ErrorKind::InvalidData);
+        match Error::from(io) {
+            Error::Corrupt { msg: "bad", location: Some(Location::Entry { dir: 5, index: 3 }) } => {},
+            e => panic!("{:?}", e),
+        }
+
+        let e = Error::from(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+        assert!(e.source().is_some());
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::TimedOut);
+        assert_eq!(io::Error::from(Error::NoSpace("full")).kind(), io::ErrorKind::StorageFull);
+    }
+}
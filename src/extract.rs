@@ -0,0 +1,257 @@
+/*
+ * Copying the contents of a volume to a directory on the host
+ */
+use ::io_at::ReadAt;
+use ::std::fmt;
+use ::std::fs::{self as host,FileTimes};
+use ::std::io::{self,Read,Write};
+use ::std::path::{Component,Path,PathBuf};
+use super::{Error,Fs,Location,Result};
+use super::fs::Metadata;
+
+/// A file or directory which `Fs::extract_to()` could not (completely) copy
+#[derive(Debug)]
+pub struct ExtractFailure {
+    path: String,
+    error: Error,
+}
+
+impl ExtractFailure {
+    /// Path on the volume
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for ExtractFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+/// The result of `Fs::extract_to()`
+#[derive(Debug,Default)]
+pub struct ExtractReport {
+    files: u64,
+    dirs: u64,
+    bytes: u64,
+    failures: Vec<ExtractFailure>,
+}
+
+impl ExtractReport {
+    /// Number of files copied completely
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Number of directories created (not counting the destination)
+    pub fn dirs(&self) -> u64 {
+        self.dirs
+    }
+
+    /// Bytes of file data copied, including from files that were only partly copied
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Everything that couldn't be copied, in the order it was found
+    pub fn failures(&self) -> &[ExtractFailure] {
+        &self.failures
+    }
+
+    /// True if there were no failures
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Where `path` on the volume is copied to, refusing names (such as `..` on a damaged volume)
+/// that would place it outside of `dest`
+fn host_path(dest: &Path, path: &str) -> Result<PathBuf> {
+    let rel = Path::new(path.trim_start_matches('/'));
+    if rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(Error::Name("name can't be used on the host"));
+    }
+    Ok(dest.join(rel))
+}
+
+/// Apply the modification & access times in `md` to the host file `f`. Timestamps which are
+/// invalid, or which the host can't represent, are skipped.
+fn set_times(f: &host::File, md: &Metadata) -> io::Result<()> {
+    let mut t = FileTimes::new();
+    if let Ok(m) = md.modified() {
+        t = t.set_modified(m);
+    }
+    if let Ok(a) = md.accessed() {
+        t = t.set_accessed(a);
+    }
+    f.set_times(t)
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// Copy every file & directory on the volume into the host directory `dest`, creating it if
+    /// needed. Files which already exist are overwritten.
+    ///
+    /// Modification & access times are kept, and files with the `READ_ONLY` attribute are made
+    /// read-only (the attribute is ignored on directories, as Windows does). Directory times are
+    /// set once everything inside them has been copied.
+    ///
+    /// If `keep_going` is false, the first failure is returned as an error naming the path on the
+    /// volume. Otherwise every failure (for example a corrupt entry set, an unreadable directory,
+    /// or a broken cluster chain) is recorded in the report, and as much as possible is copied:
+    /// a file whose data can't all be read keeps the part before the problem.
+    pub fn extract_to(&self, dest: &Path, keep_going: bool) -> Result<ExtractReport> {
+        host::create_dir_all(dest)?;
+        let mut r = ExtractReport::default();
+        let mut dirs = Vec::new();
+        for e in self.walk("/")? {
+            let res = match e {
+                Ok(e) => self.extract_entry(dest, e.path(), e.metadata(), &mut r, &mut dirs)
+                    .map_err(|error| (e.path().to_owned(), error)),
+                Err(Error::Path { path, error }) => Err((path, *error)),
+                Err(error) => Err(("/".to_owned(), error)),
+            };
+            if let Err((path, error)) = res {
+                if !keep_going {
+                    return Err(Error::Path { path, error: Box::new(error) });
+                }
+                r.failures.push(ExtractFailure { path, error });
+            }
+        }
+
+        /* deepest first, since setting a directory's times also changes those of it's parent */
+        for (path, to, md) in dirs.into_iter().rev() {
+            if let Err(error) = host::File::open(&to).and_then(|f| set_times(&f, &md)) {
+                if !keep_going {
+                    return Err(Error::Path { path, error: Box::new(error.into()) });
+                }
+                r.failures.push(ExtractFailure { path, error: error.into() });
+            }
+        }
+        Ok(r)
+    }
+
+    fn extract_entry(&self, dest: &Path, path: &str, md: &Metadata, r: &mut ExtractReport,
+                     dirs: &mut Vec<(String, PathBuf, Metadata)>) -> Result<()> {
+        let to = host_path(dest, path)?;
+        if md.is_dir() {
+            match host::create_dir(&to) {
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && to.is_dir() => {},
+                v => v?,
+            }
+            dirs.push((path.to_owned(), to, md.clone()));
+            r.dirs += 1;
+            return Ok(());
+        }
+
+        /* a read-only copy from an earlier extraction can't be opened for writing */
+        if let Ok(m) = host::symlink_metadata(&to) {
+            if m.is_file() && m.permissions().readonly() {
+                host::remove_file(&to)?;
+            }
+        }
+        /* everything past ValidDataLength reads as zeros, so a corrupt DataLength could have us
+         * write far more than the clusters hold; only what they hold is copied */
+        let held = match md.entry_set() {
+            Some(set) => self.stream_clusters(&set.stream()).len() as u64 * self.boot_sector().bytes_per_cluster(),
+            None => md.len(),
+        };
+        let mut out = host::File::create(&to)?;
+        let mut src = self.open(path)?.take(held);
+        /* copied a chunk at a time, so a partial copy is still counted */
+        let mut buf = vec![0u8; 1 << 20];
+        let copied = loop {
+            match src.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    out.write_all(&buf[..n])?;
+                    r.bytes += n as u64;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => break Err(e),
+            }
+        };
+
+        set_times(&out, md)?;
+        if md.readonly() {
+            let mut perms = out.metadata()?.permissions();
+            perms.set_readonly(true);
+            out.set_permissions(perms)?;
+        }
+        copied?;
+        if md.len() > held {
+            let c = md.entry_set().map(|s| s.first_cluster()).unwrap_or(0);
+            return Err(Error::corrupt_at("file is longer than its clusters", Location::Cluster(c)));
+        }
+        r.files += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::{FileAttributes,Timestamp};
+    use ::std::time::{Duration,UNIX_EPOCH};
+    use ::testutil::{temp_dir,Image};
+
+    #[test]
+    fn extract() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        img.add_file("good", &[1; 1500], false, 1500);
+        let c = img.alloc(cs, false);
+        img.write_clusters(&c, &[2; 512]);
+        img.push_root(&Image::file_set("short", 0x20, 0b01, c[0], 3 * cs, 3 * cs));
+        /* only the start is valid, and the rest would be read as zeros */
+        let c = img.alloc(cs, false);
+        img.push_root(&Image::file_set("huge", 0x20, 0b01, c[0], 1 << 40, 0));
+        let mut bad = Image::file_set("checksum", 0x20, 0, 0, 0, 0);
+        bad[0][8] ^= 1;
+        img.push_root(&bad);
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            ::fs::create_dir_all(&fs, "d/e").unwrap();
+            ::fs::write(&fs, "d/e/ro", b"read only").unwrap();
+            fs.set_attributes("d/e/ro", FileAttributes::ARCHIVE | FileAttributes::READ_ONLY).unwrap();
+            let t = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(1_500_000_000), Some(0)).unwrap();
+            fs.set_times("d/e/ro", None, Some(t), Some(t)).unwrap();
+            fs.set_times("d", None, Some(t), Some(t)).unwrap();
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let dest = temp_dir("extract");
+        /* the walk is in directory order, so the first failure is "short"'s broken chain */
+        match fs.extract_to(&dest, false) {
+            Err(Error::Path { ref path, ref error }) => {
+                assert_eq!(path, "/short");
+                assert!(matches!(**error, Error::Corrupt { .. }), "{:?}", error);
+            },
+            v => panic!("{:?}", v),
+        }
+
+        let r = fs.extract_to(&dest, true).unwrap();
+        let mut failed: Vec<&str> = r.failures().iter().map(|f| f.path()).collect();
+        failed.sort();
+        assert_eq!(failed, vec!["/", "/huge", "/short"]);
+        assert_eq!((r.files(), r.dirs(), r.bytes()), (2, 2, 1500 + 512 + cs + 9));
+
+        assert_eq!(host::read(dest.join("good")).unwrap(), vec![1; 1500]);
+        assert_eq!(host::read(dest.join("short")).unwrap(), vec![2; 512]);
+        assert_eq!(host::metadata(dest.join("huge")).unwrap().len(), cs);
+        let md = host::metadata(dest.join("d/e/ro")).unwrap();
+        assert!(md.permissions().readonly());
+        let t = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        assert_eq!(md.modified().unwrap(), t);
+        assert_eq!(host::metadata(dest.join("d")).unwrap().modified().unwrap(), t);
+
+        /* again, over the read-only file */
+        assert_eq!(fs.extract_to(&dest, true).unwrap().failures().len(), 3);
+        host::remove_dir_all(&dest).unwrap();
+        assert!(host_path(&dest, "/a/../../b").is_err());
+    }
+}
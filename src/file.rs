@@ -0,0 +1,428 @@
+use ::io_at::{ReadAt,WriteAt};
+use ::std::cell::Cell;
+use ::std::cmp;
+use ::std::io::{self,Read,Seek,SeekFrom,Write};
+use super::{Dir,EntrySet,Error,Fs,Result,Stream,Timestamp};
+
+/// An open file. Reads, writes, and seeks are relative to the file's data.
+///
+/// Data past the file's `valid_data_len()` (but before `len()`) reads back as zeros.
+///
+/// Writes go directly to the underlying store, updating the file's directory entries as the file
+/// grows. If more than one `File` refers to the same file, they will not observe each others size
+/// changes.
+pub struct File<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    /// The directory containing `set`
+    dir: Stream,
+    set: EntrySet,
+    pos: u64,
+    /// If set, every `write()` happens at the end of the file
+    append: bool,
+    /// False if the file was opened without write access, so writes & `set_len()` fail
+    write: bool,
+    /// True once the modification time has been updated by a write through this `File`
+    modified: bool,
+    /// The most recently used (index in file, cluster) pair, used to avoid walking the FAT chain
+    /// from the start on every access
+    last: Cell<Option<(u64, u32)>>,
+}
+
+impl<'a, S: ReadAt + 'a> File<'a, S> {
+    pub(crate) fn from_set(fs: &'a Fs<S>, dir: Stream, set: EntrySet) -> Result<Self> {
+        if set.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+
+        Ok(File { fs, dir, set, pos: 0, append: false, write: true, modified: false, last: Cell::new(None) })
+    }
+
+    /// Allow or disallow writes (including `set_len()`) through this `File`
+    pub(crate) fn set_write(&mut self, write: bool) {
+        self.write = write;
+    }
+
+    /// When enabled, each `write()` (but not `write_at()`) first seeks to the end of the file
+    pub fn set_append(&mut self, append: bool) {
+        self.append = append;
+    }
+
+    /// The entry set describing this file
+    pub fn entry_set(&self) -> &EntrySet {
+        &self.set
+    }
+
+    /// Length of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.set.data_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes (from the start of the file) that have been written
+    pub fn valid_data_len(&self) -> u64 {
+        self.set.valid_data_len()
+    }
+}
+
+impl<'a, S: ReadAt + WriteAt + 'a> File<'a, S> {
+    /// Truncate or extend the file to `len` bytes. Extended regions read back as zeros.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        self.check_writable()?;
+        self.set.check_secondaries()?;
+        let cur = self.len();
+        if len > cur {
+            self.grow(len)?;
+        } else if len < cur {
+            let keep = len.div_ceil(self.fs.boot_sector().bytes_per_cluster());
+            self.fs.free_clusters(&self.set.stream(), keep)?;
+            if keep == 0 {
+                self.set.set_first_cluster(0);
+                self.set.set_no_fat_chain(false);
+            }
+            self.set.set_data_len(len);
+            if self.valid_data_len() > len {
+                self.set.set_valid_data_len(len);
+            }
+            self.last.set(None);
+        } else {
+            return Ok(());
+        }
+        self.set.set_modified(Timestamp::now());
+
+        self.write_entry_set()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if !self.write {
+            return Err(Error::InvalidInput("file is not opened for writing"));
+        }
+        self.fs.check_writable()
+    }
+
+    /// Allocate clusters so that the file is `len` bytes long
+    fn grow(&mut self, len: u64) -> Result<()> {
+        self.fs.grow_set(&mut self.set, len, self.last.get())
+    }
+
+    /// Write `buf` at `offs`, which must be within the file's current allocation
+    fn write_data(&self, buf: &[u8], offs: u64) -> Result<()> {
+        let bs = self.fs.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let stream = self.set.stream();
+        let mut done = 0;
+        while done < buf.len() {
+            let o = offs + done as u64;
+            let in_cluster = o % cs;
+            let mut n = (buf.len() - done) as u64;
+            if !stream.no_fat_chain {
+                n = cmp::min(n, cs - in_cluster);
+            }
+
+            let idx = o / cs;
+            let c = self.fs.stream_cluster(&stream, idx, self.last.get())?;
+            self.last.set(Some((idx, c)));
+            self.fs.write_at(&buf[done..(done + n as usize)], bs.cluster_offs(c) + in_cluster)?;
+            done += n as usize;
+        }
+        Ok(())
+    }
+
+    fn write_entry_set(&mut self) -> Result<()> {
+        self.set.update_checksum();
+        Dir::from_stream(self.fs, self.dir).write_set(&self.set)
+    }
+}
+
+impl<'a, S: ReadAt + 'a> ReadAt for File<'a, S> {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        let len = self.len();
+        if offs >= len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = cmp::min(buf.len() as u64, len - offs) as usize;
+        let vdl = self.valid_data_len();
+        if offs >= vdl {
+            for b in &mut buf[..n] {
+                *b = 0;
+            }
+            return Ok(n);
+        }
+
+        /* never read more than a single cluster (or contiguous run of clusters) at once */
+        let bs = self.fs.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let stream = self.set.stream();
+        let in_cluster = offs % cs;
+        let mut n = cmp::min(n as u64, vdl - offs);
+        if !stream.no_fat_chain {
+            n = cmp::min(n, cs - in_cluster);
+        }
+
+        let idx = offs / cs;
+        let c = self.fs.stream_cluster(&stream, idx, self.last.get())?;
+        self.last.set(Some((idx, c)));
+        self.fs.read_exact_at(&mut buf[..n as usize], bs.cluster_offs(c) + in_cluster)?;
+        Ok(n as usize)
+    }
+}
+
+impl<'a, S: ReadAt + 'a> Read for File<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, S: ReadAt + WriteAt + 'a> WriteAt for File<'a, S> {
+    fn write_at(&mut self, buf: &[u8], offs: u64) -> io::Result<usize> {
+        self.check_writable()?;
+        self.set.check_secondaries()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offs.checked_add(buf.len() as u64)
+            .ok_or(Error::InvalidInput("write extends past the maximum file size"))?;
+
+        let orig = self.set.clone();
+        if end > self.len() {
+            self.grow(end)?;
+        }
+
+        /* the gap between the old valid data and this write needs to be zeroed */
+        let vdl = self.valid_data_len();
+        if offs > vdl {
+            let zeros = vec![0u8; self.fs.boot_sector().bytes_per_cluster() as usize];
+            let mut o = vdl;
+            while o < offs {
+                let n = cmp::min(zeros.len() as u64, offs - o);
+                self.write_data(&zeros[..n as usize], o)?;
+                o += n;
+            }
+        }
+
+        self.write_data(buf, offs)?;
+        if end > vdl {
+            self.set.set_valid_data_len(end);
+        }
+        /* the entry set is only rewritten when the length or allocation changes, and for the
+         * first write through this handle, which is when the modification time is updated */
+        if self.set != orig || !self.modified {
+            self.set.set_modified(Timestamp::now());
+            self.modified = true;
+            self.write_entry_set()?;
+        }
+        Ok(buf.len())
+    }
+}
+
+impl<'a, S: ReadAt + WriteAt + 'a> Write for File<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.append {
+            self.pos = self.len();
+        }
+        let pos = self.pos;
+        let n = self.write_at(buf, pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Data is written directly to the store, so there is nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, S: ReadAt + 'a> Seek for File<'a, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offs) = match pos {
+            SeekFrom::Start(p) => {
+                self.pos = p;
+                return Ok(p);
+            },
+            SeekFrom::Current(o) => (self.pos, o),
+            SeekFrom::End(o) => (self.len(), o),
+        };
+
+        let p = if offs < 0 {
+            base.checked_sub(offs.unsigned_abs())
+        } else {
+            base.checked_add(offs as u64)
+        };
+
+        match p {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Fs;
+    use ::testutil::Image;
+    use ::io_at::{ReadAt,WriteAt};
+    use ::std::io::{Read,Seek,SeekFrom,Write};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_chained_and_contiguous() {
+        let mut img = Image::new();
+        let d = data(5000);
+        img.add_file("chained.bin", &d, false, d.len() as u64);
+        img.add_file("Contiguous.bin", &d, true, d.len() as u64);
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+
+        for name in &["chained.bin", "/CONTIGUOUS.BIN"] {
+            let mut f = fs.open(name).unwrap();
+            assert_eq!(f.len(), d.len() as u64);
+            let mut r = Vec::new();
+            f.read_to_end(&mut r).unwrap();
+            assert_eq!(r, d);
+
+            let mut b = [0u8; 700];
+            f.seek(SeekFrom::Start(1000)).unwrap();
+            f.read_exact(&mut b).unwrap();
+            assert_eq!(&b[..], &d[1000..1700]);
+
+            assert_eq!(f.read_at(&mut b, 4900).unwrap(), 100);
+            assert_eq!(&b[..100], &d[4900..]);
+        }
+
+        assert!(fs.open("missing").is_err());
+    }
+
+    #[test]
+    fn read_past_valid_data_len() {
+        let mut img = Image::new();
+        let d = data(3000);
+        img.add_file("f", &d, false, 1234);
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        let mut f = fs.open("f").unwrap();
+        let mut r = Vec::new();
+        f.read_to_end(&mut r).unwrap();
+        assert_eq!(r.len(), 3000);
+        assert_eq!(&r[..1234], &d[..1234]);
+        assert!(r[1234..].iter().all(|b| *b == 0));
+    }
+
+    fn read_all(img: &Image, name: &str) -> Vec<u8> {
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        let mut r = Vec::new();
+        fs.open(name).unwrap().read_to_end(&mut r).unwrap();
+        r
+    }
+
+    #[test]
+    fn overwrite_and_extend() {
+        let mut img = Image::new();
+        let mut d = data(1500);
+        img.add_file("a", &d, true, d.len() as u64);
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            let mut f = fs.open("a").unwrap();
+            f.write_all_at(&[0xAA; 100], 700).unwrap();
+            f.seek(SeekFrom::End(0)).unwrap();
+            f.write_all(&data(2000)).unwrap();
+            assert_eq!(f.len(), 3500);
+            /* the following clusters were free, so it stays contiguous */
+            assert!(f.entry_set().no_fat_chain());
+        }
+
+        for b in &mut d[700..800] {
+            *b = 0xAA;
+        }
+        d.extend(data(2000));
+        assert_eq!(read_all(&img, "a"), d);
+    }
+
+    #[test]
+    fn entry_set_written_once() {
+        let mut img = Image::new();
+        img.add_file("a", &data(1500), true, 1500);
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let mut f = fs.open("a").unwrap();
+        f.write_all_at(b"first", 0).unwrap();
+        let set = fs.lookup("a").unwrap();
+        assert_eq!(set.modified(), f.entry_set().modified());
+
+        /* overwriting leaves the entry set alone, even once the time has moved on */
+        ::std::thread::sleep(::std::time::Duration::from_millis(30));
+        f.write_all_at(b"second", 100).unwrap();
+        assert_eq!(fs.lookup("a").unwrap(), set);
+        /* a new handle updates the time again */
+        fs.open("a").unwrap().write_all_at(b"third", 200).unwrap();
+        assert!(fs.lookup("a").unwrap().modified() != set.modified());
+    }
+
+    #[test]
+    fn extend_blocked_contiguous() {
+        let mut img = Image::new();
+        let d = data(1000);
+        img.add_file("a", &d, true, d.len() as u64);
+        img.add_file("b", &d, true, d.len() as u64);
+        let free = {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            let free = fs.bitmap().free_count();
+            let mut f = fs.open("a").unwrap();
+            f.write_all_at(&d, 1000).unwrap();
+            assert!(!f.entry_set().no_fat_chain());
+            assert_eq!(fs.bitmap().free_count(), free - 2);
+            free
+        };
+
+        let mut e = d.clone();
+        e.extend(&d);
+        assert_eq!(read_all(&img, "a"), e);
+        assert_eq!(read_all(&img, "b"), d);
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let mut f = fs.open("a").unwrap();
+        f.set_len(10).unwrap();
+        assert_eq!(fs.bitmap().free_count(), free + 1);
+        f.set_len(0).unwrap();
+        assert_eq!(fs.bitmap().free_count(), free + 2);
+        assert_eq!(f.entry_set().first_cluster(), 0);
+    }
+
+    #[test]
+    fn set_len_and_sparse_write() {
+        let mut img = Image::new();
+        img.add_file("e", &[], false, 0);
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            let mut f = fs.open("e").unwrap();
+            f.set_len(3000).unwrap();
+            assert_eq!(f.valid_data_len(), 0);
+            f.write_all_at(b"end", 2000).unwrap();
+            assert_eq!(f.valid_data_len(), 2003);
+            f.set_len(2001).unwrap();
+        }
+
+        let mut e = vec![0u8; 2001];
+        e[2000] = b'e';
+        assert_eq!(read_all(&img, "e"), e);
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let mut img = Image::new();
+        img.add_file("a", b"abc", false, 3);
+        let fs = Fs::from_ro(&mut img.data).unwrap();
+        let mut f = fs.open("a").unwrap();
+        assert!(f.write_all(b"x").is_err());
+        assert!(f.set_len(0).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+/*!
+ * An interface modeled on `std::fs`, for code written against it.
+ *
+ * Each function takes the `Fs` to operate on as it's first argument, and paths are `&str`s
+ * interpreted relative to the root directory (see `Fs::lookup()`). Like `std::fs`, errors are
+ * returned as `io::Error`s, which may be converted back into the originating `exfat::Error` with
+ * `Error::from()`.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::io::{self,Read,Write};
+use ::std::time::SystemTime;
+use super::{Dir,EntrySet,EntrySets,Error,File,FileAttributes,Fs,Timestamp};
+
+/// Options controlling how a file is opened, like `std::fs::OpenOptions`
+#[derive(Clone,Debug,Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    /// All options start out disabled
+    pub fn new() -> Self {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every `write()` goes to the end of the file. Implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Truncate the file to zero length when opening it. Requires `write`.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Create the file if it doesn't exist. Requires `write` or `append`.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the file, failing if it already exists. Requires `write` or `append`, and overrides
+    /// `create` and `truncate`.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Open the file at `path` with these options.
+    ///
+    /// Writing (and `set_len()`) through the returned `File` fails unless it was opened with
+    /// `write` or `append`, and `fs` was opened with `Fs::from_rw()`.
+    pub fn open<'a, S>(&self, fs: &'a Fs<S>, path: &str) -> io::Result<File<'a, S>>
+        where S: ReadAt + WriteAt
+    {
+        let write = self.check()?;
+        let mut f = if self.create_new {
+            fs.create_file(path)?
+        } else {
+            match fs.open(path) {
+                Ok(mut f) => {
+                    if self.truncate {
+                        f.set_len(0)?;
+                    }
+                    f
+                },
+                Err(Error::NotFound) if self.create => fs.create_file(path)?,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if write {
+            /* fail now rather than on the first write */
+            fs.check_writable()?;
+        }
+        f.set_append(self.append);
+        f.set_write(write);
+        Ok(f)
+    }
+
+    /// Like `open()`, for a filesystem whose store can't be written to. Only `read` may be
+    /// enabled.
+    pub fn open_read_only<'a, S: ReadAt>(&self, fs: &'a Fs<S>, path: &str) -> io::Result<File<'a, S>> {
+        if self.check()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write access requires a writable store"));
+        }
+        let mut f = fs.open(path)?;
+        f.set_write(false);
+        Ok(f)
+    }
+
+    /// Check that the options can be used together, returning whether they need write access
+    fn check(&self) -> io::Result<bool> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        let write = self.write || self.append;
+        if !self.read && !write {
+            return invalid("must open for reading, writing, or appending");
+        }
+        if (self.truncate && !self.write) || ((self.create || self.create_new) && !write) {
+            return invalid("truncate and create require write access");
+        }
+        if self.truncate && self.append {
+            return invalid("can not both truncate and append");
+        }
+        Ok(write)
+    }
+}
+
+/// Whether an entry is a file or a directory
+#[derive(Clone,Copy,Debug,Eq,PartialEq,Hash)]
+pub struct FileType {
+    dir: bool,
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.dir
+    }
+}
+
+fn timestamp_to_system_time(ts: Timestamp) -> io::Result<SystemTime> {
+    ts.to_system_time().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "invalid timestamp")
+    })
+}
+
+/// Information about a file or directory, like `std::fs::Metadata`
+#[derive(Clone,Debug)]
+pub struct Metadata {
+    /// `None` for the root directory
+    set: Option<EntrySet>,
+    len: u64,
+}
+
+impl Metadata {
+    pub(crate) fn from_set(set: EntrySet) -> Self {
+        Metadata { len: set.data_len(), set: Some(set) }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType { dir: self.is_dir() }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.set.as_ref().map(|s| s.is_dir()).unwrap_or(true)
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// Size in bytes. For directories this is the space allocated to hold their entries.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn attributes(&self) -> FileAttributes {
+        self.set.as_ref().map(|s| s.attributes()).unwrap_or(FileAttributes::DIRECTORY)
+    }
+
+    /// True if the `READ_ONLY` attribute is set
+    pub fn readonly(&self) -> bool {
+        self.attributes().contains(FileAttributes::READ_ONLY)
+    }
+
+    /// The entry set this metadata was read from, or `None` for the root directory
+    pub fn entry_set(&self) -> Option<&EntrySet> {
+        self.set.as_ref()
+    }
+
+    fn timestamp<F: Fn(&EntrySet) -> Timestamp>(&self, f: F) -> io::Result<SystemTime> {
+        match self.set {
+            Some(ref s) => timestamp_to_system_time(f(s)),
+            None => Err(io::Error::new(io::ErrorKind::Unsupported,
+                                       "the root directory has no timestamps")),
+        }
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.timestamp(|s| s.modified())
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        self.timestamp(|s| s.accessed())
+    }
+
+    pub fn created(&self) -> io::Result<SystemTime> {
+        self.timestamp(|s| s.created())
+    }
+}
+
+/// An entry returned by `read_dir()`, like `std::fs::DirEntry`
+#[derive(Clone,Debug)]
+pub struct DirEntry {
+    path: String,
+    set: EntrySet,
+}
+
+impl DirEntry {
+    /// The full path of this entry: the path given to `read_dir()` joined with `file_name()`
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn file_name(&self) -> String {
+        self.set.name()
+    }
+
+    /// Unlike `std::fs`, this never needs to access the filesystem
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        Ok(Metadata::from_set(self.set.clone()))
+    }
+
+    pub fn file_type(&self) -> io::Result<FileType> {
+        Ok(FileType { dir: self.set.is_dir() })
+    }
+}
+
+/// Iterator over the entries of a directory. See `read_dir()`.
+pub struct ReadDir<'a, S: ReadAt + 'a> {
+    path: String,
+    sets: EntrySets<'a, S>,
+}
+
+impl<'a, S: ReadAt + 'a> Iterator for ReadDir<'a, S> {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let set = match self.sets.next()? {
+                Ok(s) => s,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            /* only File entry sets have names, the others (bitmap, label, ...) are skipped */
+            if set.is_file() {
+                let path = join(&self.path, &set.name());
+                return Some(Ok(DirEntry { path, set }));
+            }
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+fn is_root(path: &str) -> bool {
+    path.split('/').all(|c| c.is_empty())
+}
+
+/// The entries of the directory at `path`
+pub fn read_dir<'a, S: ReadAt>(fs: &'a Fs<S>, path: &str) -> io::Result<ReadDir<'a, S>> {
+    let d: Dir<'a, S> = fs.open_dir(path)?;
+    Ok(ReadDir { path: path.to_owned(), sets: d.entry_sets() })
+}
+
+pub fn metadata<S: ReadAt>(fs: &Fs<S>, path: &str) -> io::Result<Metadata> {
+    if is_root(path) {
+        let len = fs.root_dir()?.stream().data_len;
+        return Ok(Metadata { set: None, len });
+    }
+    Ok(Metadata::from_set(fs.lookup(path)?))
+}
+
+/// Read the entire contents of the file at `path`
+pub fn read<S: ReadAt>(fs: &Fs<S>, path: &str) -> io::Result<Vec<u8>> {
+    /* not sized from the DataLength, which may be corrupt */
+    let mut v = Vec::new();
+    fs.open(path)?.read_to_end(&mut v)?;
+    Ok(v)
+}
+
+pub fn read_to_string<S: ReadAt>(fs: &Fs<S>, path: &str) -> io::Result<String> {
+    String::from_utf8(read(fs, path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Replace the contents of the file at `path` with `contents`, creating it if needed
+pub fn write<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str, contents: &[u8]) -> io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(fs, path)?.write_all(contents)
+}
+
+pub fn create_dir<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str) -> io::Result<()> {
+    fs.create_dir(path)?;
+    Ok(())
+}
+
+/// Create the directory at `path` along with any missing parents. Succeeds if the directory
+/// already exists.
+pub fn create_dir_all<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str) -> io::Result<()> {
+    let mut p = String::new();
+    for c in path.split('/').filter(|c| !c.is_empty()) {
+        p.push('/');
+        p.push_str(c);
+        match fs.lookup(&p) {
+            Ok(ref set) if set.is_dir() => {},
+            Ok(_) => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          "a path component exists but is not a directory"));
+            },
+            Err(Error::NotFound) => {
+                fs.create_dir(&p)?;
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+pub fn remove_file<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str) -> io::Result<()> {
+    Ok(fs.remove_file(path)?)
+}
+
+pub fn remove_dir<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str) -> io::Result<()> {
+    Ok(fs.remove_dir(path)?)
+}
+
+/// Remove the directory at `path` after removing everything inside of it
+pub fn remove_dir_all<S: ReadAt + WriteAt>(fs: &Fs<S>, path: &str) -> io::Result<()> {
+    if is_root(path) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "can not remove the root directory"));
+    }
+
+    /* gather the entries first, as removing them changes the directory being iterated over */
+    let entries = read_dir(fs, path)?.collect::<io::Result<Vec<_>>>()?;
+    for e in entries {
+        if e.file_type()?.is_dir() {
+            remove_dir_all(fs, &e.path())?;
+        } else {
+            fs.remove_file(&e.path())?;
+        }
+    }
+    Ok(fs.remove_dir(path)?)
+}
+
+/// Rename (and possibly move) `from` to `to`. As with `std::fs::rename`, anything already at `to`
+/// is replaced: a file may replace a file, and a directory an empty directory.
+pub fn rename<S: ReadAt + WriteAt>(fs: &Fs<S>, from: &str, to: &str) -> io::Result<()> {
+    Ok(fs.rename_replace(from, to)?)
+}
+
+/// Copy the contents of the file at `from` to `to`, which is created or truncated. Returns the
+/// number of bytes copied.
+pub fn copy<S: ReadAt + WriteAt>(fs: &Fs<S>, from: &str, to: &str) -> io::Result<u64> {
+    let (src_dir, src) = fs.locate(from)?;
+    if let Ok((dst_dir, dst)) = fs.locate(to) {
+        let same_dir = dst_dir.stream().first_cluster == src_dir.stream().first_cluster;
+        if same_dir && dst.index() == src.index() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "can not copy a file onto itself"));
+        }
+    }
+
+    let mut r = File::from_set(fs, src_dir.stream(), src)?;
+    let mut w = OpenOptions::new().write(true).create(true).truncate(true).open(fs, to)?;
+    io::copy(&mut r, &mut w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+    use ::std::io::{Seek,SeekFrom};
+
+    fn names<S: ReadAt>(fs: &Fs<S>, path: &str) -> Vec<String> {
+        let mut v: Vec<String> = read_dir(fs, path).unwrap().map(|e| e.unwrap().path()).collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn open_options() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let kind = |r: io::Result<File<'_, _>>| r.err().unwrap().kind();
+
+        assert_eq!(kind(OpenOptions::new().read(true).open(&fs, "f")), io::ErrorKind::NotFound);
+        assert_eq!(kind(OpenOptions::new().read(true).create(true).open(&fs, "f")),
+                   io::ErrorKind::InvalidInput);
+        assert_eq!(kind(OpenOptions::new().open(&fs, "f")), io::ErrorKind::InvalidInput);
+
+        let mut f = OpenOptions::new().write(true).create_new(true).open(&fs, "f").unwrap();
+        f.write_all(b"hello").unwrap();
+        assert_eq!(kind(OpenOptions::new().write(true).create_new(true).open(&fs, "f")),
+                   io::ErrorKind::AlreadyExists);
+
+        let mut f = OpenOptions::new().append(true).open(&fs, "f").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(b" world").unwrap();
+        assert_eq!(read_to_string(&fs, "f").unwrap(), "hello world");
+
+        let mut f = OpenOptions::new().write(true).truncate(true).open(&fs, "f").unwrap();
+        f.write_all(b"x").unwrap();
+        assert_eq!(read(&fs, "f").unwrap(), b"x");
+
+        write(&fs, "g", b"contents").unwrap();
+        assert_eq!(read(&fs, "g").unwrap(), b"contents");
+        assert_eq!(kind(OpenOptions::new().read(true).open(&fs, "/")), io::ErrorKind::InvalidInput);
+
+        /* without write access, even on a writable filesystem */
+        let mut f = OpenOptions::new().read(true).open(&fs, "g").unwrap();
+        assert_eq!(f.write(b"x").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(f.set_len(0).is_err());
+
+        let ro = Fs::from_ro(&img.data[..]).unwrap();
+        let mut f = OpenOptions::new().read(true).open_read_only(&ro, "g").unwrap();
+        let mut v = Vec::new();
+        f.read_to_end(&mut v).unwrap();
+        assert_eq!(v, b"contents");
+        let e = OpenOptions::new().read(true).write(true).open_read_only(&ro, "g").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_corrupt_len() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        let c = img.alloc(cs, false);
+        let len = u64::MAX / 2;
+        img.push_root(&Image::file_set("big", 0x20, 0b01, c[0], len, len));
+
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        assert!(read(&fs, "big").is_err());
+    }
+
+    #[test]
+    fn dirs_and_metadata() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.set_volume_label("label").unwrap();
+        create_dir_all(&fs, "/a/b/c").unwrap();
+        create_dir_all(&fs, "a/b").unwrap();
+        write(&fs, "a/b/file", &[5; 700]).unwrap();
+        write(&fs, "a/top", b"").unwrap();
+        assert!(create_dir_all(&fs, "a/top/x").is_err());
+
+        assert_eq!(names(&fs, "/"), vec!["/a"]);
+        assert_eq!(names(&fs, "a/"), vec!["a/b", "a/top"]);
+        let e = read_dir(&fs, "a/b").unwrap().map(|e| e.unwrap())
+            .find(|e| e.file_name() == "file").unwrap();
+        let m = e.metadata().unwrap();
+        assert!(m.is_file() && !m.is_dir());
+        assert_eq!(m.len(), 700);
+        assert_eq!(m.attributes(), FileAttributes::ARCHIVE);
+        assert!(m.modified().is_ok());
+
+        let root = metadata(&fs, "").unwrap();
+        assert!(root.is_dir() && root.entry_set().is_none());
+        assert!(root.modified().is_err());
+        assert!(metadata(&fs, "a/b/c").unwrap().file_type().is_dir());
+
+        assert_eq!(copy(&fs, "a/b/file", "copy").unwrap(), 700);
+        assert_eq!(read(&fs, "copy").unwrap(), vec![5; 700]);
+        assert!(copy(&fs, "copy", "/COPY").is_err());
+        rename(&fs, "copy", "a/b/c/moved").unwrap();
+        /* like std::fs::rename, an existing file is replaced */
+        write(&fs, "over", b"over").unwrap();
+        rename(&fs, "over", "a/b/c/moved").unwrap();
+        assert_eq!(read(&fs, "a/b/c/moved").unwrap(), b"over");
+        assert!(metadata(&fs, "over").is_err());
+        assert_eq!(rename(&fs, "a/top", "a/b").unwrap_err().kind(), io::ErrorKind::IsADirectory);
+
+        let free = {
+            let b = fs.bitmap();
+            b.free_count()
+        };
+        remove_dir_all(&fs, "a").unwrap();
+        assert_eq!(names(&fs, ""), Vec::<String>::new());
+        assert_eq!(fs.bitmap().free_count(), free + 3 + 2 + 1);
+        assert_eq!(fs.volume_label().unwrap(), "label");
+    }
+}
@@ -0,0 +1,265 @@
+/*!
+ * Mounting a volume with FUSE (linux only)
+ *
+ * The kernel's FUSE protocol is spoken directly over `/dev/fuse`, without libfuse. When running
+ * as root the volume is mounted with `mount(2)`, and otherwise `fusermount3` (or `fusermount`)
+ * mounts it for us.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::libc;
+use ::std::ffi::CString;
+use ::std::fs::{File,OpenOptions};
+use ::std::io::{self,Read,Write};
+use ::std::mem;
+use ::std::os::unix::ffi::OsStrExt;
+use ::std::os::unix::io::{AsRawFd,FromRawFd,RawFd};
+use ::std::path::Path;
+use ::std::process::Command;
+use ::std::ptr;
+use super::Fs;
+
+mod session;
+
+use self::session::{Changes,Session};
+
+/// Options for mounting a volume, used like `fs::OpenOptions`
+#[derive(Clone,Debug)]
+pub struct MountOptions {
+    read_only: bool,
+    allow_other: bool,
+    owner: Option<(u32, u32)>,
+    fsname: String,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions { read_only: false, allow_other: false, owner: None, fsname: "exfat".to_owned() }
+    }
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        MountOptions::default()
+    }
+
+    /// Mount read-only. This is implied if the `Fs` was opened read-only.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Let users other than the one mounting access the volume. Unless run as root, this needs
+    /// `user_allow_other` in `/etc/fuse.conf`.
+    pub fn allow_other(&mut self, allow_other: bool) -> &mut Self {
+        self.allow_other = allow_other;
+        self
+    }
+
+    /// The owner shown for every file & directory, which defaults to the user mounting the volume
+    pub fn owner(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// The source shown in `/proc/mounts`, `exfat` by default
+    pub fn fsname(&mut self, fsname: &str) -> &mut Self {
+        self.fsname = fsname.to_owned();
+        self
+    }
+
+    /// Mount `fs` at `mountpoint`, and serve requests for it until it is unmounted (by `umount`
+    /// or `fusermount -u`).
+    ///
+    /// Requests are handled one at a time, and every change is written to the underlying store
+    /// before it is replied to.
+    pub fn mount<S: ReadAt + WriteAt>(&self, fs: &Fs<S>, mountpoint: &Path) -> io::Result<()> {
+        if self.read_only || fs.is_read_only() {
+            self.serve(fs, None, mountpoint)
+        } else {
+            self.serve(fs, Some(fs), mountpoint)
+        }
+    }
+
+    /// Like `mount`, for a store that can't be written to: the volume is always mounted
+    /// read-only.
+    pub fn mount_read_only<S: ReadAt>(&self, fs: &Fs<S>, mountpoint: &Path) -> io::Result<()> {
+        self.serve(fs, None, mountpoint)
+    }
+
+    fn serve<'a, S: ReadAt + 'a>(&self, fs: &'a Fs<S>, changes: Option<&'a (dyn Changes + 'a)>, mountpoint: &Path)
+        -> io::Result<()>
+    {
+        let read_only = changes.is_none();
+        let dev = if unsafe { libc::geteuid() } == 0 {
+            self.mount_direct(mountpoint, read_only)?
+        } else {
+            self.fusermount(mountpoint, read_only)?
+        };
+
+        let (uid, gid) = self.owner.unwrap_or_else(|| unsafe { (libc::getuid(), libc::getgid()) });
+        let mut session = Session::new(fs, changes, uid, gid);
+        let mut buf = vec![0u8; session::MAX_WRITE + 4096];
+        while !session.destroyed {
+            let n = match (&dev).read(&mut buf) {
+                Ok(n) => n,
+                Err(e) => match e.raw_os_error() {
+                    /* ENOENT: a request was interrupted before we read it */
+                    Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                    /* unmounted */
+                    Some(libc::ENODEV) => break,
+                    _ => return Err(e),
+                },
+            };
+
+            if let Some(reply) = session.dispatch(&buf[..n]) {
+                match (&dev).write(&reply) {
+                    Ok(_) => {},
+                    /* the request was interrupted, and the reply isn't wanted */
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Options common to `mount(2)` and `fusermount`
+    fn common_options(&self, read_only: bool) -> String {
+        let mut o = "default_permissions".to_owned();
+        if self.allow_other {
+            o.push_str(",allow_other");
+        }
+        if read_only {
+            o.push_str(",ro");
+        }
+        o
+    }
+
+    fn mount_direct(&self, mountpoint: &Path, read_only: bool) -> io::Result<File> {
+        let dev = OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+        let data = format!("fd={},rootmode=40000,user_id={},group_id={},{}", dev.as_raw_fd(),
+                           unsafe { libc::getuid() }, unsafe { libc::getgid() }, self.common_options(read_only));
+        let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+        if read_only {
+            flags |= libc::MS_RDONLY;
+        }
+
+        let source = cstring(self.fsname.as_bytes())?;
+        let target = cstring(mountpoint.as_os_str().as_bytes())?;
+        let data = cstring(data.as_bytes())?;
+        let r = unsafe {
+            libc::mount(source.as_ptr(), target.as_ptr(), b"fuse.exfat\0".as_ptr() as *const libc::c_char,
+                        flags, data.as_ptr() as *const libc::c_void)
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(dev)
+    }
+
+    /// Have `fusermount` mount the volume, and pass us the opened `/dev/fuse` over a socket
+    fn fusermount(&self, mountpoint: &Path, read_only: bool) -> io::Result<File> {
+        let mut fds = [0; 2];
+        if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (theirs, ours) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let options = format!("fsname={},subtype=exfat,{}", escape_option(&self.fsname),
+                              self.common_options(read_only));
+        let mut child = None;
+        for prog in &["fusermount3", "fusermount"] {
+            let r = Command::new(prog)
+                .arg("-o").arg(&options)
+                .arg("--").arg(mountpoint)
+                .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+                .spawn();
+            match r {
+                Ok(c) => {
+                    child = Some(c);
+                    break;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        let mut child = child.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "fusermount is needed to mount without root")
+        })?;
+        drop(theirs);
+
+        let fd = recv_fd(ours.as_raw_fd());
+        let status = child.wait()?;
+        match fd {
+            Ok(fd) if status.success() => Ok(unsafe { File::from_raw_fd(fd) }),
+            Ok(fd) => {
+                drop(unsafe { File::from_raw_fd(fd) });
+                Err(io::Error::other(format!("fusermount failed: {}", status)))
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Escape `,` & `\` in an option's value, so `fusermount` doesn't split it into several options
+/// (as libfuse's `fuse_opt_add_opt_escaped` does)
+fn escape_option(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+    for c in v.chars() {
+        if c == ',' || c == '\\' {
+            s.push('\\');
+        }
+        s.push(c);
+    }
+    s
+}
+
+fn cstring(b: &[u8]) -> io::Result<CString> {
+    CString::new(b).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path or option contains a NUL"))
+}
+
+/// Receive a file descriptor sent with `SCM_RIGHTS` over the unix socket `sock`
+fn recv_fd(sock: RawFd) -> io::Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr() as *mut libc::c_void, iov_len: 1 };
+    /* u64s, for the alignment cmsghdr needs */
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    loop {
+        let n = unsafe { libc::recvmsg(sock, &mut msg, 0) };
+        if n > 0 {
+            break;
+        }
+        if n == 0 {
+            return Err(io::Error::other("fusermount did not send a file descriptor"));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::other("fusermount did not send a file descriptor"));
+        }
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_option;
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_option("/tmp/disk.img"), "/tmp/disk.img");
+        assert_eq!(escape_option("a,ro,b\\c"), "a\\,ro\\,b\\\\c");
+    }
+}
@@ -0,0 +1,830 @@
+/*
+ * Requests from the kernel, and the replies to them
+ *
+ * The layout of each request & reply is described by linux's include/uapi/linux/fuse.h. Protocol
+ * version 7.31 is spoken, though only the parts needed here are used.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::libc;
+use ::std::collections::HashMap;
+use ::std::time::{Duration,UNIX_EPOCH};
+use super::super::{Error,FileAttributes,Fs,Result,Timestamp};
+use super::super::fs::{self as xfs,Metadata};
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+/// Largest write the kernel is allowed to send. Reads from `/dev/fuse` need room for this plus
+/// the request header.
+pub const MAX_WRITE: usize = 128 * 1024;
+
+const ROOT_ID: u64 = 1;
+
+/// The inode number given to directory entries the kernel hasn't looked up, as libfuse does
+const UNKNOWN_INO: u64 = 0xFFFF_FFFF;
+
+/// Seconds the kernel may cache names & attributes for
+const TTL: u64 = 1;
+
+/* opcodes */
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const SETATTR: u32 = 4;
+const MKNOD: u32 = 8;
+const MKDIR: u32 = 9;
+const UNLINK: u32 = 10;
+const RMDIR: u32 = 11;
+const RENAME: u32 = 12;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const WRITE: u32 = 16;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FSYNC: u32 = 20;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const FSYNCDIR: u32 = 30;
+const CREATE: u32 = 35;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/* SETATTR valid bits */
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_ATIME_NOW: u32 = 1 << 7;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+/// INIT flag: writes larger than a page are allowed
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+/// A reply body, or an errno
+type Reply = ::std::result::Result<Vec<u8>, i32>;
+
+fn errno<E: Into<Error>>(e: E) -> i32 {
+    let e: Error = e.into();
+    match e {
+        Error::Io(ref e) => e.raw_os_error().unwrap_or(libc::EIO),
+        Error::Corrupt { .. } => libc::EIO,
+        Error::Unsupported(_) => libc::EOPNOTSUPP,
+        Error::Name(_) | Error::InvalidInput(_) => libc::EINVAL,
+        Error::NoSpace(_) => libc::ENOSPC,
+        Error::NotFound => libc::ENOENT,
+        Error::AlreadyExists => libc::EEXIST,
+        Error::NotADirectory => libc::ENOTDIR,
+        Error::IsADirectory => libc::EISDIR,
+        Error::DirectoryNotEmpty => libc::ENOTEMPTY,
+        Error::ReadOnly => libc::EROFS,
+        Error::Path { error, .. } => errno(*error),
+    }
+}
+
+/// The fields of a request, taken in order
+struct Args<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn take(&mut self, n: usize) -> ::std::result::Result<&'a [u8], i32> {
+        if self.buf.len() < n {
+            return Err(libc::EINVAL);
+        }
+        let (a, b) = self.buf.split_at(n);
+        self.buf = b;
+        Ok(a)
+    }
+
+    fn u32(&mut self) -> ::std::result::Result<u32, i32> {
+        self.take(4).map(|b| read_num_bytes!(u32, 4, b))
+    }
+
+    fn u64(&mut self) -> ::std::result::Result<u64, i32> {
+        self.take(8).map(|b| read_num_bytes!(u64, 8, b))
+    }
+
+    /// A NUL terminated name
+    fn name(&mut self) -> ::std::result::Result<&'a str, i32> {
+        let end = self.buf.iter().position(|b| *b == 0).ok_or(libc::EINVAL)?;
+        let s = ::std::str::from_utf8(&self.buf[..end]).map_err(|_| libc::EINVAL)?;
+        self.buf = &self.buf[(end + 1)..];
+        Ok(s)
+    }
+}
+
+fn put32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put64(v: &mut Vec<u8>, x: u64) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+/// Seconds & nanoseconds since the unix epoch, or zero for timestamps which can't be decoded
+fn unix_time(t: Timestamp) -> (u64, u32) {
+    t.to_system_time()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+        .unwrap_or((0, 0))
+}
+
+fn timestamp(secs: u64, nsecs: u32) -> ::std::result::Result<Timestamp, i32> {
+    Timestamp::from_system_time(UNIX_EPOCH + Duration::new(secs, nsecs), Some(0)).map_err(|_| libc::EINVAL)
+}
+
+/// The changes a session makes to the volume, which are only possible if it's store can be
+/// written to
+pub trait Changes {
+    fn create_file(&self, path: &str) -> Result<()>;
+    fn create_dir(&self, path: &str) -> Result<()>;
+    fn remove_file(&self, path: &str) -> Result<()>;
+    fn remove_dir(&self, path: &str) -> Result<()>;
+    fn rename_replace(&self, from: &str, to: &str) -> Result<()>;
+    fn set_attributes(&self, path: &str, attributes: FileAttributes) -> Result<()>;
+    fn set_times(&self, path: &str, modified: Option<Timestamp>, accessed: Option<Timestamp>) -> Result<()>;
+    fn set_len(&self, path: &str, len: u64) -> Result<()>;
+    fn write_all_at(&self, path: &str, data: &[u8], offs: u64) -> Result<()>;
+}
+
+impl<S: ReadAt + WriteAt> Changes for Fs<S> {
+    fn create_file(&self, path: &str) -> Result<()> {
+        Fs::create_file(self, path).map(|_| ())
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        Fs::create_dir(self, path).map(|_| ())
+    }
+
+    fn remove_file(&self, path: &str) -> Result<()> {
+        Fs::remove_file(self, path)
+    }
+
+    fn remove_dir(&self, path: &str) -> Result<()> {
+        Fs::remove_dir(self, path)
+    }
+
+    fn rename_replace(&self, from: &str, to: &str) -> Result<()> {
+        Fs::rename_replace(self, from, to)
+    }
+
+    fn set_attributes(&self, path: &str, attributes: FileAttributes) -> Result<()> {
+        Fs::set_attributes(self, path, attributes)
+    }
+
+    fn set_times(&self, path: &str, modified: Option<Timestamp>, accessed: Option<Timestamp>) -> Result<()> {
+        Fs::set_times(self, path, None, modified, accessed)
+    }
+
+    fn set_len(&self, path: &str, len: u64) -> Result<()> {
+        xfs::OpenOptions::new().write(true).open(self, path)?.set_len(len)
+    }
+
+    fn write_all_at(&self, path: &str, data: &[u8], offs: u64) -> Result<()> {
+        let mut f = xfs::OpenOptions::new().write(true).open(self, path)?;
+        let mut done = 0;
+        while done < data.len() {
+            done += f.write_at(&data[done..], offs + done as u64)?;
+        }
+        Ok(())
+    }
+}
+
+struct Node {
+    path: String,
+    /// Number of times the kernel has been given this node by LOOKUP, MKDIR, etc, less those it
+    /// has forgotten
+    lookups: u64,
+}
+
+/// The state of a mounted volume.
+///
+/// The kernel refers to files & directories by node ids, which are assigned here as they are
+/// first seen and kept until the kernel forgets them. Each maps to a path (renames update the
+/// paths of any nodes they affect), and every operation looks that path up again, so no files
+/// are held open between requests.
+pub struct Session<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    /// `None` if the volume is mounted read-only
+    changes: Option<&'a (dyn Changes + 'a)>,
+    uid: u32,
+    gid: u32,
+    nodes: HashMap<u64, Node>,
+    ids: HashMap<String, u64>,
+    next_id: u64,
+    /// The kernel has sent DESTROY, and no more requests will follow
+    pub destroyed: bool,
+}
+
+impl<'a, S: ReadAt + 'a> Session<'a, S> {
+    /// Every file & directory is shown as owned by `uid` & `gid`. `changes` is normally `fs`
+    /// itself, or `None` to serve the volume read-only.
+    pub fn new(fs: &'a Fs<S>, changes: Option<&'a (dyn Changes + 'a)>, uid: u32, gid: u32) -> Self {
+        let mut s = Session {
+            fs, changes, uid, gid,
+            nodes: HashMap::new(),
+            ids: HashMap::new(),
+            next_id: ROOT_ID,
+            destroyed: false,
+        };
+        s.id_for("/".to_owned());
+        s
+    }
+
+    /// Handle a single request, returning the reply to it (if any)
+    pub fn dispatch(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let mut a = Args { buf: req };
+        let (opcode, unique, node) = match (a.u32(), a.u32(), a.u64(), a.u64(), a.take(16)) {
+            (Ok(_), Ok(opcode), Ok(unique), Ok(node), Ok(_)) => (opcode, unique, node),
+            _ => return None,
+        };
+
+        let r = match opcode {
+            FORGET => {
+                if let Ok(n) = a.u64() {
+                    self.forget(node, n);
+                }
+                return None;
+            },
+            BATCH_FORGET => {
+                let count = a.u32().unwrap_or(0);
+                let _ = a.u32();
+                for _ in 0..count {
+                    match (a.u64(), a.u64()) {
+                        (Ok(node), Ok(n)) => self.forget(node, n),
+                        _ => break,
+                    }
+                }
+                return None;
+            },
+            /* every request is handled before the next is read, so there is nothing to interrupt */
+            INTERRUPT => return None,
+            INIT => self.init(a),
+            DESTROY => {
+                self.destroyed = true;
+                Ok(Vec::new())
+            },
+            LOOKUP => self.lookup(node, a),
+            GETATTR => self.getattr(node),
+            SETATTR => self.setattr(node, a),
+            MKNOD => self.mknod(node, a),
+            MKDIR => self.mkdir(node, a),
+            CREATE => self.create(node, a),
+            UNLINK => self.remove(node, a, false),
+            RMDIR => self.remove(node, a, true),
+            RENAME => self.rename(node, a),
+            OPEN => self.open(a),
+            OPENDIR => Ok(open_out()),
+            READ => self.read(node, a),
+            WRITE => self.write(node, a),
+            READDIR => self.readdir(node, a),
+            STATFS => Ok(self.statfs()),
+            RELEASE | RELEASEDIR | FLUSH | FSYNC | FSYNCDIR => Ok(Vec::new()),
+            _ => Err(libc::ENOSYS),
+        };
+
+        let (error, body) = match r {
+            Ok(body) => (0, body),
+            Err(e) => (-e, Vec::new()),
+        };
+        let mut out = Vec::with_capacity(16 + body.len());
+        put32(&mut out, 16 + body.len() as u32);
+        put32(&mut out, error as u32);
+        put64(&mut out, unique);
+        out.extend_from_slice(&body);
+        Some(out)
+    }
+
+    /// The id of the node at `path`, assigning a new one if there isn't one already, counting a
+    /// reference the kernel will later forget
+    fn id_for(&mut self, path: String) -> u64 {
+        let id = match self.ids.get(&path) {
+            Some(&id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.ids.insert(path.clone(), id);
+                self.nodes.insert(id, Node { path, lookups: 0 });
+                id
+            },
+        };
+        self.nodes.get_mut(&id).unwrap().lookups += 1;
+        id
+    }
+
+    fn forget(&mut self, id: u64, n: u64) {
+        if id == ROOT_ID {
+            return;
+        }
+        let gone = match self.nodes.get_mut(&id) {
+            Some(node) => {
+                node.lookups = node.lookups.saturating_sub(n);
+                node.lookups == 0
+            },
+            None => false,
+        };
+        if gone {
+            let node = self.nodes.remove(&id).unwrap();
+            if self.ids.get(&node.path) == Some(&id) {
+                self.ids.remove(&node.path);
+            }
+        }
+    }
+
+    fn changes(&self) -> ::std::result::Result<&'a (dyn Changes + 'a), i32> {
+        self.changes.ok_or(libc::EROFS)
+    }
+
+    fn path(&self, id: u64) -> ::std::result::Result<String, i32> {
+        self.nodes.get(&id).map(|n| n.path.clone()).ok_or(libc::ENOENT)
+    }
+
+    /// The path of `name` in the directory `dir`, with the name as it is stored on the volume
+    /// (which may differ in case from `name`), and it's metadata
+    fn find(&self, dir: u64, name: &str) -> ::std::result::Result<(String, Metadata), i32> {
+        let dir = self.path(dir)?;
+        let set = self.fs.lookup(&join(&dir, name)).map_err(errno)?;
+        Ok((join(&dir, &set.name()), Metadata::from_set(set)))
+    }
+
+    fn attr(&self, id: u64, md: &Metadata) -> Vec<u8> {
+        let times = md.entry_set().map(|s| (unix_time(s.accessed()), unix_time(s.modified())));
+        let ((atime, atime_ns), (mtime, mtime_ns)) = times.unwrap_or(((0, 0), (0, 0)));
+        let mut mode = if md.is_dir() { libc::S_IFDIR | 0o755 } else { libc::S_IFREG | 0o644 };
+        if self.changes.is_none() || md.readonly() {
+            mode &= !0o222;
+        }
+
+        let mut v = Vec::with_capacity(88);
+        put64(&mut v, id);
+        put64(&mut v, md.len());
+        put64(&mut v, md.len().div_ceil(512));
+        put64(&mut v, atime);
+        put64(&mut v, mtime);
+        put64(&mut v, mtime);
+        put32(&mut v, atime_ns);
+        put32(&mut v, mtime_ns);
+        put32(&mut v, mtime_ns);
+        put32(&mut v, mode);
+        put32(&mut v, if md.is_dir() { 2 } else { 1 });
+        put32(&mut v, self.uid);
+        put32(&mut v, self.gid);
+        put32(&mut v, 0);
+        put32(&mut v, self.fs.boot_sector().bytes_per_cluster() as u32);
+        put32(&mut v, 0);
+        v
+    }
+
+    /// A reply naming the node at `path`, which the kernel will hold a reference to
+    fn entry_out(&mut self, path: String, md: &Metadata) -> Vec<u8> {
+        let id = self.id_for(path);
+        let mut v = Vec::with_capacity(128);
+        put64(&mut v, id);
+        put64(&mut v, 0);
+        put64(&mut v, TTL);
+        put64(&mut v, TTL);
+        put32(&mut v, 0);
+        put32(&mut v, 0);
+        v.extend_from_slice(&self.attr(id, md));
+        v
+    }
+
+    fn init(&mut self, mut a: Args) -> Reply {
+        let major = a.u32()?;
+        let minor = a.u32()?;
+        let max_readahead = a.u32()?;
+        let flags = a.u32()?;
+        if major < KERNEL_VERSION {
+            return Err(libc::EPROTO);
+        }
+
+        let minor = if major > KERNEL_VERSION { KERNEL_MINOR_VERSION } else { minor.min(KERNEL_MINOR_VERSION) };
+        let mut v = Vec::with_capacity(64);
+        put32(&mut v, KERNEL_VERSION);
+        put32(&mut v, minor);
+        put32(&mut v, max_readahead);
+        put32(&mut v, flags & FUSE_BIG_WRITES);
+        put32(&mut v, 16 | 12 << 16); /* max_background, congestion_threshold */
+        put32(&mut v, MAX_WRITE as u32);
+        put32(&mut v, 1); /* time_gran */
+        v.resize(64, 0);
+        /* kernels before 7.23 expect the shorter, older, reply */
+        if minor < 23 {
+            v.truncate(24);
+        }
+        Ok(v)
+    }
+
+    fn lookup(&mut self, dir: u64, mut a: Args) -> Reply {
+        let (path, md) = self.find(dir, a.name()?)?;
+        Ok(self.entry_out(path, &md))
+    }
+
+    fn getattr(&self, id: u64) -> Reply {
+        let md = xfs::metadata(self.fs, &self.path(id)?).map_err(errno)?;
+        let mut v = Vec::with_capacity(104);
+        put64(&mut v, TTL);
+        put64(&mut v, 0);
+        v.extend_from_slice(&self.attr(id, &md));
+        Ok(v)
+    }
+
+    fn setattr(&mut self, id: u64, mut a: Args) -> Reply {
+        let valid = a.u32()?;
+        a.take(4 + 8)?;
+        let size = a.u64()?;
+        a.take(8)?;
+        let (atime, mtime) = (a.u64()?, a.u64()?);
+        a.take(8)?;
+        let (atime_ns, mtime_ns) = (a.u32()?, a.u32()?);
+        a.take(4)?;
+        let mode = a.u32()?;
+        a.take(4)?;
+        let (uid, gid) = (a.u32()?, a.u32()?);
+
+        let path = self.path(id)?;
+        let changes = FATTR_MODE | FATTR_UID | FATTR_GID | FATTR_SIZE | FATTR_ATIME | FATTR_MTIME;
+        if valid & changes != 0 {
+            self.changes()?;
+        }
+        /* ownership isn't recorded, so it can only be "changed" to what it already is */
+        if (valid & FATTR_UID != 0 && uid != self.uid) || (valid & FATTR_GID != 0 && gid != self.gid) {
+            return Err(libc::EPERM);
+        }
+
+        if valid & FATTR_MODE != 0 {
+            let old = xfs::metadata(self.fs, &path).map_err(errno)?.attributes();
+            let mut attrs = old;
+            attrs.set(FileAttributes::READ_ONLY, mode & 0o200 == 0);
+            if attrs != old {
+                self.changes()?.set_attributes(&path, attrs).map_err(errno)?;
+            }
+        }
+        if valid & FATTR_SIZE != 0 {
+            self.changes()?.set_len(&path, size).map_err(errno)?;
+        }
+
+        let accessed = if valid & FATTR_ATIME_NOW != 0 {
+            Some(Timestamp::now())
+        } else if valid & FATTR_ATIME != 0 {
+            Some(timestamp(atime, atime_ns)?)
+        } else {
+            None
+        };
+        let modified = if valid & FATTR_MTIME_NOW != 0 {
+            Some(Timestamp::now())
+        } else if valid & FATTR_MTIME != 0 {
+            Some(timestamp(mtime, mtime_ns)?)
+        } else {
+            None
+        };
+        if accessed.is_some() || modified.is_some() {
+            self.changes()?.set_times(&path, modified, accessed).map_err(errno)?;
+        }
+
+        self.getattr(id)
+    }
+
+    fn new_entry_path(&self, dir: u64, name: &str) -> ::std::result::Result<String, i32> {
+        self.changes()?;
+        Ok(join(&self.path(dir)?, name))
+    }
+
+    fn mknod(&mut self, dir: u64, mut a: Args) -> Reply {
+        let mode = a.u32()?;
+        a.take(12)?;
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(libc::EPERM);
+        }
+        let path = self.new_entry_path(dir, a.name()?)?;
+        self.changes()?.create_file(&path).map_err(errno)?;
+        let md = xfs::metadata(self.fs, &path).map_err(errno)?;
+        Ok(self.entry_out(path, &md))
+    }
+
+    fn mkdir(&mut self, dir: u64, mut a: Args) -> Reply {
+        a.take(8)?;
+        let path = self.new_entry_path(dir, a.name()?)?;
+        self.changes()?.create_dir(&path).map_err(errno)?;
+        let md = xfs::metadata(self.fs, &path).map_err(errno)?;
+        Ok(self.entry_out(path, &md))
+    }
+
+    fn create(&mut self, dir: u64, mut a: Args) -> Reply {
+        let flags = a.u32()? as i32;
+        a.take(12)?;
+        let name = a.name()?;
+        let path = self.new_entry_path(dir, name)?;
+        let path = match self.changes()?.create_file(&path) {
+            Ok(_) => path,
+            Err(Error::AlreadyExists) if flags & libc::O_EXCL == 0 => self.find(dir, name)?.0,
+            Err(e) => return Err(errno(e)),
+        };
+        let md = xfs::metadata(self.fs, &path).map_err(errno)?;
+        let mut v = self.entry_out(path, &md);
+        v.extend_from_slice(&open_out());
+        Ok(v)
+    }
+
+    fn remove(&mut self, dir: u64, mut a: Args, is_dir: bool) -> Reply {
+        let (path, _) = self.find(dir, a.name()?)?;
+        let changes = self.changes()?;
+        if is_dir {
+            changes.remove_dir(&path).map_err(errno)?;
+        } else {
+            changes.remove_file(&path).map_err(errno)?;
+        }
+        /* the node lives on until it is forgotten, but a new entry at the same path is new */
+        self.ids.remove(&path);
+        Ok(Vec::new())
+    }
+
+    fn rename(&mut self, dir: u64, mut a: Args) -> Reply {
+        let new_dir = a.u64()?;
+        let (old_name, new_name) = (a.name()?, a.name()?);
+        let (from, _) = self.find(dir, old_name)?;
+        let to = self.new_entry_path(new_dir, new_name)?;
+        let replaced = match self.find(new_dir, new_name) {
+            Ok((existing, _)) => Some(existing).filter(|p| *p != from),
+            Err(libc::ENOENT) => None,
+            Err(e) => return Err(e),
+        };
+
+        /* like rename(2), whatever is at `to` is replaced */
+        self.changes()?.rename_replace(&from, &to).map_err(errno)?;
+        if let Some(p) = replaced {
+            self.ids.remove(&p);
+        }
+
+        /* move the node, and every node below it */
+        let prefix = format!("{}/", from);
+        let moved: Vec<u64> = self.nodes.iter()
+            .filter(|&(_, n)| n.path == from || n.path.starts_with(&prefix))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in moved {
+            let node = self.nodes.get_mut(&id).unwrap();
+            let path = format!("{}{}", to, &node.path[from.len()..]);
+            if self.ids.get(&node.path) == Some(&id) {
+                self.ids.remove(&node.path);
+            }
+            self.ids.insert(path.clone(), id);
+            node.path = path;
+        }
+        Ok(Vec::new())
+    }
+
+    fn open(&self, mut a: Args) -> Reply {
+        let flags = a.u32()? as i32;
+        if self.changes.is_none() && flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        Ok(open_out())
+    }
+
+    fn read(&self, id: u64, mut a: Args) -> Reply {
+        a.take(8)?;
+        let offs = a.u64()?;
+        let size = a.u32()? as usize;
+        let f = self.fs.open(&self.path(id)?).map_err(errno)?;
+        let mut buf = vec![0u8; size];
+        let mut done = 0;
+        while done < size {
+            match f.read_at(&mut buf[done..], offs + done as u64).map_err(errno)? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buf.truncate(done);
+        Ok(buf)
+    }
+
+    fn write(&self, id: u64, mut a: Args) -> Reply {
+        a.take(8)?;
+        let offs = a.u64()?;
+        let size = a.u32()?;
+        a.take(4 + 8 + 4 + 4)?;
+        let data = a.take(size as usize)?;
+        self.changes()?.write_all_at(&self.path(id)?, data, offs).map_err(errno)?;
+        let mut v = Vec::with_capacity(8);
+        put32(&mut v, size);
+        put32(&mut v, 0);
+        Ok(v)
+    }
+
+    fn readdir(&self, id: u64, mut a: Args) -> Reply {
+        a.take(8)?;
+        let offs = a.u64()? as usize;
+        let size = a.u32()? as usize;
+        let path = self.path(id)?;
+
+        let mut entries = vec![(".".to_owned(), path.clone(), true), ("..".to_owned(), parent(&path).to_owned(), true)];
+        for e in xfs::read_dir(self.fs, &path).map_err(errno)? {
+            let e = e.map_err(errno)?;
+            let is_dir = e.file_type().map_err(errno)?.is_dir();
+            entries.push((e.file_name(), e.path(), is_dir));
+        }
+
+        let mut v = Vec::new();
+        for (i, (name, path, is_dir)) in entries.into_iter().enumerate().skip(offs) {
+            let len = (24 + name.len()).next_multiple_of(8);
+            if v.len() + len > size {
+                break;
+            }
+            /* only LOOKUP & friends hand out nodes, since only they are forgotten */
+            put64(&mut v, self.ids.get(&path).cloned().unwrap_or(UNKNOWN_INO));
+            put64(&mut v, i as u64 + 1);
+            put32(&mut v, name.len() as u32);
+            put32(&mut v, if is_dir { libc::DT_DIR } else { libc::DT_REG } as u32);
+            v.extend_from_slice(name.as_bytes());
+            let padded = v.len().next_multiple_of(8);
+            v.resize(padded, 0);
+        }
+        Ok(v)
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let bs = self.fs.boot_sector();
+        let free = self.fs.bitmap().free_count() as u64;
+        let mut v = Vec::with_capacity(80);
+        put64(&mut v, bs.cluster_count() as u64);
+        put64(&mut v, free);
+        put64(&mut v, free);
+        put64(&mut v, 0);
+        put64(&mut v, 0);
+        put32(&mut v, bs.bytes_per_cluster() as u32);
+        put32(&mut v, 255);
+        put32(&mut v, bs.bytes_per_cluster() as u32);
+        v.resize(80, 0);
+        v
+    }
+}
+
+/// The reply to OPEN & OPENDIR. No handle is needed, as files are looked up for every request.
+fn open_out() -> Vec<u8> {
+    vec![0; 16]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+
+    /// A request with the given opcode, node, and body
+    fn req(opcode: u32, node: u64, body: &[u8]) -> Vec<u8> {
+        let mut v = Vec::new();
+        put32(&mut v, 40 + body.len() as u32);
+        put32(&mut v, opcode);
+        put64(&mut v, 7);
+        put64(&mut v, node);
+        v.resize(40, 0);
+        v.extend_from_slice(body);
+        v
+    }
+
+    fn name(n: &str) -> Vec<u8> {
+        let mut v = n.as_bytes().to_vec();
+        v.push(0);
+        v
+    }
+
+    /// The errno and body of a reply
+    fn call<S: ReadAt>(s: &mut Session<S>, opcode: u32, node: u64, body: &[u8]) -> (i32, Vec<u8>) {
+        let r = s.dispatch(&req(opcode, node, body)).unwrap();
+        assert_eq!(read_num_bytes!(u32, 4, &r[..]) as usize, r.len());
+        assert_eq!(read_num_bytes!(u64, 8, &r[8..]), 7);
+        (-(read_num_bytes!(u32, 4, &r[4..]) as i32), r[16..].to_vec())
+    }
+
+    fn u64_at(v: &[u8], offs: usize) -> u64 {
+        read_num_bytes!(u64, 8, &v[offs..])
+    }
+
+    #[test]
+    fn file_ops() {
+        let mut img = Image::new();
+        img.add_file("Hello.txt", b"hello", false, 5);
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let mut s = Session::new(&fs, Some(&fs), 1000, 100);
+
+        let mut init = Vec::new();
+        for x in &[7, 40, 65536, FUSE_BIG_WRITES] {
+            put32(&mut init, *x);
+        }
+        let (e, r) = call(&mut s, INIT, 0, &init);
+        assert_eq!((e, r.len()), (0, 64));
+        assert_eq!(read_num_bytes!(u32, 4, &r[4..]), KERNEL_MINOR_VERSION);
+
+        /* names are matched without case, and the stored name is kept */
+        let (e, r) = call(&mut s, LOOKUP, ROOT_ID, &name("HELLO.TXT"));
+        assert_eq!(e, 0);
+        let f = u64_at(&r, 0);
+        assert_eq!(u64_at(&r, 40 + 8), 5);
+        assert_eq!(s.path(f), Ok("/Hello.txt".to_owned()));
+        assert_eq!(call(&mut s, LOOKUP, ROOT_ID, &name("missing")).0, libc::ENOENT);
+
+        let mut read = vec![0; 16];
+        put32(&mut read, 100);
+        read.resize(40, 0);
+        assert_eq!(call(&mut s, READ, f, &read), (0, b"hello".to_vec()));
+
+        let mut write = vec![0; 8];
+        put64(&mut write, 3);
+        put32(&mut write, 4);
+        write.resize(40, 0);
+        write.extend_from_slice(b"p me");
+        assert_eq!(call(&mut s, WRITE, f, &write).0, 0);
+        assert_eq!(call(&mut s, READ, f, &read), (0, b"help me".to_vec()));
+
+        let (e, r) = call(&mut s, MKDIR, ROOT_ID, &[&[0u8; 8][..], &name("d")].concat());
+        assert_eq!(e, 0);
+        let d = u64_at(&r, 0);
+        /* a failed rename leaves the target alone */
+        let mut over_dir = Vec::new();
+        put64(&mut over_dir, ROOT_ID);
+        over_dir.extend_from_slice(&name("hello.txt"));
+        over_dir.extend_from_slice(&name("d"));
+        assert_eq!(call(&mut s, RENAME, ROOT_ID, &over_dir).0, libc::EISDIR);
+        assert_eq!(call(&mut s, GETATTR, d, &[]).0, 0);
+        let mut rename = Vec::new();
+        put64(&mut rename, d);
+        rename.extend_from_slice(&name("hello.txt"));
+        rename.extend_from_slice(&name("moved"));
+        assert_eq!(call(&mut s, RENAME, ROOT_ID, &rename).0, 0);
+        assert_eq!(s.path(f), Ok("/d/moved".to_owned()));
+        assert_eq!(xfs::read(&fs, "/d/moved").unwrap(), b"help me");
+
+        /* chmod a-w sets READ_ONLY */
+        let mut setattr = Vec::new();
+        put32(&mut setattr, FATTR_MODE);
+        setattr.resize(68, 0);
+        put32(&mut setattr, libc::S_IFREG | 0o444);
+        setattr.resize(88, 0);
+        let (e, r) = call(&mut s, SETATTR, f, &setattr);
+        assert_eq!(e, 0);
+        assert_eq!(read_num_bytes!(u32, 4, &r[16 + 60..]), libc::S_IFREG | 0o444);
+        assert!(fs.lookup("/d/moved").unwrap().attributes().contains(FileAttributes::READ_ONLY));
+
+        xfs::write(&fs, "d/other", b"").unwrap();
+        let mut readdir = vec![0; 16];
+        put32(&mut readdir, 4096);
+        readdir.resize(40, 0);
+        let (e, r) = call(&mut s, READDIR, d, &readdir);
+        assert_eq!(e, 0);
+        assert_eq!(&r[24..25], b".");
+        assert_eq!(&r[(32 + 24)..(32 + 26)], b"..");
+        assert_eq!(&r[(64 + 24)..(64 + 29)], b"moved");
+        assert_eq!(u64_at(&r, 64), f);
+        /* entries which haven't been looked up aren't given nodes */
+        assert_eq!((u64_at(&r, 96), &r[(96 + 24)..(96 + 29)]), (UNKNOWN_INO, &b"other"[..]));
+        assert_eq!(s.nodes.len(), 3);
+        fs.remove_file("d/other").unwrap();
+
+        assert_eq!(call(&mut s, RMDIR, ROOT_ID, &name("d")).0, libc::ENOTEMPTY);
+        assert_eq!(call(&mut s, UNLINK, d, &name("moved")).0, 0);
+        assert_eq!(call(&mut s, GETATTR, f, &[]).0, libc::ENOENT);
+        assert_eq!(call(&mut s, RMDIR, ROOT_ID, &name("d")).0, 0);
+
+        assert!(s.dispatch(&req(FORGET, f, &[1, 0, 0, 0, 0, 0, 0, 0])).is_none());
+        assert!(s.path(f).is_err());
+        assert_eq!(call(&mut s, 9999, ROOT_ID, &[]).0, libc::ENOSYS);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut img = Image::new();
+        img.add_file("f", b"abc", false, 3);
+        /* a store that can only be read from */
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        let mut s = Session::new(&fs, None, 0, 0);
+
+        let (e, r) = call(&mut s, LOOKUP, ROOT_ID, &name("f"));
+        assert_eq!(e, 0);
+        let f = u64_at(&r, 0);
+        assert_eq!(read_num_bytes!(u32, 4, &r[40 + 60..]), libc::S_IFREG | 0o444);
+        assert_eq!(call(&mut s, OPEN, f, &[2, 0, 0, 0, 0, 0, 0, 0]).0, libc::EROFS);
+        assert_eq!(call(&mut s, OPEN, f, &[0; 8]).0, 0);
+        assert_eq!(call(&mut s, MKDIR, ROOT_ID, &[&[0u8; 8][..], &name("d")].concat()).0, libc::EROFS);
+        let mut setattr = Vec::new();
+        put32(&mut setattr, FATTR_SIZE);
+        setattr.resize(88, 0);
+        assert_eq!(call(&mut s, SETATTR, f, &setattr).0, libc::EROFS);
+    }
+}
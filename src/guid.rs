@@ -0,0 +1,168 @@
+use ::io_at::{ReadAt,WriteAt};
+use ::std::collections::hash_map::RandomState;
+use ::std::fmt;
+use ::std::hash::BuildHasher;
+use ::std::time::SystemTime;
+use super::{entry_type,DirEntry,EntrySet,Error,Fs,Result};
+
+/// A GUID as stored on disk, in the mixed-endian layout used by Windows: a little endian u32, two
+/// little endian u16s, then 8 bytes.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq,Hash,PartialOrd,Ord)]
+pub struct Guid(pub [u8;16]);
+
+impl Guid {
+    /// A new random (version 4) GUID
+    pub fn generate() -> Self {
+        /* RandomState is randomly keyed per process, and the keys change for each new instance */
+        let t = SystemTime::now();
+        let mut v = [0u8;16];
+        for (i, c) in v.chunks_mut(8).enumerate() {
+            c.copy_from_slice(&RandomState::new().hash_one((t, i)).to_le_bytes());
+        }
+
+        v[7] = (v[7] & 0x0f) | 0x40;
+        v[8] = (v[8] & 0x3f) | 0x80;
+        Guid(v)
+    }
+
+    pub fn bytes(&self) -> &[u8;16] {
+        &self.0
+    }
+
+    /// True if every byte is zero. The null GUID is not a valid identifier.
+    pub fn is_null(&self) -> bool {
+        self.0 == [0;16]
+    }
+}
+
+impl fmt::Display for Guid {
+    /// The usual `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-",
+               read_num_bytes!(u32, 4, &b[0..]),
+               read_num_bytes!(u16, 2, &b[4..]),
+               read_num_bytes!(u16, 2, &b[6..]))?;
+        for (i, x) in b[8..].iter().enumerate() {
+            if i == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02X}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// Volume GUID entry offsets: 4 (GeneralPrimaryFlags, size 2), 6 (VolumeGuid, size 16)
+fn guid_from_entry(e: &DirEntry) -> Guid {
+    Guid(*index_fixed!(&e.raw()[6..]; .. 16))
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// The volume serial number from the boot sector. See also `volume_guid()`.
+    pub fn volume_serial_num(&self) -> u32 {
+        self.boot_sector().volume_serial_num()
+    }
+
+    /// The GUID from the root directory's Volume GUID entry, if there is one
+    pub fn volume_guid(&self) -> Result<Option<Guid>> {
+        Ok(self.root_entry_set(entry_type::VOLUME_GUID)?.map(|set| guid_from_entry(set.primary())))
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Set the volume GUID, adding a Volume GUID entry to the root directory if there isn't one.
+    /// `None` removes the entry.
+    pub fn set_volume_guid(&self, guid: Option<Guid>) -> Result<()> {
+        self.check_writable()?;
+        let mut root = self.root_dir()?;
+        let existing = self.root_entry_set(entry_type::VOLUME_GUID)?;
+        let guid = match guid {
+            Some(g) => g,
+            None => {
+                return match existing {
+                    Some(set) => root.remove_set(&set),
+                    None => Ok(()),
+                };
+            },
+        };
+        if guid.is_null() {
+            return Err(Error::InvalidInput("the null GUID is not allowed"));
+        }
+
+        /* no secondary entries, and the GeneralPrimaryFlags are always zero */
+        let mut e = [0u8;32];
+        e[0] = entry_type::VOLUME_GUID;
+        e[6..22].copy_from_slice(guid.bytes());
+        let mut set = EntrySet::new(vec![DirEntry::from(e)]);
+
+        match existing {
+            /* replaced, so any secondary entries the old set had are removed too */
+            Some(old) => root.replace_set(&old, &mut set),
+            None => root.insert_set(&mut set),
+        }
+    }
+
+    /// Generate a new random volume GUID and store it, returning the GUID
+    pub fn generate_volume_guid(&self) -> Result<Guid> {
+        let g = Guid::generate();
+        self.set_volume_guid(Some(g))?;
+        Ok(g)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+
+    #[test]
+    fn display() {
+        let g = Guid([0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66,
+                      0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+        assert_eq!(g.to_string(), "00112233-4455-6677-8899-AABBCCDDEEFF");
+        assert_ne!(Guid::generate(), Guid::generate());
+        assert_eq!(Guid::generate().to_string().as_bytes()[14], b'4');
+    }
+
+    #[test]
+    fn set_generate_and_remove() {
+        let mut img = Image::new();
+        let g = {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            assert_eq!(fs.volume_guid().unwrap(), None);
+            assert_eq!(fs.volume_serial_num(), 0x1234_5678);
+            fs.generate_volume_guid().unwrap()
+        };
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        assert_eq!(fs.volume_guid().unwrap(), Some(g));
+        let set = fs.root_entry_set(entry_type::VOLUME_GUID).unwrap().unwrap();
+        assert_eq!(set.set_checksum(), set.compute_checksum());
+
+        let h = Guid([1; 16]);
+        fs.set_volume_guid(Some(h)).unwrap();
+        assert_eq!(fs.volume_guid().unwrap(), Some(h));
+        assert!(fs.set_volume_guid(Some(Guid::default())).is_err());
+        fs.set_volume_guid(None).unwrap();
+        assert_eq!(fs.volume_guid().unwrap(), None);
+    }
+
+    #[test]
+    fn replace_with_secondaries() {
+        let mut img = Image::new();
+        let mut set = [[0u8; 32]; 2];
+        set[0][0] = entry_type::VOLUME_GUID;
+        set[0][6..22].copy_from_slice(&[5; 16]);
+        set[1][0] = entry_type::VENDOR_EXTENSION;
+        Image::seal(&mut set);
+        img.push_root(&set);
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        assert_eq!(fs.volume_guid().unwrap(), Some(Guid([5; 16])));
+        fs.set_volume_guid(Some(Guid([6; 16]))).unwrap();
+        assert_eq!(fs.volume_guid().unwrap(), Some(Guid([6; 16])));
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+    }
+}
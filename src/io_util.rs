@@ -0,0 +1,94 @@
+/*
+ * `ReadAt` & `WriteAt` may transfer less than requested in a single call. Every access to a store
+ * goes through these helpers so that short transfers are always completed (or reported).
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::io;
+
+/// Fill `buf` from `s` starting at `offs`, retrying after short reads & `Interrupted` errors.
+///
+/// Reaching the end of the store first (as with a truncated image) is an `UnexpectedEof` error.
+pub fn read_exact_at<R: ReadAt + ?Sized>(s: &R, mut buf: &mut [u8], mut offs: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match s.read_at(buf, offs) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("store ends at byte {}, before the end of a read", offs)));
+            },
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offs += n as u64;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write all of `buf` to `s` starting at `offs`, retrying after short writes & `Interrupted`
+/// errors.
+///
+/// A store which accepts no data (usually because it's full) is a `WriteZero` error.
+pub fn write_all_at<W: WriteAt + ?Sized>(s: &mut W, mut buf: &[u8], mut offs: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match s.write_at(buf, offs) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          format!("store accepted no data at byte {}", offs)));
+            },
+            Ok(n) => {
+                buf = &buf[n..];
+                offs += n as u64;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::{Image,Trickle};
+    use super::super::Fs;
+
+    #[test]
+    fn short_transfers() {
+        let mut v = [0u8; 10];
+        let mut t = Trickle::new(&mut v[..]);
+        write_all_at(&mut t, b"abcdef", 2).unwrap();
+        let mut b = [0u8; 8];
+        read_exact_at(&t, &mut b, 1).unwrap();
+        assert_eq!(&b, b"\0abcdef\0");
+        assert!(t.calls() > 14);
+
+        let e = read_exact_at(&t, &mut b, 5).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(write_all_at(&mut t, b"xyz", 9).unwrap_err().kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn filesystem_on_trickle_store() {
+        let mut img = Image::new();
+        {
+            let fs = Fs::from_rw(Trickle::new(&mut img.data)).unwrap();
+            ::fs::create_dir(&fs, "d").unwrap();
+            ::fs::write(&fs, "d/f", &[7; 1500]).unwrap();
+            fs.set_volume_label("trickle").unwrap();
+        }
+
+        let fs = Fs::from_ro(Trickle::new(&img.data[..])).unwrap();
+        assert_eq!(::fs::read(&fs, "d/f").unwrap(), vec![7; 1500]);
+        assert_eq!(fs.volume_label().unwrap(), "trickle");
+
+        /* a truncated image is reported rather than read as zeros */
+        let end = fs.boot_sector().cluster_offs(fs.lookup("d/f").unwrap().first_cluster()) + 700;
+        let fs = Fs::from_ro(&img.data[..end as usize]).unwrap();
+        match ::fs::read(&fs, "d/f").map_err(::Error::from) {
+            Err(::Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            r => panic!("{:?}", r),
+        }
+    }
+}
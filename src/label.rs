@@ -0,0 +1,117 @@
+use ::io_at::{ReadAt,WriteAt};
+use super::{entry_type,DirEntry,EntrySet,Error,Fs,Result};
+
+/// Maximum length of a volume label in UTF-16 code units
+const MAX_LABEL_LEN: usize = 11;
+
+/// Volume Label entry offsets: 1 (CharacterCount), 2 (VolumeLabel, 22 bytes)
+fn label_from_entry(e: &DirEntry) -> Result<String> {
+    let n = e.raw()[1] as usize;
+    if n > MAX_LABEL_LEN {
+        return Err(Error::corrupt("volume label is too long"));
+    }
+    let v: Vec<u16> = e.raw()[2..(2 + n * 2)].chunks(2)
+        .map(|c| read_num_bytes!(u16, 2, c))
+        .collect();
+    Ok(String::from_utf16_lossy(&v))
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// The volume label, or an empty string if the volume has none
+    pub fn volume_label(&self) -> Result<String> {
+        match self.root_entry_set(entry_type::VOLUME_LABEL)? {
+            Some(set) => label_from_entry(set.primary()),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Set the volume label to `label`, which may be at most 11 UTF-16 code units long.
+    ///
+    /// A Volume Label entry is added to the root directory if there isn't one, and an empty `label`
+    /// removes the entry.
+    pub fn set_volume_label(&self, label: &str) -> Result<()> {
+        self.check_writable()?;
+        let v: Vec<u16> = label.encode_utf16().collect();
+        if v.len() > MAX_LABEL_LEN {
+            return Err(Error::Name("volume label is too long"));
+        }
+        if label.chars().any(|c| (c as u32) < 0x20) {
+            return Err(Error::Name("volume label contains a control character"));
+        }
+
+        let mut root = self.root_dir()?;
+        let existing = self.root_entry_set(entry_type::VOLUME_LABEL)?;
+        if v.is_empty() {
+            return match existing {
+                Some(set) => root.remove_set(&set),
+                None => Ok(()),
+            };
+        }
+
+        let mut e = [0u8;32];
+        e[0] = entry_type::VOLUME_LABEL;
+        e[1] = v.len() as u8;
+        for (i, c) in v.iter().enumerate() {
+            write_num_bytes!(u16, 2, &mut e[(2 + i * 2)..], *c);
+        }
+
+        match existing {
+            Some(set) => root.write_entries(set.index(), &[DirEntry::from(e)]),
+            None => {
+                let mut set = EntrySet::new(vec![DirEntry::from(e)]);
+                root.insert_set(&mut set)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Fs;
+    use ::testutil::Image;
+
+    #[test]
+    fn set_and_clear() {
+        let mut img = Image::new();
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            assert_eq!(fs.volume_label().unwrap(), "");
+            fs.set_volume_label("CAMERA Ω").unwrap();
+            fs.create_file("f").unwrap();
+        }
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            assert_eq!(fs.volume_label().unwrap(), "CAMERA Ω");
+            assert!(fs.set_volume_label("twelve chars").is_err());
+            fs.set_volume_label("elevenchars").unwrap();
+            assert_eq!(fs.volume_label().unwrap(), "elevenchars");
+            assert_eq!(fs.lookup("f").unwrap().index(), 3);
+
+            fs.set_volume_label("").unwrap();
+            assert_eq!(fs.volume_label().unwrap(), "");
+            fs.set_volume_label("").unwrap();
+        }
+
+        /* the cleared slot is reused */
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.set_volume_label("again").unwrap();
+        assert_eq!(fs.volume_label().unwrap(), "again");
+        assert_eq!(fs.lookup("f").unwrap().index(), 3);
+    }
+
+    #[test]
+    fn damaged_file_in_root() {
+        let mut img = Image::new();
+        let mut bad = Image::file_set("bad", 0x20, 0, 0, 0, 0);
+        bad[0][8] ^= 1;
+        img.push_root(&bad);
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        assert_eq!(fs.volume_label().unwrap(), "");
+        fs.set_volume_label("label").unwrap();
+        assert_eq!(fs.volume_label().unwrap(), "label");
+        assert_eq!(fs.volume_guid().unwrap(), None);
+    }
+}
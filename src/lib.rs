@@ -1,4 +1,4 @@
-/**
+/*!
  * exFat filesystem
  *
  * A sector contains a fixed number (per-exfat volume, power of 2) of bytes.
@@ -42,27 +42,19 @@
 #[macro_use]
 extern crate index_fixed;
 extern crate io_at;
+extern crate io_block;
 extern crate fmt_extra;
 extern crate core;
+#[macro_use]
+extern crate bitflags;
+#[cfg(feature = "fuse")]
+extern crate libc;
 
 use ::io_at::{ReadAt,WriteAt};
 use ::std::io::Read;
-use ::std::ops::Index;
-use ::fmt_extra::AsciiStr;
+pub use io_util::{read_exact_at,write_all_at};
 use ::std::{mem,slice};
-
-#[derive(Debug)]
-pub enum BootSectorInitError {
-    BadMagic(AsciiStr<[u8;8]>),
-    MustBeZeroNonZero,
-    FatOffsTooSmall(u32)
-}
-
-#[derive(Debug)]
-pub enum BootSectorInitIoError {
-    Io(::std::io::Error),
-    Init(BootSectorInitError)
-}
+use ::std::cell::{Cell,Ref,RefCell};
 
 macro_rules! read_num_bytes {
     ($ty:ty, $size:expr, $src:expr) => ({
@@ -79,6 +71,62 @@ macro_rules! read_num_bytes {
     });
 }
 
+macro_rules! write_num_bytes {
+    ($ty:ty, $size:expr, $dst:expr, $val:expr) => ({
+        assert!($size == ::core::mem::size_of::<$ty>());
+        let v: $ty = $val;
+        $dst[..$size].copy_from_slice(&v.to_le_bytes());
+    });
+}
+
+mod error;
+mod io_util;
+mod block;
+mod upcase;
+mod bitmap;
+mod dir;
+mod entry_set;
+mod file;
+mod ops;
+mod timestamp;
+mod attributes;
+mod label;
+mod guid;
+mod vendor;
+pub mod fs;
+pub mod partition;
+pub mod raw;
+mod walk;
+mod check;
+mod repair;
+mod build;
+mod extract;
+mod undelete;
+mod orphan;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(test)]
+mod testutil;
+
+pub use error::{Error,Location,Result};
+pub use block::BlockStore;
+pub use upcase::UpcaseTable;
+pub use bitmap::Bitmap;
+pub use dir::{Dir,DirEntries,EntrySets};
+pub use entry_set::{set_checksum,EntrySet};
+pub use file::File;
+pub use timestamp::Timestamp;
+pub use attributes::FileAttributes;
+pub use guid::Guid;
+pub use vendor::{VendorAllocation,VendorExtension};
+pub use walk::{Walk,WalkEntry,WalkOrder};
+pub use check::{boot_checksum,CheckReport,Finding,Problem,Severity};
+pub use repair::{Fixes,Repair,RepairReport};
+pub use build::{ImageBuilder,TimeSource};
+pub use extract::{ExtractFailure,ExtractReport};
+pub use undelete::{Confidence,DeletedFile};
+pub use orphan::{Orphan,OrphanDir};
+
 /**
  * An Exfat superblock. Sometimes refered to as a "boot sector". Contains all the essential items
  * for recognizing and using the filesystem.
@@ -89,35 +137,47 @@ macro_rules! read_num_bytes {
  * As an alternative, it might make sense to construct this from any AsRef<[u8]> which can promise
  * it's long enough.
  */
+#[derive(Clone)]
 pub struct BootSector {
     raw: [u8;512],
 }
 
+impl ::std::fmt::Debug for BootSector {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("BootSector")
+            .field("volume_len", &self.volume_len())
+            .field("fat_offs", &self.fat_offs())
+            .field("fat_len", &self.fat_len())
+            .field("cluster_heap_offs", &self.cluster_heap_offs())
+            .field("cluster_count", &self.cluster_count())
+            .field("first_cluster_of_root_dir", &self.first_cluster_of_root_dir())
+            .field("bytes_per_sector_shift", &self.bytes_per_sector_shift())
+            .field("sectors_per_cluster_shift", &self.sectors_per_cluster_shift())
+            .finish()
+    }
+}
+
 impl BootSector {
     /*
      * FIXME: we really need a unification of ReadAt and Read here: as we're only doing a single
      * call (and don't care where the cursor ends up), it'd be nice to allow either
      */
     /// Populate with a superblock from this `ReadAt`able thing, at a given offset
-    pub fn read_at_from<R: ReadAt>(s: R, offs: u64) -> Result<Self, BootSectorInitIoError> {
-        let mut sb = unsafe { BootSector { raw: ::std::mem::uninitialized() } };
-        /*
-         * FIXME: ReadAt does not promise that this returns all the data requested. Add a wrapper
-         * here or in io-at
-         */
-        try!(s.read_at(&mut sb.raw, offs).map_err(|e| BootSectorInitIoError::Io(e)));
-        sb.validate().map_err(|e| BootSectorInitIoError::Init(e))
+    pub fn read_at_from<R: ReadAt>(s: R, offs: u64) -> Result<Self> {
+        let mut sb = BootSector { raw: [0;512] };
+        read_exact_at(&s, &mut sb.raw, offs)?;
+        sb.validate().map_err(|e| e.at(Location::Offset(offs)))
     }
 
     /// Populate with a superblock from this `Read`able thing, at it's current offset
-    pub fn read_from<R: Read>(mut s: R) -> Result<Self, BootSectorInitIoError> {
-        let mut sb = unsafe { BootSector { raw: ::std::mem::uninitialized() } };
-        try!(s.read_exact(&mut sb.raw).map_err(|e| BootSectorInitIoError::Io(e)));
-        sb.validate().map_err(|e| BootSectorInitIoError::Init(e))
+    pub fn read_from<R: Read>(mut s: R) -> Result<Self> {
+        let mut sb = BootSector { raw: [0;512] };
+        s.read_exact(&mut sb.raw)?;
+        sb.validate()
     }
 
     /// Create from the exact amount of data needed
-    pub fn from(s: [u8;512]) -> Result<Self, BootSectorInitError> {
+    pub fn from(s: [u8;512]) -> Result<Self> {
         /* validate BootSector */
         BootSector { raw: s }.validate()
     }
@@ -223,6 +283,8 @@ impl BootSector {
         read_num_bytes!(u32, 4, &self.raw()[96..])
     }
 
+    /// Volumes may also have a GUID, see `Fs::volume_guid()`
+    ///
     /// offset: 100, size 4
     pub fn volume_serial_num(&self) -> u32 {
         read_num_bytes!(u32, 4, &self.raw()[100..])
@@ -325,13 +387,48 @@ impl BootSector {
         index_fixed!(&self.raw(); 510, .. (510+2))
     }
 
-    fn validate(self) -> Result<Self, BootSectorInitError> {
+    /// Number of bytes in a sector, derived from `bytes_per_sector_shift`
+    pub fn bytes_per_sector(&self) -> u64 {
+        1 << self.bytes_per_sector_shift()
+    }
+
+    /// Number of bytes in a cluster, derived from `bytes_per_sector_shift` and
+    /// `sectors_per_cluster_shift`
+    pub fn bytes_per_cluster(&self) -> u64 {
+        1 << (self.bytes_per_sector_shift() + self.sectors_per_cluster_shift())
+    }
+
+    /// Volume-relative byte offset of the cluster with index `cluster`
+    ///
+    /// The first cluster in the cluster heap has index 2.
+    pub fn cluster_offs(&self, cluster: u32) -> u64 {
+        ((self.cluster_heap_offs() as u64) << self.bytes_per_sector_shift())
+            + ((cluster as u64 - 2) << (self.bytes_per_sector_shift() + self.sectors_per_cluster_shift()))
+    }
+
+    /// Index (0 or 1) of the FAT and allocation bitmap currently in use
+    pub fn active_fat(&self) -> u8 {
+        if self.number_of_fats() > 1 { (self.volume_flags() & 1) as u8 } else { 0 }
+    }
+
+    /// Volume-relative byte offset of the active FAT
+    pub fn active_fat_offs(&self) -> u64 {
+        (self.fat_offs() as u64 + self.active_fat() as u64 * self.fat_len() as u64)
+            << self.bytes_per_sector_shift()
+    }
+
+    /// True if `cluster` refers to a cluster within the cluster heap
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count()
+    }
+
+    fn validate(self) -> Result<Self> {
         /* 0,1,2: jmp junk */
         /* 3-11: "EXFAT" */
         {
             let magic = self.magic();
             if magic != b"EXFAT   " {
-                return Err(BootSectorInitError::BadMagic(AsciiStr(magic.clone())))
+                return Err(Error::corrupt("boot sector does not have the exFAT file system name"));
             }
         }
 
@@ -340,25 +437,45 @@ impl BootSector {
             let z = &self.raw()[11..(11+53)];
             for b in z {
                 if *b != 0 {
-                    return Err(BootSectorInitError::MustBeZeroNonZero);
+                    return Err(Error::corrupt("boot sector MustBeZero field is not zero"));
                 }
             }
         }
 
         if self.fat_offs() < 24 {
-            return Err(BootSectorInitError::FatOffsTooSmall(self.fat_offs()));
+            return Err(Error::corrupt("boot sector FatOffset is too small"));
         }
 
-        {
-            // self.volume_len() > (2**(20-self.bytes_per_sector_shift()))
+        /* the sizes the remaining checks (and everything else) are computed from */
+        if !(9..=12).contains(&self.bytes_per_sector_shift()) {
+            return Err(Error::corrupt("boot sector BytesPerSectorShift is not from 9 to 12"));
         }
-
-        {
-            // self.fat_offs() > (self.cluster_heap_offs() - self.fat_len() * self.num_fats())
+        if self.bytes_per_sector_shift() as u32 + self.sectors_per_cluster_shift() as u32 > 25 {
+            return Err(Error::corrupt("boot sector cluster size is larger than 32MiB"));
+        }
+        if !(1..=2).contains(&self.number_of_fats()) {
+            return Err(Error::corrupt("boot sector NumberOfFats is not 1 or 2"));
         }
 
-        {
-            // self.f
+        /* none of these can overflow: each is at most a u32 shifted by 16, or a sum of u32s */
+        let volume_len = self.volume_len();
+        if volume_len < 1 << (20 - self.bytes_per_sector_shift()) {
+            return Err(Error::corrupt("boot sector VolumeLength is less than 1MiB"));
+        }
+        let fats_end = self.fat_offs() as u64 + self.fat_len() as u64 * self.number_of_fats() as u64;
+        if fats_end > volume_len {
+            return Err(Error::corrupt("boot sector FAT extends past the end of the volume"));
+        }
+        if (self.fat_len() as u64) << self.bytes_per_sector_shift() < (self.cluster_count() as u64 + 2) * 4 {
+            return Err(Error::corrupt("boot sector FatLength is too small for ClusterCount"));
+        }
+        if (self.cluster_heap_offs() as u64) < fats_end {
+            return Err(Error::corrupt("boot sector ClusterHeapOffset overlaps the FAT"));
+        }
+        let heap_end = self.cluster_heap_offs() as u64
+            + ((self.cluster_count() as u64) << self.sectors_per_cluster_shift());
+        if heap_end > volume_len {
+            return Err(Error::corrupt("boot sector cluster heap extends past the end of the volume"));
         }
 
         Ok(self)
@@ -376,7 +493,7 @@ impl BootSector {
  * unmarked.
  */
 #[derive(Clone,Debug)]
-struct ExtendedBootSector {
+pub struct ExtendedBootSector {
     s: Vec<u8>,
     bytes_per_sector_shift: u8,
 }
@@ -386,15 +503,16 @@ impl ExtendedBootSector {
         /* TODO: split the "kind" out early? Or late?
          * Perhaps an enum is appropriate here?
          */
-        ExtendedBootSector { s: s, bytes_per_sector_shift: bytes_per_sector_shift }
+        ExtendedBootSector { s, bytes_per_sector_shift }
     }
 
     pub fn raw(&self) -> &[u8] {
         self.s.as_ref()
     }
 
+    /// The last 4 bytes of the sector
     pub fn signature(&self) -> u32 {
-        let offs = 1 << self.bytes_per_sector_shift - 4;
+        let offs = (1 << self.bytes_per_sector_shift) - 4;
         read_num_bytes!(u32, 4, &self.raw()[offs..])
     }
 
@@ -403,21 +521,14 @@ impl ExtendedBootSector {
     }
 }
 
-struct ExtendedBootSectors;
-
-struct OemParameter {
+#[derive(Clone,Debug)]
+pub struct OemParameter {
     raw: [u8;48],
 }
 
 impl OemParameter {
     pub fn is_used(&self) -> bool {
-        for i in self.uuid() {
-            if *i != 0 {
-                return true;
-            }
-        }
-
-        return false;
+        self.uuid().iter().any(|i| *i != 0)
     }
 
     pub fn uuid(&self) -> &[u8;16] {
@@ -430,35 +541,38 @@ impl OemParameter {
 }
 
 /// The boot record contains a sector containing oem parameters
-struct OemParameters {
+#[derive(Clone,Debug)]
+pub struct OemParameters {
     s: Vec<u8>,
 }
 
 impl OemParameters {
-    pub fn read_at_from<S: ReadAt>(s: S) -> io_at::Result<Self> {
-        s::
+    /*
+     * FIXME: instead of using '512' here, we need to either use the bootsector's sector side
+     * or query the store for the underlying sector size
+     */
+    pub fn read_at_from<S: ReadAt>(s: S, offs: u64) -> io_at::Result<Self> {
+        let mut v = vec![0u8;512];
+        read_exact_at(&s, &mut v, offs)?;
+        Ok(OemParameters::from(v))
     }
 
     pub fn from(s: Vec<u8>) -> Self {
-        OemParameters { s: s }
+        OemParameters { s }
     }
 
     pub fn raw(&self) -> &[u8] {
         self.s.as_ref()
     }
 
-    pub fn all(&self) -> &[OemParameter;10] {
-        unsafe {
-            ::std::mem::transmute::<*const u8, &[OemParameter;10]>
-                (self.raw().as_ptr())
-        }
+    /// The 10 parameter slots, in order
+    pub fn all(&self) -> Vec<OemParameter> {
+        self.raw()[..480].chunks(48).map(|c| {
+            OemParameter { raw: *index_fixed!(&c; .. 48) }
+        }).collect()
     }
 }
 
-pub enum FsInitError {
-    BootSectorInitError(BootSectorInitIoError)
-}
-
 #[derive(Debug, Clone)]
 pub struct BootRegion {
     bs: BootSector,
@@ -470,23 +584,45 @@ impl BootRegion {
     /*
      * TODO: consider using io_at::At adaptor instead of passing `offs` around manually.
      */
-    pub fn read_at_from<S: ReadAt>(t: S, offs: u64) -> Result<Self, BootSectorInitIoError> {
-        let bs = try!(BootSector::read_at_from(&t, offs).map_err(|e| FsInitError::BootSectorInitError(e)));
-        /*
-         * FIXME: instead of using '512' here, we need to either use the bootsector's sector side
-         * or query the store for the underlying sector size
-         */
-        let oem = try!(OemParameters::read_at_from(&t, offs + 512 * 9));
-        Ok(BootRegion { bs: bs, oem: oem })
+    pub fn read_at_from<S: ReadAt>(t: S, offs: u64) -> Result<Self> {
+        let bs = BootSector::read_at_from(&t, offs)?;
+        let oem = OemParameters::read_at_from(&t, offs + bs.bytes_per_sector() * 9)?;
+        Ok(BootRegion { bs, oem })
+    }
+
+    /// Like `read_at_from()`, without validating the boot sector. Used for the backup region,
+    /// which a damaged volume can be opened without (see `Fs::check()`).
+    fn read_unchecked_at_from<S: ReadAt>(t: S, offs: u64, bytes_per_sector: u64) -> Result<Self> {
+        let mut bs = BootSector { raw: [0;512] };
+        read_exact_at(&t, &mut bs.raw, offs)?;
+        let oem = OemParameters::read_at_from(&t, offs + bytes_per_sector * 9)?;
+        Ok(BootRegion { bs, oem })
+    }
+
+    pub fn boot_sector(&self) -> &BootSector {
+        &self.bs
+    }
+
+    pub fn oem_parameters(&self) -> &OemParameters {
+        &self.oem
+    }
+}
+
+/// The 32-bit checksum used by the boot checksum sector and the up-case table: rotate right by one
+/// bit, then add the next byte.
+pub fn checksum32(mut sum: u32, data: &[u8]) -> u32 {
+    for b in data {
+        sum = sum.rotate_right(1).wrapping_add(*b as u32);
     }
+    sum
 }
 
 /// A full filesystem instance. Allows access to all aspects of the filesystem.
 ///
 /// TODO:
 ///  - right now we allocate & read quite a bit on object creation. It would be useful (for
-///  embedded systems and others) to allow defering or avoiding allocations instead. Can we do this
-///  within the confines of our type system without too much extra overhead?
+///    embedded systems and others) to allow defering or avoiding allocations instead. Can we do
+///    this within the confines of our type system without too much extra overhead?
 pub struct Fs<S: ReadAt> {
     // We probably don't need 2 copies of the boot region permenantly. The multiple copies are
     // really only important for initial validation of the filesystem. After that point, the ones
@@ -494,30 +630,344 @@ pub struct Fs<S: ReadAt> {
     // for updating the bootsectors (or pieces thereof). Perhaps we'll need to keep a second copy
     // around with the previous contents? not sure.
     boot_regions: [BootRegion;2],
-    store: S,
+    fat: RefCell<Fat>,
+    bitmap: RefCell<Bitmap>,
+    /// Location of the active allocation bitmap's data
+    bitmap_stream: Stream,
+    upcase: UpcaseTable,
+    /// PercentInUse as it is on disk, which the first write changes
+    percent_in_use: Cell<u8>,
+    read_only: bool,
+    store: RefCell<S>,
 }
 
 impl<S: ReadAt> Fs<S> {
-    pub fn from_ro(t: S) -> Result<Self, FsInitError> {
-        // FIXME: using 512 here is wrong. We need to use either the media's sector size or the
-        // sector size from the first bootsector.
-        let br = [
-            try!(BootRegion::read_at_from(&t, 0)),
-            try!(BootRegion::read_at_from(&t, 512 * 24)),
-        ];
+    /// Open a filesystem without allowing any modifications to it (even if `S` is writable)
+    pub fn from_ro(t: S) -> Result<Self> {
+        // The backup boot region immediately follows the main one (which is 12 sectors long)
+        let main = BootRegion::read_at_from(&t, 0)?;
+        let bps = main.bs.bytes_per_sector();
+        let backup = BootRegion::read_unchecked_at_from(&t, bps * 12, bps)?;
+
+        let percent_in_use = main.bs.percent_in_use();
+        let fat = {
+            let bs = &main.bs;
+            Fat::read_at_from(&t, bs.active_fat_offs(), (bs.cluster_count() as usize + 2) * 4)?
+        };
+
+        let mut fs = Fs {
+            boot_regions: [main, backup],
+            fat: RefCell::new(fat),
+            bitmap: RefCell::new(Bitmap::from(Vec::new(), 0)),
+            bitmap_stream: Stream { first_cluster: 0, no_fat_chain: false, data_len: 0, valid_data_len: 0 },
+            upcase: UpcaseTable::identity(),
+            percent_in_use: Cell::new(percent_in_use),
+            read_only: true,
+            store: RefCell::new(t),
+        };
+        fs.read_root_metadata()?;
+        Ok(fs)
+    }
+
+    /// Load the up-case table & allocation bitmap, which are located via entries in the root
+    /// directory.
+    fn read_root_metadata(&mut self) -> Result<()> {
+        let active = self.boot_sector().active_fat();
+        let mut upcase = None;
+        let mut bitmap = None;
+        let root = self.boot_sector().first_cluster_of_root_dir();
+        for set in self.root_dir()?.entry_sets() {
+            /* a damaged file shouldn't make the whole volume unusable (see `Fs::check()`) */
+            let set = match set {
+                Ok(set) => set,
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) => continue,
+            };
+            let p = *set.primary();
+            let at = Location::Entry { dir: root, index: set.index() };
+            let stream = Stream {
+                first_cluster: p.first_cluster(),
+                no_fat_chain: false,
+                data_len: p.data_len(),
+                valid_data_len: p.data_len(),
+            };
+
+            match set.entry_type() {
+                entry_type::UPCASE_TABLE if upcase.is_none() => {
+                    if p.data_len() > UpcaseTable::MAX_RAW_LEN {
+                        return Err(Error::corrupt_at("up-case table is too large", at));
+                    }
+                    let raw = self.read_stream(&stream)?;
+                    if checksum32(0, &raw) != p.table_checksum() {
+                        return Err(Error::corrupt_at("up-case table checksum mismatch", at));
+                    }
+                    upcase = Some(UpcaseTable::from_raw(&raw));
+                },
+                /* BitmapFlags: bit 0 selects which FAT the bitmap goes with */
+                entry_type::ALLOCATION_BITMAP if p.raw()[1] & 1 == active => {
+                    let cc = self.boot_sector().cluster_count();
+                    let needed = (cc as u64).div_ceil(8);
+                    if p.data_len() < needed {
+                        return Err(Error::corrupt_at("allocation bitmap is too small", at));
+                    }
+                    if p.data_len() > needed.next_multiple_of(self.boot_sector().bytes_per_cluster()) {
+                        return Err(Error::corrupt_at("allocation bitmap is too large", at));
+                    }
+                    bitmap = Some((Bitmap::from(self.read_stream(&stream)?, cc), stream));
+                },
+                _ => {},
+            }
+        }
 
-        Ok(Fs { boot_regions: br, store: t })
+        let missing = |msg| Error::corrupt_at(msg, Location::Cluster(root));
+        self.upcase = upcase.ok_or_else(|| missing("root directory has no up-case table"))?;
+        let (bitmap, stream) = bitmap.ok_or_else(|| missing("root directory has no allocation bitmap"))?;
+        self.bitmap = RefCell::new(bitmap);
+        self.bitmap_stream = stream;
+        Ok(())
     }
 
     pub fn boot_sector(&self) -> &BootSector {
         &self.boot_regions[0].bs
     }
 
+    /// The boot sector's PercentInUse as it is now. Writing to the volume sets it to 0xFF
+    /// (unknown), as it isn't kept up to date, without changing `boot_sector()`.
+    pub fn percent_in_use(&self) -> u8 {
+        self.percent_in_use.get()
+    }
+
+    /// The main & backup boot regions. The backup's boot sector isn't validated.
+    pub fn boot_regions(&self) -> &[BootRegion;2] {
+        &self.boot_regions
+    }
+
     /*
     pub fn ext_boot_sectors(&self) -> &ExtendedBootSectors {
         /* do something?? */
     }
     */
+
+    pub fn fat(&self) -> Ref<'_, Fat> {
+        self.fat.borrow()
+    }
+
+    /// The allocation bitmap associated with the active FAT
+    pub fn bitmap(&self) -> Ref<'_, Bitmap> {
+        self.bitmap.borrow()
+    }
+
+    /// True if this filesystem was opened with `from_ro()`
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn upcase_table(&self) -> &UpcaseTable {
+        &self.upcase
+    }
+
+    /// The store this filesystem was constructed from
+    pub fn store(&self) -> Ref<'_, S> {
+        self.store.borrow()
+    }
+
+    /// Fill `buf` from the store, starting at the volume relative byte offset `offs`
+    fn read_exact_at(&self, buf: &mut [u8], offs: u64) -> Result<()> {
+        Ok(read_exact_at(&*self.store.borrow(), buf, offs)?)
+    }
+
+    /// The root directory. Unlike every other directory, it has no entry describing it and is
+    /// always stored in a FAT cluster chain.
+    pub fn root_dir(&self) -> Result<Dir<'_, S>> {
+        let first = self.boot_sector().first_cluster_of_root_dir();
+        let mut clusters = 0u64;
+        for c in self.fat().chain(first) {
+            if c.is_err() {
+                return Err(Error::corrupt_at("root directory cluster chain is broken", Location::Cluster(first)));
+            }
+            clusters += 1;
+        }
+
+        let len = clusters * self.boot_sector().bytes_per_cluster();
+        Ok(Dir::from_stream(self, Stream {
+            first_cluster: first,
+            no_fat_chain: false,
+            data_len: len,
+            valid_data_len: len,
+        }))
+    }
+
+    /// The first entry set in the root directory with the given primary entry type
+    pub(crate) fn root_entry_set(&self, entry_type: u8) -> Result<Option<EntrySet>> {
+        for set in self.root_dir()?.entry_sets() {
+            /* as in `read_root_metadata()`, damaged files are skipped */
+            let set = match set {
+                Ok(set) => set,
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) => continue,
+            };
+            if set.entry_type() == entry_type {
+                return Ok(Some(set));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Locate the entry set for `path`, which is interpreted relative to the root directory.
+    ///
+    /// Path components are seperated by '/' and compared using the volume's up-case table.
+    pub fn lookup(&self, path: &str) -> Result<EntrySet> {
+        self.locate(path).map(|(_, set)| set)
+    }
+
+    /// Like `lookup()`, but also return the directory containing the entry set
+    pub(crate) fn locate(&self, path: &str) -> Result<(Dir<'_, S>, EntrySet)> {
+        let mut dir = self.root_dir()?;
+        let mut found = None;
+        for name in path.split('/').filter(|c| !c.is_empty()) {
+            if let Some(set) = found.take() {
+                dir = Dir::from_set(self, dir.stream(), set)?;
+            }
+
+            found = Some(dir.find(name)?.ok_or(Error::NotFound)?);
+        }
+
+        match found {
+            Some(set) => Ok((dir, set)),
+            None => Err(Error::InvalidInput("path has no components")),
+        }
+    }
+
+    /// Open the directory at `path`. An empty path (or "/") refers to the root directory.
+    pub fn open_dir(&self, path: &str) -> Result<Dir<'_, S>> {
+        if path.split('/').all(|c| c.is_empty()) {
+            return self.root_dir();
+        }
+
+        let (parent, set) = self.locate(path)?;
+        Dir::from_set(self, parent.stream(), set)
+    }
+
+    /// Open the file at `path`. Writing is only possible if the filesystem was opened with
+    /// `from_rw()`.
+    pub fn open(&self, path: &str) -> Result<File<'_, S>> {
+        let (dir, set) = self.locate(path)?;
+        File::from_set(self, dir.stream(), set)
+    }
+
+    /// Find the cluster holding the `idx`th cluster-sized chunk of `stream`.
+    ///
+    /// `from` is a previously looked up `(idx, cluster)` pair for the same stream, which allows
+    /// sequential access to avoid walking the FAT chain from the start every time.
+    fn stream_cluster(&self, stream: &Stream, idx: u64, from: Option<(u64, u32)>) -> Result<u32> {
+        let bs = self.boot_sector();
+        if stream.no_fat_chain {
+            let c = stream.first_cluster as u64 + idx;
+            if !bs.is_valid_cluster(stream.first_cluster) {
+                return Err(Error::corrupt_at("stream starts outside the cluster heap",
+                                             Location::Cluster(stream.first_cluster)));
+            }
+            if c > u32::MAX as u64 || !bs.is_valid_cluster(c as u32) {
+                return Err(Error::corrupt_at("contiguous stream extends past the cluster heap",
+                                             Location::Cluster(stream.first_cluster)));
+            }
+            return Ok(c as u32);
+        }
+
+        let (mut i, mut c) = match from {
+            Some((i, c)) if i <= idx => (i, c),
+            _ => (0, stream.first_cluster),
+        };
+
+        if !bs.is_valid_cluster(c) {
+            return Err(Error::corrupt_at("stream starts outside the cluster heap", Location::Cluster(c)));
+        }
+
+        while i < idx {
+            let n = self.fat().entry(FatEntry::from_val(c));
+            if !bs.is_valid_cluster(n.val()) {
+                return Err(Error::corrupt_at("cluster chain is shorter than its data length",
+                                             Location::Cluster(c)));
+            }
+            c = n.val();
+            i += 1;
+        }
+
+        Ok(c)
+    }
+
+    /// Read an entire (small) stream into memory
+    fn read_stream(&self, stream: &Stream) -> Result<Vec<u8>> {
+        let bs = self.boot_sector();
+        let cs = bs.bytes_per_cluster();
+
+        /* the clusters are found first, so a corrupt length can't make us allocate more than the
+         * chain holds */
+        let n = stream.valid_data_len.div_ceil(cs);
+        if n > bs.cluster_count() as u64 {
+            return Err(Error::corrupt_at("stream is larger than the cluster heap",
+                                         Location::Cluster(stream.first_cluster)));
+        }
+        let mut clusters = Vec::new();
+        let mut last = None;
+        for i in 0..n {
+            let c = self.stream_cluster(stream, i, last)?;
+            last = Some((i, c));
+            clusters.push(c);
+        }
+
+        let mut v = vec![0u8; stream.valid_data_len as usize];
+        for (chunk, c) in v.chunks_mut(cs as usize).zip(clusters) {
+            self.read_exact_at(chunk, bs.cluster_offs(c))?;
+        }
+        Ok(v)
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Open a filesystem for reading and writing
+    pub fn from_rw(t: S) -> Result<Self> {
+        let mut fs = Fs::from_ro(t)?;
+        fs.read_only = false;
+        Ok(fs)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offs: u64) -> Result<()> {
+        self.check_writable()?;
+        /* PercentInUse isn't kept up to date, so it is marked as unknown before anything changes */
+        if self.percent_in_use.get() != 0xFF {
+            write_all_at(&mut *self.store.borrow_mut(), &[0xFF], 112)?;
+            self.percent_in_use.set(0xFF);
+        }
+        Ok(write_all_at(&mut *self.store.borrow_mut(), buf, offs)?)
+    }
+
+    /// Write `data` to the start of `stream`, which must be large enough to hold it
+    fn write_stream(&self, stream: &Stream, data: &[u8]) -> Result<()> {
+        let cs = self.boot_sector().bytes_per_cluster();
+        let mut last = None;
+        for (i, chunk) in data.chunks(cs as usize).enumerate() {
+            let c = self.stream_cluster(stream, i as u64, last)?;
+            last = Some((i as u64, c));
+            self.write_at(chunk, self.boot_sector().cluster_offs(c))?;
+        }
+        Ok(())
+    }
+
+    /// Update the active FAT's entry for `cluster`
+    fn set_fat(&self, cluster: u32, next: FatEntry) -> Result<()> {
+        self.fat.borrow_mut().v[cluster as usize] = next.val().to_le();
+        let offs = self.boot_sector().active_fat_offs() + cluster as u64 * 4;
+        self.write_at(&next.val().to_le_bytes(), offs)
+    }
 }
 
 /// The FAT (file allocation table) contains a contiguous series of FAT entries.
@@ -540,7 +990,7 @@ pub struct Fat {
 }
 
 unsafe fn as_mut_bytes(v: &mut [u32]) -> &mut [u8] {
-    slice::from_raw_parts_mut(mem::transmute::<*mut u32, *mut u8>(v.as_mut_ptr()), v.len() * mem::size_of::<u32>())
+    slice::from_raw_parts_mut(v.as_mut_ptr() as *mut u8, mem::size_of_val(v))
 }
 
 impl Fat {
@@ -554,31 +1004,34 @@ impl Fat {
      */
     pub fn read_at_from<T: ReadAt>(s: T, offs: u64, len: usize) -> ::io_at::Result<Self> {
         let e = len / 4;
-        if (len % 4) != 0 {
+        if !len.is_multiple_of(4) {
             panic!("FAT length must be a multiple of 4");
         }
 
-        let mut f = Fat { v: Vec::with_capacity(e) };
-        unsafe { f.v.set_len(e) }
-        try!(s.read_at(
+        let mut f = Fat { v: vec![0; e] };
+        read_exact_at(&s,
             unsafe {
                 as_mut_bytes(f.v.as_mut_slice())
-            }, offs)
-        );
+            }, offs)?;
         Ok(f)
     }
 
     pub fn media_type(&self) -> u8 {
-        (self.v[0] & 0xff) as u8
+        (u32::from_le(self.v[0]) & 0xff) as u8
     }
 
     pub fn cluster_ct(&self) -> u32 {
-        self.v.len() as u32 / 4 - 2
+        self.v.len() as u32 - 2
     }
 
     // TODO: consider if we can get Index to work here by abusing a '&' type.
     pub fn entry(&self, e: FatEntry) -> FatEntry {
-        FatEntry::from_val(self.v[e.val() as usize])
+        FatEntry::from_val(u32::from_le(self.v[e.val() as usize]))
+    }
+
+    /// Iterate over the cluster chain starting at `first`
+    pub fn chain(&self, first: u32) -> ClusterChain<'_> {
+        ClusterChain { f: self, e: FatEntry::from_val(first), remaining: self.cluster_ct(), done: false }
     }
 }
 
@@ -589,8 +1042,6 @@ pub struct FatEntry {
     v: u32
 }
 
-// FIXME: we need to convert from LE at some point in here. Doing so in 'from_val' is probably the
-// best choice, and would mean we'd want to name it something like "from_raw" instead.
 impl FatEntry {
     pub fn from_val(i: u32) -> Self {
         FatEntry { v: i }
@@ -620,17 +1071,34 @@ pub struct ClusterHeap {
 }
 */
 
-/// A series of `DirectoryEntry`s stored in a cluster chain
-///
-/// Each entry is 32 bytes
-pub struct Dir {
+/// Describes where the data of a file or directory lives in the cluster heap
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+struct Stream {
+    first_cluster: u32,
+    /// If true, the clusters are contiguous and the FAT does not describe them
+    no_fat_chain: bool,
+    data_len: u64,
+    valid_data_len: u64,
 }
 
+#[derive(Clone,Copy,Eq,PartialEq,Debug)]
 pub struct DirEntry {
     v: [u8;32],
 }
 
 impl DirEntry {
+    pub fn from(v: [u8;32]) -> Self {
+        DirEntry { v }
+    }
+
+    pub fn raw(&self) -> &[u8;32] {
+        &self.v
+    }
+
+    pub(crate) fn raw_mut(&mut self) -> &mut [u8;32] {
+        &mut self.v
+    }
+
     /// 0x00 = end-of-directory, all other fields reserved
     ///        subsequent DirEntries in a Dir are also given this type
     /// 0x01...0x7f: unused-dir-entry marker
@@ -640,10 +1108,39 @@ impl DirEntry {
         self.v[0]
     }
 
+    /// The `entry_type()` broken into its component fields
+    pub fn kind(&self) -> EntryType {
+        EntryType { raw: self.entry_type() }
+    }
+
     pub fn custom_defined(&self) -> &[u8;19] {
         index_fixed!(&self.v; 1, ... 19)
     }
 
+    /// Number of secondary entries following a primary entry
+    ///
+    /// Only meaningful for primary entries using the generic primary layout (File, Volume GUID,
+    /// and benign primary entries)
+    ///
+    /// offset: 1, size: 1
+    pub fn secondary_count(&self) -> u8 {
+        self.v[1]
+    }
+
+    /// Checksum over the entire entry set (see `EntrySet::compute_checksum()`)
+    ///
+    /// offset: 2, size: 2
+    pub fn set_checksum(&self) -> u16 {
+        read_num_bytes!(u16, 2, &self.v[2..])
+    }
+
+    /// Checksum of an up-case table entry's data
+    ///
+    /// offset: 4, size: 4
+    pub fn table_checksum(&self) -> u32 {
+        read_num_bytes!(u32, 4, &self.v[4..])
+    }
+
     pub fn first_cluster(&self) -> u32 {
         read_num_bytes!(u32, 4, &self.v[20..])
     }
@@ -653,57 +1150,169 @@ impl DirEntry {
     }
 }
 
+/// Values for `DirEntry::entry_type()` that are defined by the exFAT specification
+pub mod entry_type {
+    pub const END_OF_DIRECTORY: u8 = 0x00;
+    pub const ALLOCATION_BITMAP: u8 = 0x81;
+    pub const UPCASE_TABLE: u8 = 0x82;
+    pub const VOLUME_LABEL: u8 = 0x83;
+    pub const FILE: u8 = 0x85;
+    pub const VOLUME_GUID: u8 = 0xA0;
+    pub const STREAM_EXTENSION: u8 = 0xC0;
+    pub const FILE_NAME: u8 = 0xC1;
+    pub const VENDOR_EXTENSION: u8 = 0xE0;
+    pub const VENDOR_ALLOCATION: u8 = 0xE1;
+}
+
+#[derive(Clone,Copy,Eq,PartialEq,Debug)]
 pub struct EntryType {
     raw: u8
 }
 
 impl EntryType {
+    pub fn from(raw: u8) -> Self {
+        EntryType { raw }
+    }
+
     pub fn type_code(&self) -> u8 {
-        self.raw & ((1 << 6) - 1)
+        self.raw & ((1 << 5) - 1)
     }
 
+    /// 0 = critical, 1 = benign
     pub fn type_importance(&self) -> u8 {
         (self.raw >> 5) & 1
     }
 
+    /// 0 = primary, 1 = secondary
     pub fn type_category(&self) -> u8 {
         (self.raw >> 6) & 1
     }
 
     /// note: 0x1...0x7f, "unused-directory-entry" when this is false.
     pub fn in_use(&self) -> bool {
-        (self.raw >> 6) & 1 != 0
+        (self.raw >> 7) & 1 != 0
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.type_category() == 0
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.type_importance() == 0
     }
 }
 
 /// An iterator over a cluster chain
+///
+/// Yields `Err(c)` (and then stops) if the entry following cluster `c` is marked bad, if `c` is
+/// outside the cluster heap, or if the chain is longer than the number of clusters (which
+/// indicates a loop).
 #[derive(Clone)]
 pub struct ClusterChain<'a> {
     f: &'a Fat,
     e: FatEntry,
+    remaining: u32,
+    done: bool,
 }
 
 impl<'a> Iterator for ClusterChain<'a> {
-    type Item = Result<FatEntry, FatEntry>;
+    type Item = ::std::result::Result<FatEntry, FatEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let c = self.e;
-        if c.is_last() {
-            None
+        if c.is_last() || self.done {
+            return None;
+        }
+
+        if c.val() < 2 || c.val() as usize >= self.f.v.len() || self.remaining == 0 {
+            self.done = true;
+            return Some(Err(c));
+        }
+
+        self.remaining -= 1;
+        self.e = self.f.entry(c);
+        if self.e.is_bad() {
+            self.done = true;
+            Some(Err(c))
         } else {
-            self.e = self.f.entry(c);
-            if self.e.is_bad() {
-                Some(Err(c))
-            } else {
-                Some(Ok(c))
-            }
+            Some(Ok(c))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use testutil::Image;
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn open_blank() {
+        let img = Image::new();
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        assert_eq!(fs.boot_sector().cluster_count(), img.cluster_count());
+        assert_eq!(fs.root_dir().unwrap().entry_sets().count(), 2);
+    }
+
+    #[test]
+    fn hostile_boot_sector() {
+        /* (offset, size, value) of a field to overwrite */
+        let cases: &[(usize, usize, u64)] = &[
+            (109, 1, 60),           /* SectorsPerClusterShift: cluster size overflows */
+            (109, 1, 17),           /* 2**26 byte clusters */
+            (108, 1, 8),            /* BytesPerSectorShift too small */
+            (108, 1, 200),
+            (110, 1, 0),            /* NumberOfFats */
+            (72, 8, 100),           /* VolumeLength below 1MiB */
+            (84, 4, 0xFFFF_FFFF),   /* FatLength past the end of the volume */
+            (84, 4, 1),             /* FatLength too small for ClusterCount */
+            (88, 4, 25),            /* ClusterHeapOffset inside the FAT */
+            (92, 4, 0xFFFF_FFF0),   /* ClusterCount past the end of the volume */
+        ];
+        for &(offs, size, v) in cases {
+            let mut img = Image::new();
+            testutil::put(&mut img.data, offs, v, size);
+            img.update_boot_checksum();
+            match Fs::from_ro(&img.data[..]) {
+                Err(Error::Corrupt { .. }) => {},
+                Err(e) => panic!("{} = {}: {}", offs, v, e),
+                Ok(_) => panic!("{} = {}: opened", offs, v),
+            }
+            /* reported as it is, rather than as a disk without a volume */
+            match partition::open_exfat(&img.data[..]) {
+                Err(Error::Corrupt { .. }) => {},
+                Err(e) => panic!("{} = {}: {}", offs, v, e),
+                Ok(_) => panic!("{} = {}: found", offs, v),
+            }
+        }
+    }
+
+    #[test]
+    fn hostile_bitmap_len() {
+        let mut img = Image::new();
+        let e = img.cluster_offs(read_num_bytes!(u32, 4, &img.data[96..]));
+        testutil::put(&mut img.data, e + 24, u64::MAX, 8);
+        assert!(matches!(Fs::from_ro(&img.data[..]), Err(Error::Corrupt { .. })));
+
+        /* streams longer than their chain are refused before being read */
+        let img = Image::new();
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        let root = fs.boot_sector().first_cluster_of_root_dir();
+        for len in &[u64::MAX, 1 << 40, 100 * fs.boot_sector().bytes_per_cluster()] {
+            let stream = Stream { first_cluster: root, no_fat_chain: false, data_len: *len, valid_data_len: *len };
+            assert!(matches!(fs.read_stream(&stream), Err(Error::Corrupt { .. })), "{}", len);
+        }
+    }
+
+    #[test]
+    fn cluster_chain_loop() {
+        let mut fat = Fat { v: vec![0xFFFF_FFF8, 0xFFFF_FFFF, 3, 2] };
+        assert!(fat.chain(2).any(|c| c.is_err()));
+        fat.v[3] = 0xFFFF_FFFF;
+        assert_eq!(fat.chain(2).collect::<Vec<_>>(),
+            vec![Ok(FatEntry::from_val(2)), Ok(FatEntry::from_val(3))]);
+    }
 }
@@ -0,0 +1,335 @@
+/*
+ * Creating, removing, and renaming directory entries
+ */
+use ::io_at::{ReadAt,WriteAt};
+use super::{Dir,EntrySet,Error,File,FileAttributes,Fs,Result};
+use super::entry_set::validate_name;
+
+/// Split `path` into the path of the containing directory and the final component
+pub(crate) fn split_path(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[(i + 1)..]),
+        None => ("", path),
+    };
+    if name.is_empty() {
+        return Err(Error::InvalidInput("path has no components"));
+    }
+    Ok((parent, name))
+}
+
+fn already_exists() -> Error {
+    Error::AlreadyExists
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Resolve the directory that will contain a new entry at `path`, and the (validated) name of
+    /// that entry
+    fn new_entry_at<'b>(&self, path: &'b str) -> Result<(Dir<'_, S>, &'b str, Vec<u16>)> {
+        let (parent, name) = split_path(path)?;
+        let name16 = validate_name(name)?;
+        let dir = self.open_dir(parent)?;
+        Ok((dir, name, name16))
+    }
+
+    /// Create a new, empty file at `path`. The containing directory must already exist.
+    pub fn create_file(&self, path: &str) -> Result<File<'_, S>> {
+        self.check_writable()?;
+        let (mut dir, name, name16) = self.new_entry_at(path)?;
+        if dir.find(name)?.is_some() {
+            return Err(already_exists());
+        }
+
+        let hash = self.upcase_table().name_hash(&name16);
+        let mut set = EntrySet::new_file(&name16, hash, FileAttributes::ARCHIVE);
+        dir.insert_set(&mut set)?;
+        File::from_set(self, dir.stream(), set)
+    }
+
+    /// Create a new, empty directory at `path`. The containing directory must already exist.
+    pub fn create_dir(&self, path: &str) -> Result<Dir<'_, S>> {
+        self.check_writable()?;
+        let (mut dir, name, name16) = self.new_entry_at(path)?;
+        if dir.find(name)?.is_some() {
+            return Err(already_exists());
+        }
+
+        /* directories always have at least one (zeroed) cluster, all of which is valid data */
+        let cs = self.boot_sector().bytes_per_cluster();
+        let hash = self.upcase_table().name_hash(&name16);
+        let mut set = EntrySet::new_file(&name16, hash, FileAttributes::DIRECTORY);
+        self.grow_set(&mut set, cs, None)?;
+        set.set_valid_data_len(cs);
+        set.update_checksum();
+
+        let r = self.write_at(&vec![0u8; cs as usize], self.boot_sector().cluster_offs(set.first_cluster()))
+            .and_then(|_| dir.insert_set(&mut set));
+        if let Err(e) = r {
+            self.free_clusters(&set.stream(), 0)?;
+            return Err(e);
+        }
+
+        Dir::from_set(self, dir.stream(), set)
+    }
+
+    /// Remove the file at `path`, freeing it's clusters
+    pub fn remove_file(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let (dir, set) = self.locate(path)?;
+        if set.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        self.remove_set(&dir, &set)
+    }
+
+    /// Remove the directory at `path`, which must be empty
+    pub fn remove_dir(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let (dir, set) = self.locate(path)?;
+        if !Dir::from_set(self, dir.stream(), set.clone())?.is_empty()? {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        self.remove_set(&dir, &set)
+    }
+
+    fn remove_set(&self, dir: &Dir<'_, S>, set: &EntrySet) -> Result<()> {
+        for s in set.secondary_allocations().iter().chain(Some(&set.stream())) {
+            self.check_stream(s)?;
+        }
+        dir.remove_set(set)?;
+        for s in set.secondary_allocations() {
+            self.free_clusters(&s, 0)?;
+        }
+        self.free_clusters(&set.stream(), 0)
+    }
+
+    /// Rename (and possibly move) the file or directory at `from` to `to`.
+    ///
+    /// The entry at `to` must not already exist, unless it is the same entry as `from` (which
+    /// permits changing only the case of a name). Directories can not be moved into themselves.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.rename_entry(from, to, false)
+    }
+
+    /// Like `rename()`, but an entry already at `to` is replaced, as `rename(2)` does: a file may
+    /// replace a file, and a directory an empty directory.
+    pub fn rename_replace(&self, from: &str, to: &str) -> Result<()> {
+        self.rename_entry(from, to, true)
+    }
+
+    fn rename_entry(&self, from: &str, to: &str, replace: bool) -> Result<()> {
+        self.check_writable()?;
+        let (src_dir, set) = self.locate(from)?;
+        let (to_parent, _) = split_path(to)?;
+        let (mut dst_dir, name, name16) = self.new_entry_at(to)?;
+        let same_dir = src_dir.stream().first_cluster == dst_dir.stream().first_cluster;
+
+        let mut replaced = None;
+        if let Some(existing) = dst_dir.find(name)? {
+            if !same_dir || existing.index() != set.index() {
+                if !replace {
+                    return Err(already_exists());
+                }
+                match (set.is_dir(), existing.is_dir()) {
+                    (false, true) => return Err(Error::IsADirectory),
+                    (true, false) => return Err(Error::NotADirectory),
+                    (true, true) if !Dir::from_set(self, dst_dir.stream(), existing.clone())?.is_empty()? =>
+                        return Err(Error::DirectoryNotEmpty),
+                    _ => {},
+                }
+                replaced = Some(existing);
+            }
+        }
+
+        if set.is_dir() && !same_dir {
+            /* the destination must not be the directory being moved, or inside of it */
+            let mut p = String::new();
+            for c in to_parent.split('/').filter(|c| !c.is_empty()) {
+                p.push('/');
+                p.push_str(c);
+                if self.lookup(&p)?.first_cluster() == set.first_cluster() {
+                    return Err(Error::InvalidInput("can not move a directory into itself"));
+                }
+            }
+        }
+
+        let mut new = set.clone();
+        new.set_name(&name16, self.upcase_table().name_hash(&name16))?;
+        if same_dir && replaced.is_none() {
+            return dst_dir.replace_set(&set, &mut new);
+        }
+
+        /* add the new entries before removing the old ones, so a failure can't lose the file */
+        dst_dir.insert_set(&mut new)?;
+        if let Some(old) = replaced {
+            self.remove_set(&dst_dir, &old)?;
+        }
+        src_dir.remove_set(&set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error,Fs};
+    use ::testutil::Image;
+    use ::std::io::{Read,Write};
+
+    fn names(fs: &Fs<&mut Vec<u8>>, path: &str) -> Vec<String> {
+        let d = fs.open_dir(path).unwrap();
+        let mut v: Vec<String> = d.entry_sets().map(|s| s.unwrap()).filter(|s| s.is_file())
+            .map(|s| s.name()).collect();
+        v.sort();
+        v
+    }
+
+    fn read_all(fs: &Fs<&mut Vec<u8>>, path: &str) -> Vec<u8> {
+        let mut r = Vec::new();
+        fs.open(path).unwrap().read_to_end(&mut r).unwrap();
+        r
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        let mut img = Image::new();
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            let mut f = fs.create_file("hello.txt").unwrap();
+            f.write_all(b"hello world").unwrap();
+            assert!(matches!(fs.create_file("HELLO.TXT"), Err(Error::AlreadyExists)));
+            assert!(matches!(fs.create_file("a/b"), Err(Error::NotFound)));
+            assert!(matches!(fs.create_file("bad:name"), Err(Error::Name(_))));
+        }
+
+        let fs = Fs::from_ro(&img.data[..]).unwrap();
+        let mut r = Vec::new();
+        fs.open("/Hello.txt").unwrap().read_to_end(&mut r).unwrap();
+        assert_eq!(r, b"hello world");
+
+        let set = fs.lookup("hello.txt").unwrap();
+        assert_eq!(set.created().utc_offset(), Some(0));
+        assert!(set.modified().to_system_time().unwrap() >= set.created().to_system_time().unwrap());
+    }
+
+    #[test]
+    fn nested_dirs_and_growth() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.create_dir("d").unwrap();
+        fs.create_dir("d/e/").unwrap();
+
+        /* each entry set takes 3 entries, and a 512 byte cluster holds 16 entries */
+        let long = "a file name long enough to need a second name entry";
+        for i in 0..12 {
+            let mut f = fs.create_file(&format!("d/e/{} {}", long, i)).unwrap();
+            f.write_all(format!("{}", i).as_bytes()).unwrap();
+        }
+        for i in 0..40 {
+            fs.create_file(&format!("root{}", i)).unwrap();
+        }
+
+        let e = fs.open_dir("d/e").unwrap();
+        assert!(e.entry_set().unwrap().data_len() > 512);
+        assert_eq!(e.entry_set().unwrap().data_len(), e.entry_set().unwrap().valid_data_len());
+        assert_eq!(names(&fs, "d/e").len(), 12);
+        assert_eq!(names(&fs, "").len(), 41);
+        for i in 0..12 {
+            assert_eq!(read_all(&fs, &format!("d/E/{} {}", long, i)), format!("{}", i).as_bytes());
+        }
+    }
+
+    #[test]
+    fn remove_frees_clusters() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let free = fs.bitmap().free_count();
+        fs.create_dir("d").unwrap();
+        fs.create_file("d/f").unwrap().write_all(&[1; 2000]).unwrap();
+        assert_eq!(fs.bitmap().free_count(), free - 5);
+
+        assert!(matches!(fs.remove_dir("d"), Err(Error::DirectoryNotEmpty)));
+        assert!(matches!(fs.remove_file("d"), Err(Error::IsADirectory)));
+        fs.remove_file("d/f").unwrap();
+        assert!(fs.lookup("d/f").is_err());
+        fs.remove_dir("d").unwrap();
+        assert!(fs.lookup("d").is_err());
+        assert_eq!(fs.bitmap().free_count(), free);
+
+        /* the freed entries are reused */
+        fs.create_file("g").unwrap();
+        assert_eq!(fs.lookup("g").unwrap().index(), 2);
+    }
+
+    #[test]
+    fn corrupt_contiguous_streams() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        let last = img.cluster_count() + 1;
+        img.push_root(&Image::file_set("zero", 0x20, 0b11, 0, cs, cs));
+        img.push_root(&Image::file_set("past", 0x20, 0b11, last, 2 * cs, 2 * cs));
+        img.push_root(&Image::file_set("wraps", 0x20, 0b11, last, 1 << 45, 1 << 45));
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let free = fs.bitmap().free_count();
+        for name in &["zero", "past", "wraps"] {
+            assert!(matches!(fs.remove_file(name), Err(Error::Corrupt { .. })), "{}", name);
+            assert!(fs.lookup(name).is_ok());
+        }
+        let mut f = fs.open("past").unwrap();
+        assert!(matches!(f.set_len(0), Err(Error::Corrupt { .. })));
+        assert!(matches!(fs.open("zero").unwrap().set_len(3 * cs), Err(Error::Corrupt { .. })));
+        assert_eq!(fs.bitmap().free_count(), free);
+    }
+
+    #[test]
+    fn rename_within_and_across_dirs() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.create_dir("a").unwrap();
+        fs.create_dir("a/b").unwrap();
+        fs.create_file("a/f").unwrap().write_all(b"data").unwrap();
+
+        fs.rename("a/f", "a/F").unwrap();
+        assert_eq!(names(&fs, "a"), vec!["F", "b"]);
+        fs.rename("a/F", "a/a much longer name than before").unwrap();
+        assert_eq!(names(&fs, "a"), vec!["a much longer name than before", "b"]);
+        fs.rename("a/a much longer name than before", "/g").unwrap();
+        assert_eq!(names(&fs, "a"), vec!["b"]);
+        assert_eq!(read_all(&fs, "g"), b"data");
+
+        fs.create_file("a/b/x").unwrap();
+        assert!(matches!(fs.rename("g", "a/b/x"), Err(Error::AlreadyExists)));
+        assert!(matches!(fs.rename("a", "a/b/a"), Err(Error::InvalidInput(_))));
+        fs.rename("a/b", "b").unwrap();
+        assert_eq!(names(&fs, "b"), vec!["x"]);
+        assert_eq!(names(&fs, ""), vec!["a", "b", "g"]);
+    }
+
+    #[test]
+    fn rename_replace() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.create_dir("d").unwrap();
+        fs.create_dir("e").unwrap();
+        fs.create_file("d/x").unwrap();
+        fs.create_file("f").unwrap().write_all(b"f").unwrap();
+        fs.create_file("g").unwrap().write_all(&[1; 2000]).unwrap();
+        let free = fs.bitmap().free_count();
+
+        assert!(matches!(fs.rename_replace("f", "d"), Err(Error::IsADirectory)));
+        assert!(matches!(fs.rename_replace("d", "f"), Err(Error::NotADirectory)));
+        assert!(matches!(fs.rename_replace("e", "d"), Err(Error::DirectoryNotEmpty)));
+        assert_eq!(names(&fs, ""), vec!["d", "e", "f", "g"]);
+
+        /* the replaced file's clusters are freed */
+        fs.rename_replace("f", "G").unwrap();
+        assert_eq!(names(&fs, ""), vec!["G", "d", "e"]);
+        assert_eq!(read_all(&fs, "g"), b"f");
+        assert_eq!(fs.bitmap().free_count(), free + 4);
+
+        fs.rename_replace("G", "d/x").unwrap();
+        fs.rename_replace("d", "e").unwrap();
+        assert_eq!(names(&fs, ""), vec!["e"]);
+        assert_eq!(read_all(&fs, "e/x"), b"f");
+        assert!(fs.check().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,345 @@
+/*
+ * Finding the entry sets of files & directories whose parent directory was lost, and linking
+ * them back into the tree
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::collections::{BTreeMap,HashSet};
+use super::{DirEntry,EntrySet,Error,FatEntry,Fs,Result};
+use super::fs::Metadata;
+use super::repair::FOUND_DIR;
+use super::entry_set::validate_name;
+use super::undelete::entry_set_at;
+
+/// An entry set found in a lost cluster by `Fs::find_orphans()`
+#[derive(Clone,Debug)]
+pub struct Orphan {
+    set: EntrySet,
+    cluster: u32,
+    children: Vec<Orphan>,
+}
+
+impl Orphan {
+    pub fn name(&self) -> String {
+        self.set.name()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.set.is_dir()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.set.data_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::from_set(self.set.clone())
+    }
+
+    pub fn entry_set(&self) -> &EntrySet {
+        &self.set
+    }
+
+    /// The lost cluster the entry set was found in
+    pub fn cluster(&self) -> u32 {
+        self.cluster
+    }
+
+    /// For a directory, the entry sets found in it's clusters
+    pub fn children(&self) -> &[Orphan] {
+        &self.children
+    }
+}
+
+/// Lost directory clusters which no other orphan refers to, holding the top of an orphaned tree.
+/// `Fs::relink_orphans()` turns each into a directory in `/FOUND.000`.
+#[derive(Clone,Debug)]
+pub struct OrphanDir {
+    clusters: Vec<u32>,
+    entries: Vec<Orphan>,
+}
+
+impl OrphanDir {
+    /// The clusters the entry sets were found in, linked together by the FAT
+    pub fn clusters(&self) -> &[u32] {
+        &self.clusters
+    }
+
+    pub fn entries(&self) -> &[Orphan] {
+        &self.entries
+    }
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// The entries in `cluster`, then those in the cluster that most likely follows it in it's
+    /// directory (so that entry sets crossing into it can be read)
+    fn cluster_entries(&self, cluster: u32, lost: &[bool]) -> Result<Vec<DirEntry>> {
+        let bs = self.boot_sector();
+        let is_lost = |c: u32| (c as usize) < lost.len() && lost[c as usize];
+        let next = self.fat().entry(FatEntry::from_val(cluster)).val();
+        let next = if is_lost(next) { Some(next) } else if is_lost(cluster + 1) { Some(cluster + 1) } else { None };
+
+        let mut buf = vec![0u8; bs.bytes_per_cluster() as usize];
+        let mut entries = Vec::new();
+        for c in Some(cluster).into_iter().chain(next) {
+            self.read_exact_at(&mut buf, bs.cluster_offs(c))?;
+            entries.extend((0..(buf.len() / 32)).map(|i| DirEntry::from(*index_fixed!(&buf[(i * 32)..]; .. 32))));
+        }
+        Ok(entries)
+    }
+
+    /// Scan the lost clusters (allocated, but not used by anything) for entry sets describing
+    /// files & directories, which is what remains when the directory containing them is
+    /// destroyed. Only whole File entry sets with matching checksums are kept, and only if their
+    /// data isn't used by a file or directory that can still be reached.
+    ///
+    /// Orphaned directories are followed to rebuild as much of the tree as possible, and the
+    /// lost clusters left over (which no orphan refers to) are returned as the tops
+    /// of orphaned trees. Deleted files are not included, see `Fs::deleted()` for those.
+    pub fn find_orphans(&self) -> Result<Vec<OrphanDir>> {
+        let (used, lost) = {
+            let c = self.checker()?;
+            let lost = c.lost();
+            (c.used, lost)
+        };
+        let per_cluster = (self.boot_sector().bytes_per_cluster() / 32) as usize;
+
+        /* every entry set found, by the cluster it's in */
+        let mut found: BTreeMap<u32, Vec<EntrySet>> = BTreeMap::new();
+        for c in (0..lost.len() as u32).filter(|c| lost[*c as usize]) {
+            let entries = self.cluster_entries(c, &lost)?;
+            let mut i = 0;
+            while i < per_cluster {
+                let set = match entry_set_at(i as u32, &entries[i..], true) {
+                    Some(set) => set,
+                    None => {
+                        i += 1;
+                        continue;
+                    },
+                };
+                i += set.entries().len();
+                /* relinking allocates every cluster of the stream, so none may be in use */
+                let clusters = self.stream_clusters(&set.stream());
+                let free = |c: &u32| (*c as usize) < used.len() && used[*c as usize] == 0;
+                if set.data_len() == 0 || (!clusters.is_empty() && clusters.iter().all(free)) {
+                    found.entry(c).or_default().push(set);
+                }
+            }
+        }
+
+        /* the clusters that belong to an orphan. A file's data may hold what look like entry
+         * sets (a copy of a directory, or a disk image), which are part of the file. */
+        let owned: HashSet<u32> = found.values().flatten()
+            .flat_map(|set| self.stream_clusters(&set.stream()))
+            .collect();
+
+        /* the remaining clusters are the tops of trees, grouped by FAT chain */
+        let tops: Vec<u32> = found.keys().cloned().filter(|c| !owned.contains(c)).collect();
+        let is_top = |c: u32| found.contains_key(&c) && !owned.contains(&c);
+        let next = |c: u32| self.fat().entry(FatEntry::from_val(c)).val();
+        let followed: HashSet<u32> = tops.iter().map(|c| next(*c)).filter(|c| is_top(*c)).collect();
+
+        let mut visited = HashSet::new();
+        let mut dirs = Vec::new();
+        for start in tops.iter().filter(|c| !followed.contains(c)).chain(tops.iter()) {
+            let mut clusters = Vec::new();
+            let mut c = *start;
+            while is_top(c) && visited.insert(c) {
+                clusters.push(c);
+                c = next(c);
+            }
+            if clusters.is_empty() {
+                continue;
+            }
+
+            let mut seen = HashSet::new();
+            let entries = clusters.iter()
+                .flat_map(|c| found[c].iter().map(move |s| (*c, s)))
+                .map(|(c, s)| self.orphan_tree(c, s, &found, &mut seen))
+                .collect();
+            dirs.push(OrphanDir { clusters, entries });
+        }
+        Ok(dirs)
+    }
+
+    /// The orphan for `set` (found in `cluster`), with the entry sets found in it's clusters
+    /// beneath it. `seen` holds the directory clusters already used, so loops end.
+    fn orphan_tree(&self, cluster: u32, set: &EntrySet, found: &BTreeMap<u32, Vec<EntrySet>>,
+                   seen: &mut HashSet<u32>) -> Orphan {
+        let mut children = Vec::new();
+        if set.is_dir() {
+            for c in self.stream_clusters(&set.stream()) {
+                if !seen.insert(c) {
+                    continue;
+                }
+                for s in found.get(&c).map(|v| &v[..]).unwrap_or(&[]) {
+                    children.push(self.orphan_tree(c, s, found, seen));
+                }
+            }
+        }
+        Orphan { set: set.clone(), cluster, children }
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Link each of `dirs` (as found by `find_orphans()`) into the tree as a new directory in
+    /// `/FOUND.000`, returning the path of each.
+    ///
+    /// The entry sets at the top of each orphaned tree are copied into it's new directory
+    /// (renaming any that clash), which brings back everything below them. Their clusters, and
+    /// those of every orphan beneath them, are marked as allocated, and the lost clusters they
+    /// were found in are freed.
+    pub fn relink_orphans(&self, dirs: &[OrphanDir]) -> Result<Vec<String>> {
+        self.check_writable()?;
+        match self.lookup(FOUND_DIR) {
+            Err(Error::NotFound) => {
+                self.create_dir(FOUND_DIR)?;
+            },
+            Err(e) => return Err(e),
+            Ok(_) => {},
+        }
+
+        let mut paths = Vec::new();
+        let mut dir_num = 0;
+        for od in dirs {
+            let path = loop {
+                let p = format!("{}/DIR{:04}", FOUND_DIR, dir_num);
+                dir_num += 1;
+                match self.lookup(&p) {
+                    Err(Error::NotFound) => break p,
+                    Err(e) => return Err(e),
+                    Ok(_) => {},
+                }
+            };
+
+            let mut dir = self.create_dir(&path)?;
+            for o in &od.entries {
+                let mut set = o.set.clone();
+                validate_name(&set.name())?;
+                let mut n = 0;
+                while dir.find(&set.name())?.is_some() {
+                    n += 1;
+                    let name = validate_name(&format!("{}~{}", o.name(), n))?;
+                    set.set_name(&name, self.upcase_table().name_hash(&name))?;
+                }
+                dir.insert_set(&mut set)?;
+                self.allocate_tree(o)?;
+            }
+            for c in &od.clusters {
+                self.mark_cluster(*c, false)?;
+            }
+            paths.push(format!("/{}", path));
+        }
+        Ok(paths)
+    }
+
+    /// Mark the clusters of `o`, and of every orphan beneath it, as allocated
+    fn allocate_tree(&self, o: &Orphan) -> Result<()> {
+        for c in self.stream_clusters(&o.set.stream()) {
+            self.mark_cluster(c, true)?;
+        }
+        for child in &o.children {
+            self.allocate_tree(child)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::fs as xfs;
+    use ::testutil::Image;
+
+    #[test]
+    fn relink() {
+        let mut img = Image::new();
+        let top = {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            xfs::create_dir_all(&fs, "top/d/sub").unwrap();
+            xfs::write(&fs, "top/d/a", &[1; 1500]).unwrap();
+            xfs::write(&fs, "top/d/sub/b", b"b").unwrap();
+            xfs::write(&fs, "keep", b"keep").unwrap();
+            fs.lookup("top").unwrap().first_cluster()
+        };
+
+        /* destroy the directory holding "d" */
+        let o = img.cluster_offs(top);
+        let cs = img.cluster_size() as usize;
+        for b in &mut img.data[o..(o + cs)] {
+            *b = 0;
+        }
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let dirs = fs.find_orphans().unwrap();
+        assert_eq!(dirs.len(), 1);
+        /* the entry set of "d" is gone, but it's contents are found in it's cluster */
+        let names: Vec<String> = dirs[0].entries().iter().map(|o| o.name()).collect();
+        assert_eq!(names, vec!["sub", "a"]);
+        let sub = &dirs[0].entries()[0];
+        let names: Vec<String> = sub.children().iter().map(|o| o.name()).collect();
+        assert_eq!(names, vec!["b"]);
+
+        assert_eq!(fs.relink_orphans(&dirs).unwrap(), vec!["/FOUND.000/DIR0000"]);
+        assert_eq!(xfs::read(&fs, "FOUND.000/DIR0000/a").unwrap(), vec![1; 1500]);
+        assert_eq!(xfs::read(&fs, "FOUND.000/DIR0000/sub/b").unwrap(), b"b");
+        assert_eq!(xfs::read(&fs, "keep").unwrap(), b"keep");
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+        assert!(fs.find_orphans().unwrap().is_empty());
+    }
+
+    #[test]
+    fn clusters_in_use_not_taken() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        let keep = img.alloc(cs, false);
+        img.write_clusters(&keep, &[3; 512]);
+        img.push_root(&Image::file_set("keep", 0x20, 0b01, keep[0], 512, 512));
+
+        /* a lost cluster holding "x", whose chain continues into the cluster of "keep", and "y",
+         * which only uses a lost cluster */
+        let lost = img.alloc(cs, true)[0];
+        let data = img.alloc(cs, true)[0];
+        img.set_fat(data, keep[0]);
+        let mut sets = Image::file_set("x", 0x20, 0b01, data, 2 * cs, 2 * cs);
+        sets.extend(Image::file_set("y", 0x20, 0b01, data, cs, cs));
+        img.write_clusters(&[lost], &sets.concat());
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let dirs = fs.find_orphans().unwrap();
+        assert_eq!(dirs.len(), 1);
+        let names: Vec<String> = dirs[0].entries().iter().map(|o| o.name()).collect();
+        assert_eq!(names, vec!["y"]);
+    }
+
+    #[test]
+    fn entry_sets_in_file_data() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        let lost = img.alloc(cs, true)[0];
+        let data = img.alloc(cs, true)[0];
+
+        /* "disk" holds an image of a directory, with "inner" in it */
+        let mut contents = Image::file_set("inner", 0x20, 0b01, 0, 0, 0).concat();
+        contents.resize(cs as usize, 7);
+        img.write_clusters(&[data], &contents);
+        img.write_clusters(&[lost], &Image::file_set("disk", 0x20, 0b11, data, cs, cs).concat());
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let dirs = fs.find_orphans().unwrap();
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].clusters(), &[lost]);
+        let names: Vec<String> = dirs[0].entries().iter().map(|o| o.name()).collect();
+        assert_eq!(names, vec!["disk"]);
+
+        fs.relink_orphans(&dirs).unwrap();
+        assert!(fs.bitmap().is_allocated(data));
+        assert_eq!(xfs::read(&fs, "FOUND.000/DIR0000/disk").unwrap(), contents);
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+    }
+}
@@ -0,0 +1,386 @@
+/*!
+ * Finding exFAT volumes within a whole disk (for example, a dump of an SD card) by way of it's MBR
+ * or GPT partition table.
+ *
+ * Partition tables are read assuming 512 byte logical blocks, except that a GPT is also looked for
+ * with 4096 byte blocks.
+ */
+use ::io_at::{At,ReadAt,Take};
+use super::{BootSector,Error,Guid,Result};
+use super::io_util::read_exact_at;
+
+/// MBR partition type used for exFAT (shared with NTFS)
+pub const MBR_TYPE_EXFAT: u8 = 0x07;
+
+/// The GPT "Microsoft Basic Data" partition type, `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`
+pub const GPT_TYPE_BASIC_DATA: Guid = Guid([
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44,
+    0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+]);
+
+/// MBR partition types describing an extended partition, which holds a chain of logical ones
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// MBR partition type of the single partition in a protective MBR, which is followed by a GPT
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Limits on logical partitions and GPT entries, so that a corrupt table can't make us loop (or
+/// allocate) forever
+const MAX_LOGICAL: u32 = 128;
+const MAX_GPT_ENTRIES: u32 = 1024;
+const MAX_GPT_ENTRY_SIZE: usize = 4096;
+const MAX_GPT_ARRAY_LEN: usize = 1 << 20;
+
+/// A store limited to a single partition of a disk. Offset 0 is the start of the partition.
+pub type PartitionStore<S> = Take<At<S>>;
+
+/// The partition type, as recorded in whichever kind of table the partition came from
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+    /// Not from a partition table: the volume occupies the entire disk
+    WholeDisk,
+}
+
+/// A partition found in a partition table
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct Partition {
+    number: u32,
+    offs: u64,
+    len: u64,
+    ty: PartitionType,
+}
+
+impl Partition {
+    /// Partition number, counting from 1 in table order. MBR logical partitions are numbered from
+    /// 5, as Linux does. A whole disk volume is number 0.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Byte offset of the partition from the start of the disk
+    pub fn offs(&self) -> u64 {
+        self.offs
+    }
+
+    /// Length of the partition in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        self.ty
+    }
+
+    /// True if the partition's type is one which exFAT volumes are stored in
+    pub fn may_be_exfat(&self) -> bool {
+        match self.ty {
+            PartitionType::Mbr(t) => t == MBR_TYPE_EXFAT,
+            PartitionType::Gpt(g) => g == GPT_TYPE_BASIC_DATA,
+            PartitionType::WholeDisk => true,
+        }
+    }
+
+    /// True if the partition begins with a valid exFAT boot sector
+    pub fn probe<S: ReadAt>(&self, disk: &S) -> bool {
+        BootSector::read_at_from(disk, self.offs).is_ok()
+    }
+
+    /// Access just this partition of `disk`, suitable for `Fs::from_ro()` & `Fs::from_rw()`
+    pub fn store<S: ReadAt>(&self, disk: S) -> PartitionStore<S> {
+        Take::new(At::new(disk, self.offs), self.len)
+    }
+}
+
+fn read_block<S: ReadAt>(disk: &S, offs: u64, len: usize) -> Result<Vec<u8>> {
+    let mut v = vec![0u8; len];
+    read_exact_at(disk, &mut v, offs)?;
+    Ok(v)
+}
+
+/// Every partition in the disk's partition table, or an empty list if there is no table
+pub fn partitions<S: ReadAt>(disk: &S) -> Result<Vec<Partition>> {
+    let mbr = read_block(disk, 0, 512)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+
+    for &lba in &[512u64, 4096] {
+        let mut hdr = [0u8; 92];
+        if read_exact_at(disk, &mut hdr, lba).is_ok() && &hdr[..8] == b"EFI PART" {
+            return gpt_partitions(disk, lba, &hdr);
+        }
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|e| e.0 == MBR_TYPE_GPT_PROTECTIVE) {
+        return Err(Error::corrupt("protective MBR is not followed by a GPT header"));
+    }
+
+    let mut v = Vec::new();
+    let mut logical = 5;
+    for (i, &(ty, start, count)) in entries.iter().enumerate() {
+        if ty == 0 || count == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&ty) {
+            logical_partitions(disk, start, &mut logical, &mut v)?;
+        } else {
+            v.push(Partition { number: i as u32 + 1, offs: start * 512, len: count * 512,
+                               ty: PartitionType::Mbr(ty) });
+        }
+    }
+    Ok(v)
+}
+
+/// The (type, first LBA, sector count) of each of the 4 entries of an MBR or EBR
+fn mbr_entries(mbr: &[u8]) -> Vec<(u8, u64, u64)> {
+    mbr[446..510].chunks(16).map(|e| {
+        (e[4], read_num_bytes!(u32, 4, &e[8..]) as u64, read_num_bytes!(u32, 4, &e[12..]) as u64)
+    }).collect()
+}
+
+/// Follow the chain of EBRs that starts at sector `base`
+fn logical_partitions<S: ReadAt>(disk: &S, base: u64, number: &mut u32, v: &mut Vec<Partition>)
+    -> Result<()>
+{
+    let mut ebr_lba = base;
+    for _ in 0..MAX_LOGICAL {
+        let ebr = read_block(disk, ebr_lba * 512, 512)?;
+        if ebr[510..512] != [0x55, 0xAA] {
+            return Err(Error::corrupt("extended boot record has no signature"));
+        }
+
+        /* the first entry is relative to this EBR, the second (the next EBR) to the first EBR */
+        let e = mbr_entries(&ebr);
+        let (ty, start, count) = e[0];
+        if ty != 0 && count != 0 {
+            v.push(Partition { number: *number, offs: (ebr_lba + start) * 512, len: count * 512,
+                               ty: PartitionType::Mbr(ty) });
+            *number += 1;
+        }
+
+        let (next_ty, next, _) = e[1];
+        if next_ty == 0 || next == 0 {
+            return Ok(());
+        }
+        ebr_lba = base + next;
+    }
+    Err(Error::corrupt("too many logical partitions"))
+}
+
+fn gpt_partitions<S: ReadAt>(disk: &S, lba: u64, hdr: &[u8]) -> Result<Vec<Partition>> {
+    let entries_lba = read_num_bytes!(u64, 8, &hdr[72..]);
+    let count = read_num_bytes!(u32, 4, &hdr[80..]);
+    let size = read_num_bytes!(u32, 4, &hdr[84..]) as usize;
+    if count > MAX_GPT_ENTRIES || !(128..=MAX_GPT_ENTRY_SIZE).contains(&size) || !size.is_multiple_of(8)
+        || count as usize * size > MAX_GPT_ARRAY_LEN {
+        return Err(Error::corrupt("GPT header has an invalid partition entry array"));
+    }
+
+    let offs = entries_lba.checked_mul(lba)
+        .ok_or_else(|| Error::corrupt("GPT partition entry array is past the end of the disk"))?;
+    let raw = read_block(disk, offs, count as usize * size)?;
+    let mut v = Vec::new();
+    for (i, e) in raw.chunks(size).enumerate() {
+        let ty = Guid(*index_fixed!(&e; .. 16));
+        if ty.is_null() {
+            continue;
+        }
+        let first = read_num_bytes!(u64, 8, &e[32..]);
+        let last = read_num_bytes!(u64, 8, &e[40..]);
+        if last < first {
+            return Err(Error::corrupt("GPT partition ends before it starts"));
+        }
+        let too_large = || Error::corrupt("GPT partition is past the end of the disk");
+        let offs = first.checked_mul(lba).ok_or_else(too_large)?;
+        let len = (last - first).checked_add(1).and_then(|n| n.checked_mul(lba)).ok_or_else(too_large)?;
+        offs.checked_add(len).ok_or_else(too_large)?;
+        v.push(Partition { number: i as u32 + 1, offs, len, ty: PartitionType::Gpt(ty) });
+    }
+    Ok(v)
+}
+
+/// The exFAT volumes on `disk`: every partition with a suitable type that contains an exFAT boot
+/// sector. If the disk has no partition table but is itself an exFAT volume, it is returned as a
+/// single `WholeDisk` partition.
+pub fn find_exfat<S: ReadAt>(disk: &S) -> Result<Vec<Partition>> {
+    /* an exFAT boot sector also ends with 0x55AA, so it must be checked for before an MBR */
+    match BootSector::read_at_from(disk, 0) {
+        Ok(bs) => {
+            let len = bs.volume_len().checked_mul(bs.bytes_per_sector())
+                .ok_or_else(|| Error::corrupt("boot sector VolumeLength is too large"))?;
+            return Ok(vec![Partition { number: 0, offs: 0, len, ty: PartitionType::WholeDisk }]);
+        },
+        /* a damaged exFAT volume, rather than a partition table */
+        Err(e @ Error::Corrupt { .. }) if read_block(disk, 0, 512)?[3..11] == *b"EXFAT   " => return Err(e),
+        Err(_) => {},
+    }
+
+    Ok(partitions(disk)?.into_iter().filter(|p| p.may_be_exfat() && p.probe(disk)).collect())
+}
+
+/// The first exFAT volume on `disk` (see `find_exfat()`), ready to be opened as an `Fs`
+pub fn open_exfat<S: ReadAt>(disk: S) -> Result<PartitionStore<S>> {
+    match find_exfat(&disk)?.first() {
+        Some(p) => Ok(p.store(disk)),
+        None => Err(Error::InvalidInput("no exFAT volume was found on the disk")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Fs;
+    use ::testutil::{put,Image};
+
+    const PART_LBA: u64 = 2048;
+
+    /// A disk with `img` at `PART_LBA`, and `table` written over the start
+    fn disk(img: &Image, table: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut d = vec![0u8; (PART_LBA * 512) as usize];
+        d.extend_from_slice(&img.data);
+        for &(offs, b) in table {
+            d[offs as usize..(offs as usize + b.len())].copy_from_slice(b);
+        }
+        d
+    }
+
+    fn mbr_entry(ty: u8, start: u64, count: u64) -> [u8; 16] {
+        let mut e = [0u8; 16];
+        e[4] = ty;
+        put(&mut e, 8, start, 4);
+        put(&mut e, 12, count, 4);
+        e
+    }
+
+    fn check_fs(store: PartitionStore<&Vec<u8>>) {
+        let fs = Fs::from_ro(store).unwrap();
+        assert_eq!(::fs::read(&fs, "f").unwrap(), b"on a partition");
+    }
+
+    fn image() -> Image {
+        let mut img = Image::new();
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            ::fs::write(&fs, "f", b"on a partition").unwrap();
+        }
+        img
+    }
+
+    #[test]
+    fn mbr() {
+        let img = image();
+        let sectors = img.data.len() as u64 / 512;
+        let d = disk(&img, &[
+            (446, &mbr_entry(0x83, 1, 100)),
+            (446 + 16, &mbr_entry(0x05, 200, 2000)),
+            (446 + 32, &mbr_entry(MBR_TYPE_EXFAT, PART_LBA, sectors)),
+            (510, &[0x55, 0xAA]),
+            /* EBR at 200: a logical partition at 201, but no next EBR */
+            (200 * 512 + 446, &mbr_entry(MBR_TYPE_EXFAT, 1, 10)),
+            (200 * 512 + 510, &[0x55, 0xAA]),
+        ]);
+
+        let p = partitions(&d).unwrap();
+        assert_eq!(p.iter().map(|p| (p.number(), p.offs())).collect::<Vec<_>>(),
+                   vec![(1, 512), (5, 201 * 512), (3, PART_LBA * 512)]);
+        assert!(p[1].may_be_exfat() && !p[1].probe(&d));
+
+        let found = find_exfat(&d).unwrap();
+        assert_eq!(found, vec![p[2]]);
+        assert_eq!(found[0].len(), img.data.len() as u64);
+        check_fs(open_exfat(&d).unwrap());
+    }
+
+    #[test]
+    fn gpt() {
+        let img = image();
+        let mut hdr = [0u8; 92];
+        hdr[..8].copy_from_slice(b"EFI PART");
+        put(&mut hdr, 72, 2, 8);
+        put(&mut hdr, 80, 4, 4);
+        put(&mut hdr, 84, 128, 4);
+
+        let mut entries = vec![0u8; 4 * 128];
+        let last = PART_LBA + img.data.len() as u64 / 512 - 1;
+        entries[128..144].copy_from_slice(GPT_TYPE_BASIC_DATA.bytes());
+        put(&mut entries[128..], 32, PART_LBA, 8);
+        put(&mut entries[128..], 40, last, 8);
+        entries[256..272].copy_from_slice(&[1; 16]);
+        put(&mut entries[256..], 32, 40, 8);
+        put(&mut entries[256..], 40, 50, 8);
+
+        let d = disk(&img, &[
+            (446, &mbr_entry(MBR_TYPE_GPT_PROTECTIVE, 1, 0xFFFF_FFFF)),
+            (510, &[0x55, 0xAA]),
+            (512, &hdr),
+            (1024, &entries),
+        ]);
+
+        let p = partitions(&d).unwrap();
+        assert_eq!(p.len(), 2);
+        assert_eq!((p[0].number(), p[0].offs()), (2, PART_LBA * 512));
+        assert_eq!(p[1].partition_type(), PartitionType::Gpt(Guid([1; 16])));
+        assert_eq!(find_exfat(&d).unwrap(), vec![p[0]]);
+        check_fs(open_exfat(&d).unwrap());
+        assert_eq!(GPT_TYPE_BASIC_DATA.to_string(), "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+    }
+
+    #[test]
+    fn hostile_gpt() {
+        /* (header field offset, size, value) and (first, last) LBA of entry 1 */
+        let cases: &[(usize, usize, u64, u64, u64)] = &[
+            (84, 4, 0xFFFF_FFF8, 0, 0),         /* huge entries */
+            (84, 4, 4096, 0, 0),                /* 4MiB of entries */
+            (72, 8, u64::MAX, 0, 0),            /* entry array offset overflows */
+            (80, 4, 4, 1 << 60, 1 << 60),       /* partition offset overflows */
+            (80, 4, 4, 0, u64::MAX),            /* partition length overflows */
+            (80, 4, 4, u64::MAX - 1, u64::MAX), /* partition end overflows */
+        ];
+        let img = image();
+        for &(offs, size, v, first, last) in cases {
+            let mut hdr = [0u8; 92];
+            hdr[..8].copy_from_slice(b"EFI PART");
+            put(&mut hdr, 72, 2, 8);
+            put(&mut hdr, 80, 1024, 4);
+            put(&mut hdr, 84, 128, 4);
+            put(&mut hdr, offs, v, size);
+
+            let mut entries = vec![0u8; 128];
+            entries[..16].copy_from_slice(GPT_TYPE_BASIC_DATA.bytes());
+            put(&mut entries, 32, first, 8);
+            put(&mut entries, 40, last, 8);
+
+            let d = disk(&img, &[
+                (446, &mbr_entry(MBR_TYPE_GPT_PROTECTIVE, 1, 0xFFFF_FFFF)),
+                (510, &[0x55, 0xAA]),
+                (512, &hdr),
+                (1024, &entries),
+            ]);
+            match partitions(&d) {
+                Err(Error::Corrupt { .. }) => {},
+                r => panic!("{} = {}, {}..{}: {:?}", offs, v, first, last, r),
+            }
+        }
+    }
+
+    #[test]
+    fn whole_disk_and_none() {
+        let img = image();
+        let found = find_exfat(&img.data).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].partition_type(), PartitionType::WholeDisk);
+        assert_eq!(found[0].len(), img.data.len() as u64);
+        check_fs(open_exfat(&img.data).unwrap());
+
+        let blank = vec![0u8; 4096];
+        assert!(partitions(&blank).unwrap().is_empty());
+        assert!(matches!(open_exfat(&blank), Err(Error::InvalidInput(_))));
+    }
+}
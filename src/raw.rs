@@ -0,0 +1,16 @@
+/*!
+ * Little endian fields of on-disk structures, for tools which decode them directly (for example,
+ * to show a damaged entry set that the rest of the library would reject).
+ */
+
+pub fn u16_at(b: &[u8], offs: usize) -> u16 {
+    read_num_bytes!(u16, 2, &b[offs..])
+}
+
+pub fn u32_at(b: &[u8], offs: usize) -> u32 {
+    read_num_bytes!(u32, 4, &b[offs..])
+}
+
+pub fn u64_at(b: &[u8], offs: usize) -> u64 {
+    read_num_bytes!(u64, 8, &b[offs..])
+}
@@ -0,0 +1,556 @@
+/*
+ * Repairing problems found by the consistency checker
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::collections::HashSet;
+use ::std::fmt;
+use super::{BootRegion,Dir,DirEntry,EntrySet,Error,FatEntry,Fs,Location,Result,Stream};
+use super::check::{CheckReport,Finding,Problem,Severity};
+
+/// Directory that recovered cluster chains are saved in
+pub(crate) const FOUND_DIR: &str = "FOUND.000";
+
+bitflags! {
+    /// The fixes `Fs::repair()` may make
+    pub struct Fixes: u32 {
+        /// Mark clusters which are in use, but free in the allocation bitmap, as allocated
+        const ALLOCATE_USED = 1 << 0;
+        /// Free clusters which are allocated, but not used by anything
+        const FREE_LOST = 1 << 1;
+        /// Save chains of lost clusters as files in `/FOUND.000` instead of freeing them
+        const RECOVER_LOST = 1 << 2;
+        /// Shorten files & directories to the intact part of a short, broken, or looping cluster
+        /// chain
+        const TRUNCATE_CHAINS = 1 << 3;
+        /// Rewrite entry set checksums which don't match. Not part of `SAFE`, as a mismatch
+        /// usually means the entries themselves are damaged, and this makes them look valid.
+        const SET_CHECKSUMS = 1 << 4;
+        /// Copy the backup boot region over a main boot region with a bad checksum
+        const RESTORE_BOOT_REGION = 1 << 5;
+        /// Clear VolumeDirty, once every error found has been fixed
+        const CLEAR_VOLUME_DIRTY = 1 << 6;
+        /// Rebuild the allocation bitmap from the clusters in use
+        const REBUILD_BITMAP = Self::ALLOCATE_USED.bits | Self::FREE_LOST.bits;
+        /// The fixes which never discard data
+        const SAFE = Self::ALLOCATE_USED.bits | Self::RESTORE_BOOT_REGION.bits | Self::CLEAR_VOLUME_DIRTY.bits;
+    }
+}
+
+/// A fix made (or, in a dry run, that would be made) by `Fs::repair()`
+#[derive(Clone,Debug)]
+pub struct Repair {
+    fix: Fixes,
+    finding: Finding,
+    action: String,
+}
+
+impl Repair {
+    /// The fix that was applied
+    pub fn fix(&self) -> Fixes {
+        self.fix
+    }
+
+    /// The problem this repair addresses
+    pub fn finding(&self) -> &Finding {
+        &self.finding
+    }
+
+    /// What was done, for example "truncated to 4096 bytes"
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(p) = self.finding.path() {
+            write!(f, "{}: ", p)?;
+        }
+        write!(f, "{} ({})", self.action, self.finding.problem())
+    }
+}
+
+/// The result of `Fs::repair()`
+#[derive(Clone,Debug)]
+pub struct RepairReport {
+    check: CheckReport,
+    repairs: Vec<Repair>,
+    dry_run: bool,
+}
+
+impl RepairReport {
+    /// Everything found before repairing
+    pub fn check(&self) -> &CheckReport {
+        &self.check
+    }
+
+    pub fn repairs(&self) -> &[Repair] {
+        &self.repairs
+    }
+
+    /// True if nothing was written
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+/// Work in progress for `Fs::repair()`
+struct Repairer<'a, S: ReadAt + WriteAt + 'a> {
+    fs: &'a Fs<S>,
+    fixes: Fixes,
+    dry_run: bool,
+    repairs: Vec<Repair>,
+}
+
+impl<'a, S: ReadAt + WriteAt + 'a> Repairer<'a, S> {
+    /// Record a repair, returning true if it should actually be made
+    fn apply(&mut self, fix: Fixes, finding: &Finding, action: String) -> bool {
+        self.repairs.push(Repair { fix, finding: finding.clone(), action });
+        !self.dry_run
+    }
+
+    /// Read the entry set at `index` in the directory `dir`, without validating it
+    fn read_set(&self, dir: Stream, index: u32) -> Result<(Dir<'a, S>, EntrySet)> {
+        let d = Dir::from_stream(self.fs, dir);
+        let mut entries: Vec<DirEntry> = Vec::new();
+        for e in d.entries().skip(index as usize) {
+            let (_, e) = e?;
+            entries.push(e);
+            if entries.len() > entries[0].secondary_count() as usize {
+                break;
+            }
+        }
+        if entries.len() != entries.first().map(|p| p.secondary_count() as usize + 1).unwrap_or(0) {
+            return Err(Error::corrupt_at("entry set is missing secondary entries",
+                                         Location::Entry { dir: dir.first_cluster, index }));
+        }
+        Ok((d, EntrySet::from_raw(index, entries)))
+    }
+
+    fn clear_volume_dirty(&mut self, f: &Finding) -> Result<()> {
+        let fs = self.fs;
+        if self.apply(Fixes::CLEAR_VOLUME_DIRTY, f, "cleared VolumeDirty".to_owned()) {
+            /* read from disk, as the boot region may have just been restored */
+            let mut b = [0u8; 2];
+            fs.read_exact_at(&mut b, 106)?;
+            let flags = u16::from_le_bytes(b) & !(1 << 1);
+            fs.write_at(&flags.to_le_bytes(), 106)?;
+        }
+        Ok(())
+    }
+
+    fn set_checksum(&mut self, f: &Finding, dir: Stream, index: u32) -> Result<()> {
+        let (d, mut set) = self.read_set(dir, index)?;
+        set.update_checksum();
+        let action = format!("set checksum to {:#06x}", set.set_checksum());
+        if self.apply(Fixes::SET_CHECKSUMS, f, action) {
+            d.write_entries(index, set.entries())?;
+        }
+        Ok(())
+    }
+
+    /// Shorten the stream of the entry set at `index` to the clusters that can be reached before
+    /// it's cluster chain breaks
+    fn truncate(&mut self, f: &Finding, dir: Stream, index: u32) -> Result<()> {
+        let fs = self.fs;
+        let bs = fs.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let (d, mut set) = self.read_set(dir, index)?;
+        let stream = set.stream();
+        let want = stream.data_len.div_ceil(cs);
+
+        let mut n = 0;
+        let mut last = None;
+        if stream.no_fat_chain {
+            while n < want && bs.is_valid_cluster(stream.first_cluster.wrapping_add(n as u32)) {
+                n += 1;
+            }
+        } else {
+            let mut seen = HashSet::new();
+            let mut c = stream.first_cluster;
+            while n < want && bs.is_valid_cluster(c) && seen.insert(c) {
+                n += 1;
+                last = Some(c);
+                let next = fs.fat().entry(FatEntry::from_val(c));
+                if next.is_last() {
+                    break;
+                }
+                c = next.val();
+            }
+        }
+
+        let len = n * cs;
+        if !self.apply(Fixes::TRUNCATE_CHAINS, f, format!("truncated to {} bytes", len)) {
+            return Ok(());
+        }
+        if let Some(l) = last {
+            if !fs.fat().entry(FatEntry::from_val(l)).is_last() {
+                fs.set_fat(l, FatEntry::from_val(0xFFFF_FFFF))?;
+            }
+        }
+        if n == 0 {
+            set.set_first_cluster(0);
+            set.set_no_fat_chain(false);
+        }
+        set.set_data_len(len);
+        if set.valid_data_len() > len {
+            set.set_valid_data_len(len);
+        }
+        set.update_checksum();
+        d.write_entries(index, set.entries())
+    }
+
+    /// Mark the clusters of a `UsedButFree` or `AllocatedButUnused` run
+    fn mark_run(&mut self, fix: Fixes, f: &Finding, first: u32, count: u32, allocated: bool) -> Result<()> {
+        let action = format!("marked {} clusters {}", count, if allocated { "allocated" } else { "free" });
+        if self.apply(fix, f, action) {
+            for c in first..(first + count) {
+                self.fs.mark_cluster(c, allocated)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save every chain of lost clusters as a file in `FOUND_DIR`
+    fn recover_lost(&mut self, lost: &[bool], findings: &[Finding]) -> Result<()> {
+        let fs = self.fs;
+        let is_lost = |c: u32| (c as usize) < lost.len() && lost[c as usize];
+        let next = |c: u32| fs.fat().entry(FatEntry::from_val(c)).val();
+
+        /* start with the clusters no other lost cluster leads to, so whole chains are kept */
+        let referenced: HashSet<u32> = (0..lost.len() as u32)
+            .filter(|c| is_lost(*c) && is_lost(next(*c)))
+            .map(&next)
+            .collect();
+        let starts: Vec<u32> = (0..lost.len() as u32).filter(|c| is_lost(*c) && !referenced.contains(c))
+            .chain((0..lost.len() as u32).filter(|c| is_lost(*c)))
+            .collect();
+
+        /* the names already used, read once rather than looked up for every file, and compared
+         * up-cased by the volume's table like `Dir::find()` */
+        let upcase = fs.upcase_table();
+        let up = |name: Vec<u16>| -> Vec<u16> { name.into_iter().map(|c| upcase.upcase(c)).collect() };
+        let mut taken = HashSet::new();
+        match fs.open_dir(FOUND_DIR) {
+            Ok(d) => for set in d.entry_sets() {
+                match set {
+                    Ok(set) => {
+                        taken.insert(up(set.name_utf16()));
+                    },
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(_) => {},
+                }
+            },
+            Err(Error::NotFound) => {},
+            Err(e) => return Err(e),
+        }
+
+        let mut visited = vec![false; lost.len()];
+        let mut file_num = 0;
+        for start in starts {
+            /* a contiguous file's clusters have no FAT entries, so adjacent lost clusters without
+             * one are taken to be a single file */
+            let contiguous = is_lost(start) && next(start) == 0;
+            let mut chain = Vec::new();
+            let mut c = start;
+            while is_lost(c) && !visited[c as usize] && (next(c) == 0) == contiguous {
+                visited[c as usize] = true;
+                chain.push(c);
+                c = if contiguous { c + 1 } else { next(c) };
+            }
+            if chain.is_empty() {
+                continue;
+            }
+
+            /* every lost cluster is in a finding, but if one isn't there's nothing to report it with */
+            let finding = match findings.iter().find(|f| match *f.problem() {
+                Problem::AllocatedButUnused { first, count } => first <= start && start - first < count,
+                _ => false,
+            }) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            let name = loop {
+                let n = format!("FILE{:04}.CHK", file_num);
+                file_num += 1;
+                if taken.insert(up(n.encode_utf16().collect())) {
+                    break n;
+                }
+            };
+            let path = format!("{}/{}", FOUND_DIR, name);
+
+            let action = format!("saved {} clusters as /{}", chain.len(), path);
+            if self.apply(Fixes::RECOVER_LOST, finding, action) {
+                self.save_chain(&path, &chain, contiguous)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a file at `path` which uses the clusters of `chain`, which are adjacent & not in
+    /// the FAT if `contiguous`
+    fn save_chain(&self, path: &str, chain: &[u32], contiguous: bool) -> Result<()> {
+        let fs = self.fs;
+        if let Err(e) = ::fs::create_dir_all(fs, FOUND_DIR) {
+            return Err(Error::from(e));
+        }
+        fs.create_file(path)?;
+
+        let last = *chain.last().unwrap();
+        if !contiguous && !fs.fat().entry(FatEntry::from_val(last)).is_last() {
+            fs.set_fat(last, FatEntry::from_val(0xFFFF_FFFF))?;
+        }
+
+        let len = chain.len() as u64 * fs.boot_sector().bytes_per_cluster();
+        let (dir, mut set) = fs.locate(path)?;
+        set.set_first_cluster(chain[0]);
+        set.set_no_fat_chain(contiguous);
+        set.set_data_len(len);
+        set.set_valid_data_len(len);
+        set.update_checksum();
+        dir.write_set(&set)
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Check the volume (see `check()`), then fix the problems found that `fixes` selects. If
+    /// `dry_run` is true, nothing is written, but the repairs that would be made are still
+    /// reported.
+    ///
+    /// The boot region is restored first, and the boot sector read again. Bitmap fixes are made
+    /// next, so that creating files for recovered chains can't allocate clusters that are in use
+    /// (unless `ALLOCATE_USED` isn't selected).
+    pub fn repair(&mut self, fixes: Fixes, dry_run: bool) -> Result<RepairReport> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+
+        let (check, lost) = {
+            let c = self.checker()?;
+            let lost = c.lost();
+            (c.report, lost)
+        };
+
+        let findings = check.findings();
+        let mut repairs = Vec::new();
+        let backup_ok = !findings.iter().any(|f| {
+            matches!(*f.problem(), Problem::BootChecksum { backup: true } | Problem::BackupBootSector(_))
+        });
+        let restore = findings.iter().find(|f| *f.problem() == Problem::BootChecksum { backup: false });
+        if let Some(f) = restore.filter(|_| backup_ok && fixes.contains(Fixes::RESTORE_BOOT_REGION)) {
+            repairs.push(Repair {
+                fix: Fixes::RESTORE_BOOT_REGION,
+                finding: f.clone(),
+                action: "copied the backup boot region".to_owned(),
+            });
+            if !dry_run {
+                self.restore_boot_region()?;
+            }
+        }
+
+        let mut r = Repairer { fs: self, fixes, dry_run, repairs };
+        for f in findings {
+            let set = match (f.dir, f.location()) {
+                (Some(d), Some(Location::Entry { index, .. })) => Some((d, index)),
+                _ => None,
+            };
+            match (f.problem(), set) {
+                (&Problem::UsedButFree { first, count }, _) if fixes.contains(Fixes::ALLOCATE_USED) =>
+                    r.mark_run(Fixes::ALLOCATE_USED, f, first, count, true)?,
+                (&Problem::EntrySetChecksum { .. }, Some((d, i))) if fixes.contains(Fixes::SET_CHECKSUMS) =>
+                    r.set_checksum(f, d, i)?,
+                (&Problem::ChainTooShort, Some((d, i))) | (&Problem::ChainOutOfRange { .. }, Some((d, i)))
+                    | (&Problem::ChainLoop, Some((d, i))) if fixes.contains(Fixes::TRUNCATE_CHAINS) =>
+                    r.truncate(f, d, i)?,
+                _ => {},
+            }
+        }
+
+        if r.fixes.contains(Fixes::RECOVER_LOST) {
+            r.recover_lost(&lost, findings)?;
+        } else if r.fixes.contains(Fixes::FREE_LOST) {
+            for f in findings {
+                if let Problem::AllocatedButUnused { first, count } = *f.problem() {
+                    r.mark_run(Fixes::FREE_LOST, f, first, count, false)?;
+                }
+            }
+        }
+
+        /* only a volume left without errors is marked clean */
+        if r.fixes.contains(Fixes::CLEAR_VOLUME_DIRTY) {
+            let fixed = findings.iter()
+                .filter(|f| f.severity() == Severity::Error)
+                .all(|f| r.repairs.iter().any(|rep| rep.finding == *f));
+            if let Some(f) = findings.iter().find(|f| *f.problem() == Problem::VolumeDirty) {
+                if fixed {
+                    r.clear_volume_dirty(f)?;
+                }
+            }
+        }
+
+        Ok(RepairReport { check, repairs: r.repairs, dry_run })
+    }
+
+    /// Copy the backup boot region over the main one, and read it's boot sector again. The
+    /// backup's VolumeFlags aren't kept up to date, so the main one's are kept.
+    fn restore_boot_region(&mut self) -> Result<()> {
+        let len = self.boot_sector().bytes_per_sector() * 12;
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact_at(&mut buf, len)?;
+        self.read_exact_at(&mut buf[106..108], 106)?;
+        /* as with any write, PercentInUse becomes unknown */
+        buf[112] = 0xFF;
+        self.write_at(&buf, 0)?;
+        self.boot_regions[0] = BootRegion::read_at_from(&*self.store.borrow(), 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::{put,Image};
+
+    /// A volume with one problem for each kind of fix
+    fn damaged() -> Image {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+
+        let c = img.alloc(2 * cs, false);
+        img.push_root(&Image::file_set("short", 0x20, 0b01, c[0], 3 * cs, 3 * cs));
+        let mut d = Image::file_set("checksum", 0x20, 0, 0, 0, 0);
+        d[0][8] ^= 1;
+        img.push_root(&d);
+
+        /* a lost two cluster chain, and a file whose cluster is free in the bitmap */
+        let lost = img.alloc(2 * cs, false);
+        img.write_clusters(&lost, &[9; 1024]);
+        let free = img.alloc(cs, true)[0];
+        let o = img.cluster_offs(2) + (free as usize - 2) / 8;
+        img.data[o] &= !(1 << ((free - 2) % 8));
+        img.push_root(&Image::file_set("free", 0x20, 0b11, free, cs, cs));
+
+        /* a dirty volume, and a damaged main boot region whose backup has stale VolumeFlags */
+        put(&mut img.data, 106, 1 << 1, 2);
+        img.update_boot_checksum();
+        put(&mut img.data, 12 * 512 + 106, 0, 2);
+        img.data[512] = 1;
+        img
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let mut img = damaged();
+        let orig = img.data.clone();
+        let mut fs = Fs::from_rw(&mut img.data).unwrap();
+        let r = fs.repair(Fixes::all(), true).unwrap();
+        assert!(r.is_dry_run());
+        let fixes: Vec<Fixes> = r.repairs().iter().map(|r| r.fix()).collect();
+        assert_eq!(fixes, vec![Fixes::RESTORE_BOOT_REGION, Fixes::SET_CHECKSUMS, Fixes::TRUNCATE_CHAINS,
+                               Fixes::ALLOCATE_USED, Fixes::RECOVER_LOST, Fixes::CLEAR_VOLUME_DIRTY]);
+        assert_eq!(r.repairs()[2].action(), "truncated to 1024 bytes");
+        assert_eq!(r.repairs()[4].action(), "saved 2 clusters as /FOUND.000/FILE0000.CHK");
+        drop(fs);
+        assert!(img.data == orig);
+
+        /* only selected fixes are made */
+        let mut fs = Fs::from_ro(&mut img.data).unwrap();
+        let r = fs.repair(Fixes::SET_CHECKSUMS | Fixes::FREE_LOST, true).unwrap();
+        let fixes: Vec<Fixes> = r.repairs().iter().map(|r| r.fix()).collect();
+        /* the lost chain isn't contiguous, so it's two runs */
+        assert_eq!(fixes, vec![Fixes::SET_CHECKSUMS, Fixes::FREE_LOST, Fixes::FREE_LOST]);
+        assert!(matches!(fs.repair(Fixes::all(), false), Err(Error::ReadOnly)));
+    }
+
+    #[test]
+    fn safe_leaves_errors_dirty() {
+        let mut img = damaged();
+        {
+            /* damaged entry sets are left for the user to decide about, so the volume stays dirty */
+            let mut fs = Fs::from_rw(&mut img.data).unwrap();
+            let r = fs.repair(Fixes::SAFE, false).unwrap();
+            let fixes: Vec<Fixes> = r.repairs().iter().map(|r| r.fix()).collect();
+            assert_eq!(fixes, vec![Fixes::RESTORE_BOOT_REGION, Fixes::ALLOCATE_USED]);
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        assert_ne!(fs.boot_sector().volume_flags() & (1 << 1), 0);
+        /* allocating after the boot region was restored still marks PercentInUse as unknown */
+        assert_eq!(fs.boot_sector().percent_in_use(), 0xFF);
+        let r = fs.check().unwrap();
+        assert!(r.findings().iter().any(|f| *f.problem() == Problem::VolumeDirty));
+        assert!(r.findings().iter().any(|f| *f.problem() == Problem::ChainTooShort));
+    }
+
+    #[test]
+    fn restore_keeps_flags() {
+        let mut img = damaged();
+        {
+            let mut fs = Fs::from_rw(&mut img.data).unwrap();
+            let r = fs.repair(Fixes::RESTORE_BOOT_REGION, false).unwrap();
+            assert_eq!(r.repairs().len(), 1);
+            assert_ne!(fs.boot_sector().volume_flags() & (1 << 1), 0);
+            assert_eq!(fs.boot_sector().percent_in_use(), 0xFF);
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        assert_ne!(fs.boot_sector().volume_flags() & (1 << 1), 0);
+        let r = fs.check().unwrap();
+        assert!(r.findings().iter().all(|f| !matches!(*f.problem(), Problem::BootChecksum { .. })));
+    }
+
+    #[test]
+    fn repair_all() {
+        let mut img = damaged();
+        {
+            let mut fs = Fs::from_rw(&mut img.data).unwrap();
+            assert_eq!(fs.repair(Fixes::all(), false).unwrap().repairs().len(), 6);
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+        assert_eq!(fs.boot_sector().volume_flags() & (1 << 1), 0);
+        assert_eq!(::fs::read(&fs, "FOUND.000/FILE0000.CHK").unwrap(), vec![9; 1024]);
+        assert_eq!(::fs::metadata(&fs, "short").unwrap().len(), 1024);
+        assert!(fs.lookup("checksum").is_ok());
+    }
+
+    #[test]
+    fn recover_contiguous() {
+        let mut img = Image::new();
+        let cs = img.cluster_size() as usize;
+        let lost = img.alloc(3 * cs as u64, true);
+        let data: Vec<u8> = (0..(3 * cs)).map(|i| i as u8).collect();
+        img.write_clusters(&lost, &data);
+
+        let mut fs = Fs::from_rw(&mut img.data).unwrap();
+        ::fs::create_dir_all(&fs, FOUND_DIR).unwrap();
+        ::fs::write(&fs, "FOUND.000/file0000.chk", b"taken").unwrap();
+        let r = fs.repair(Fixes::RECOVER_LOST, false).unwrap();
+        let actions: Vec<&str> = r.repairs().iter().map(|r| r.action()).collect();
+        assert_eq!(actions, vec!["saved 3 clusters as /FOUND.000/FILE0001.CHK"]);
+
+        assert_eq!(::fs::read(&fs, "FOUND.000/FILE0001.CHK").unwrap(), data);
+        assert!(fs.lookup("FOUND.000/FILE0001.CHK").unwrap().no_fat_chain());
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+    }
+
+    #[test]
+    fn free_lost() {
+        let mut img = damaged();
+        let free_before = {
+            let mut fs = Fs::from_rw(&mut img.data).unwrap();
+            let n = fs.bitmap().free_count();
+            fs.repair(Fixes::REBUILD_BITMAP, false).unwrap();
+            n
+        };
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        assert_eq!(fs.bitmap().free_count(), free_before + 1);
+        assert!(fs.check().unwrap().findings().iter()
+                .all(|f| !matches!(*f.problem(), Problem::UsedButFree { .. } | Problem::AllocatedButUnused { .. })));
+    }
+}
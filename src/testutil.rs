@@ -0,0 +1,274 @@
+/*
+ * Construction of small exFAT images for tests. This intentionally avoids the library's own
+ * code paths (other than checksums) so that tests don't simply agree with themselves.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::cell::Cell;
+use ::std::fs;
+use ::std::io;
+use ::std::path::PathBuf;
+use ::std::process;
+use super::{checksum32,entry_type,DirEntry,UpcaseTable};
+use super::entry_set::set_checksum;
+
+const BYTES_PER_SECTOR_SHIFT: u8 = 9;
+const SECTORS_PER_CLUSTER_SHIFT: u8 = 0;
+const VOLUME_SECTORS: u64 = 2048;
+const FAT_OFFS: u64 = 24;
+const FAT_LEN: u64 = 16;
+const HEAP_OFFS: u64 = 64;
+const ROOT_CLUSTERS: u32 = 4;
+
+pub fn put(buf: &mut [u8], offs: usize, v: u64, size: usize) {
+    for i in 0..size {
+        buf[offs + i] = (v >> (i * 8)) as u8;
+    }
+}
+
+pub struct Image {
+    pub data: Vec<u8>,
+    next_free: u32,
+    root_next: u32,
+    root: Vec<u32>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        let mut img = Image {
+            data: vec![0u8; (VOLUME_SECTORS << BYTES_PER_SECTOR_SHIFT) as usize],
+            next_free: 2,
+            root_next: 0,
+            root: Vec::new(),
+        };
+
+        let cc = img.cluster_count();
+        {
+            let bs = &mut img.data[..512];
+            bs[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+            bs[3..11].copy_from_slice(b"EXFAT   ");
+            put(bs, 72, VOLUME_SECTORS, 8);
+            put(bs, 80, FAT_OFFS, 4);
+            put(bs, 84, FAT_LEN, 4);
+            put(bs, 88, HEAP_OFFS, 4);
+            put(bs, 92, cc as u64, 4);
+            put(bs, 100, 0x1234_5678, 4);
+            put(bs, 104, 0x0100, 2);
+            bs[108] = BYTES_PER_SECTOR_SHIFT;
+            bs[109] = SECTORS_PER_CLUSTER_SHIFT;
+            bs[110] = 1;
+            bs[111] = 0x80;
+            bs[510] = 0x55;
+            bs[511] = 0xAA;
+        }
+
+        img.set_fat(0, 0xFFFF_FFF8);
+        img.set_fat(1, 0xFFFF_FFFF);
+
+        let bitmap_len = (cc as u64).div_ceil(8);
+        let bitmap = img.alloc(bitmap_len, true);
+        img.chain(&bitmap);
+
+        let upcase = UpcaseTable::generate().to_raw();
+        let upcase_first = img.alloc(upcase.len() as u64, true);
+        img.write_clusters(&upcase_first, &upcase);
+        img.chain(&upcase_first);
+
+        let root = img.alloc(ROOT_CLUSTERS as u64 * img.cluster_size(), false);
+        img.root = root.clone();
+        put(&mut img.data, 96, root[0] as u64, 4);
+
+        let mut e = [0u8;32];
+        e[0] = entry_type::ALLOCATION_BITMAP;
+        put(&mut e, 20, bitmap[0] as u64, 4);
+        put(&mut e, 24, bitmap_len, 8);
+        img.push_root(&[e]);
+
+        let mut e = [0u8;32];
+        e[0] = entry_type::UPCASE_TABLE;
+        put(&mut e, 4, checksum32(0, &upcase) as u64, 4);
+        put(&mut e, 20, upcase_first[0] as u64, 4);
+        put(&mut e, 24, upcase.len() as u64, 8);
+        img.push_root(&[e]);
+
+        img.update_boot_checksum();
+        img
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        ((VOLUME_SECTORS - HEAP_OFFS) >> SECTORS_PER_CLUSTER_SHIFT) as u32
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << (BYTES_PER_SECTOR_SHIFT + SECTORS_PER_CLUSTER_SHIFT)
+    }
+
+    pub fn cluster_offs(&self, c: u32) -> usize {
+        ((HEAP_OFFS << BYTES_PER_SECTOR_SHIFT) + (c as u64 - 2) * self.cluster_size()) as usize
+    }
+
+    pub fn set_fat(&mut self, c: u32, v: u32) {
+        let o = ((FAT_OFFS << BYTES_PER_SECTOR_SHIFT) + c as u64 * 4) as usize;
+        put(&mut self.data, o, v as u64, 4);
+    }
+
+    /// Recompute the main boot region checksum and copy it to the backup boot region
+    pub fn update_boot_checksum(&mut self) {
+        let mut sum = 0;
+        for (i, b) in self.data[..(11 * 512)].iter().enumerate() {
+            if i == 106 || i == 107 || i == 112 {
+                continue;
+            }
+            sum = checksum32(sum, &[*b]);
+        }
+        for i in 0..128 {
+            put(&mut self.data, 11 * 512 + i * 4, sum as u64, 4);
+        }
+        let (main, rest) = self.data.split_at_mut(12 * 512);
+        rest[..(12 * 512)].copy_from_slice(main);
+    }
+
+    /// Allocate enough clusters for `len` bytes, marking them in the allocation bitmap (which is
+    /// always in cluster 2). Non-contiguous allocations leave a gap between each cluster.
+    pub fn alloc(&mut self, len: u64, contiguous: bool) -> Vec<u32> {
+        let n = len.div_ceil(self.cluster_size());
+        let step = if contiguous { 1 } else { 2 };
+        let v: Vec<u32> = (0..n as u32).map(|i| self.next_free + i * step).collect();
+        self.next_free += n as u32 * step;
+        for c in &v {
+            let o = self.cluster_offs(2) + (*c as usize - 2) / 8;
+            self.data[o] |= 1 << ((*c - 2) % 8);
+        }
+        if !contiguous {
+            self.chain(&v);
+        }
+        v
+    }
+
+    fn chain(&mut self, clusters: &[u32]) {
+        for w in clusters.windows(2) {
+            self.set_fat(w[0], w[1]);
+        }
+        if let Some(l) = clusters.last() {
+            self.set_fat(*l, 0xFFFF_FFFF);
+        }
+    }
+
+    pub fn write_clusters(&mut self, clusters: &[u32], data: &[u8]) {
+        let cs = self.cluster_size() as usize;
+        for (c, d) in clusters.iter().zip(data.chunks(cs)) {
+            let o = self.cluster_offs(*c);
+            self.data[o..(o + d.len())].copy_from_slice(d);
+        }
+    }
+
+    /// Append raw entries to the root directory
+    pub fn push_root(&mut self, entries: &[[u8;32]]) {
+        let per_cluster = (self.cluster_size() / 32) as u32;
+        for e in entries {
+            let i = self.root_next;
+            let o = self.cluster_offs(self.root[(i / per_cluster) as usize]) + (i % per_cluster) as usize * 32;
+            self.data[o..(o + 32)].copy_from_slice(e);
+            self.root_next += 1;
+        }
+    }
+
+    /// Build a File entry set with the given name and stream fields, with a valid checksum
+    pub fn file_set(name: &str, attr: u16, flags: u8, first: u32, len: u64, valid_len: u64) -> Vec<[u8;32]> {
+        let name: Vec<u16> = name.encode_utf16().collect();
+        let mut f = [0u8;32];
+        f[0] = entry_type::FILE;
+        put(&mut f, 4, attr as u64, 2);
+
+        let mut s = [0u8;32];
+        s[0] = entry_type::STREAM_EXTENSION;
+        s[1] = flags;
+        s[3] = name.len() as u8;
+        put(&mut s, 4, UpcaseTable::generate().name_hash(&name) as u64, 2);
+        put(&mut s, 8, valid_len, 8);
+        put(&mut s, 20, first as u64, 4);
+        put(&mut s, 24, len, 8);
+
+        let mut set = vec![f, s];
+        for chunk in name.chunks(15) {
+            let mut n = [0u8;32];
+            n[0] = entry_type::FILE_NAME;
+            for (i, c) in chunk.iter().enumerate() {
+                put(&mut n, 2 + i * 2, *c as u64, 2);
+            }
+            set.push(n);
+        }
+
+        Image::seal(&mut set);
+        set
+    }
+
+    /// Fix up the secondary count & checksum of a raw entry set
+    pub fn seal(set: &mut [[u8;32]]) {
+        set[0][1] = (set.len() - 1) as u8;
+        let entries: Vec<DirEntry> = set.iter().map(|e| DirEntry::from(*e)).collect();
+        let sum = set_checksum(&entries);
+        put(&mut set[0], 2, sum as u64, 2);
+    }
+
+    /// Add a file to the root directory, with either a contiguous or a (non-contiguous) FAT
+    /// chained allocation
+    pub fn add_file(&mut self, name: &str, data: &[u8], contiguous: bool, valid_len: u64) {
+        let clusters = self.alloc(data.len() as u64, contiguous);
+        self.write_clusters(&clusters, data);
+        let flags = if contiguous { 0b11 } else { 0b01 };
+        let set = Image::file_set(name, 0x20, flags, clusters.first().cloned().unwrap_or(0),
+                                  data.len() as u64, valid_len);
+        self.push_root(&set);
+    }
+}
+
+/// A fresh, empty directory on the host, unique to `name` and this process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let d = ::std::env::temp_dir().join(format!("exfat-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&d);
+    fs::create_dir_all(&d).unwrap();
+    d
+}
+
+/// A store which transfers at most one byte per call, and fails every third call with
+/// `Interrupted`
+pub struct Trickle<S> {
+    inner: S,
+    calls: Cell<usize>,
+}
+
+impl<S> Trickle<S> {
+    pub fn new(inner: S) -> Self {
+        Trickle { inner, calls: Cell::new(0) }
+    }
+
+    /// Number of read & write calls made so far
+    pub fn calls(&self) -> usize {
+        self.calls.get()
+    }
+
+    fn interrupt(&self) -> io::Result<()> {
+        self.calls.set(self.calls.get() + 1);
+        if self.calls.get().is_multiple_of(3) {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: ReadAt> ReadAt for Trickle<S> {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        self.interrupt()?;
+        let n = buf.len().min(1);
+        self.inner.read_at(&mut buf[..n], offs)
+    }
+}
+
+impl<S: WriteAt> WriteAt for Trickle<S> {
+    fn write_at(&mut self, buf: &[u8], offs: u64) -> io::Result<usize> {
+        self.interrupt()?;
+        let n = buf.len().min(1);
+        self.inner.write_at(&buf[..n], offs)
+    }
+}
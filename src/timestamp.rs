@@ -0,0 +1,274 @@
+use ::io_at::{ReadAt,WriteAt};
+use super::{Error,Fs,Result};
+use ::std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+/// Days between 1970-01-01 and the given (proleptic Gregorian) date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// (year, month, day) for a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A timestamp as stored in a File directory entry.
+///
+/// The date & time are stored in the DOS format (with a 2 second resolution, for the years 1980
+/// to 2107), along with an optional count of 10 millisecond increments (0 to 199) and an optional
+/// offset from UTC in 15 minute units. The date & time are local to that offset.
+///
+/// The LastAccessed timestamp has no 10 millisecond increment field, so it is always zero there.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq,Hash)]
+pub struct Timestamp {
+    dos: u32,
+    increment_10ms: u8,
+    utc_offset: u8,
+}
+
+impl Timestamp {
+    /// Construct from the on-disk fields: the 32-bit DOS timestamp, the 10ms increment, and the
+    /// UTC offset byte.
+    pub fn from_raw(dos: u32, increment_10ms: u8, utc_offset: u8) -> Self {
+        Timestamp { dos, increment_10ms, utc_offset }
+    }
+
+    /// The 32-bit DOS timestamp
+    ///
+    /// 0-4 = seconds / 2, 5-10 = minute, 11-15 = hour, 16-20 = day, 21-24 = month,
+    /// 25-31 = years since 1980
+    pub fn dos(&self) -> u32 {
+        self.dos
+    }
+
+    /// Additional time in 10 millisecond units, 0 to 199
+    pub fn increment_10ms(&self) -> u8 {
+        self.increment_10ms
+    }
+
+    /// The raw UTC offset byte
+    ///
+    /// 0-6 = signed offset in 15 minute units, 7 = offset valid
+    pub fn utc_offset_raw(&self) -> u8 {
+        self.utc_offset
+    }
+
+    /// Offset from UTC of the local date & time in minutes, if one was recorded
+    pub fn utc_offset(&self) -> Option<i16> {
+        if self.utc_offset & 0x80 == 0 {
+            return None;
+        }
+        /* sign extend the 7 bit value */
+        Some((((self.utc_offset << 1) as i8) >> 1) as i16 * 15)
+    }
+
+    pub fn year(&self) -> u16 {
+        1980 + (self.dos >> 25) as u16
+    }
+
+    pub fn month(&self) -> u8 {
+        ((self.dos >> 21) & 0xf) as u8
+    }
+
+    pub fn day(&self) -> u8 {
+        ((self.dos >> 16) & 0x1f) as u8
+    }
+
+    pub fn hour(&self) -> u8 {
+        ((self.dos >> 11) & 0x1f) as u8
+    }
+
+    pub fn minute(&self) -> u8 {
+        ((self.dos >> 5) & 0x3f) as u8
+    }
+
+    /// Seconds, including those from the 10ms increment (so may be up to 60)
+    pub fn second(&self) -> u8 {
+        ((self.dos & 0x1f) * 2) as u8 + self.increment_10ms / 100
+    }
+
+    /// Milliseconds past `second()`
+    pub fn millisecond(&self) -> u16 {
+        (self.increment_10ms % 100) as u16 * 10
+    }
+
+    /// Convert to a `SystemTime`, using the UTC offset when it is valid. Timestamps without a
+    /// valid offset are interpreted as UTC.
+    ///
+    /// `None` if any of the fields are out of range (a zero timestamp, for example, has month 0).
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        let (year, month, day) = (self.year() as i64, self.month() as u32, self.day() as u32);
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month)
+            || self.hour() > 23 || self.minute() > 59 || self.dos & 0x1f > 29
+            || self.increment_10ms > 199 {
+            return None;
+        }
+
+        let secs = days_from_civil(year, month, day) * 86400
+            + self.hour() as i64 * 3600
+            + self.minute() as i64 * 60
+            + self.second() as i64
+            - self.utc_offset().unwrap_or(0) as i64 * 60;
+        let t = UNIX_EPOCH + Duration::from_secs(secs as u64);
+        Some(t + Duration::from_millis(self.millisecond() as u64))
+    }
+
+    /// Encode `t`, recording the local time at `utc_offset` minutes from UTC (which must be a
+    /// multiple of 15 between -16 and +15.75 hours), or UTC without a recorded offset if `None`.
+    ///
+    /// Precision below 10ms is truncated. Times which can't be represented are rejected.
+    pub fn from_system_time(t: SystemTime, utc_offset: Option<i16>) -> Result<Self> {
+        let invalid = Error::InvalidInput;
+        let (offset, raw_offset) = match utc_offset {
+            None => (0, 0),
+            Some(o) if o % 15 == 0 && (-64..64).contains(&(o / 15)) => {
+                (o as i64, 0x80 | ((o / 15) as u8 & 0x7f))
+            },
+            Some(_) => return Err(invalid("UTC offset is not a multiple of 15 minutes within range")),
+        };
+
+        /* times before 1970 can't be measured from the epoch, and are just as out of range */
+        let out_of_range = || invalid("time is outside of the years 1980 to 2107");
+        let d = t.duration_since(UNIX_EPOCH).map_err(|_| out_of_range())?;
+        let secs = d.as_secs() as i64 + offset * 60;
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        if !(1980..=2107).contains(&year) {
+            return Err(out_of_range());
+        }
+
+        let in_day = secs.rem_euclid(86400) as u32;
+        let dos = (((year - 1980) as u32) << 25)
+            | (month << 21)
+            | (day << 16)
+            | ((in_day / 3600) << 11)
+            | ((in_day / 60 % 60) << 5)
+            | ((in_day % 60) / 2);
+        let increment_10ms = ((in_day % 2) * 100 + d.subsec_millis() / 10) as u8;
+        Ok(Timestamp { dos, increment_10ms, utc_offset: raw_offset })
+    }
+
+    /// The current time, in UTC with a recorded offset of zero
+    pub fn now() -> Self {
+        Timestamp::from_system_time(SystemTime::now(), Some(0)).unwrap_or_default()
+    }
+
+    /// Drop the 10 millisecond increment, as is required for LastAccessed timestamps
+    pub(crate) fn without_increment(self) -> Self {
+        Timestamp { increment_10ms: 0, .. self }
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Change the timestamps of the file or directory at `path`. Those given as `None` are left
+    /// as they are.
+    pub fn set_times(&self, path: &str, created: Option<Timestamp>, modified: Option<Timestamp>,
+                     accessed: Option<Timestamp>) -> Result<()> {
+        self.check_writable()?;
+        let (dir, mut set) = self.locate(path)?;
+        if let Some(t) = created {
+            set.set_created(t);
+        }
+        if let Some(t) = modified {
+            set.set_modified(t);
+        }
+        if let Some(t) = accessed {
+            set.set_accessed(t);
+        }
+        set.update_checksum();
+        dir.write_set(&set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+
+    #[test]
+    fn decode_with_offset() {
+        /* 2019-07-14 13:37:43.250 at UTC+02:00 */
+        let dos = (39 << 25) | (7 << 21) | (14 << 16) | (13 << 11) | (37 << 5) | 21;
+        let ts = Timestamp::from_raw(dos, 125, 0x80 | 8);
+        assert_eq!((ts.year(), ts.month(), ts.day()), (2019, 7, 14));
+        assert_eq!((ts.hour(), ts.minute(), ts.second(), ts.millisecond()), (13, 37, 43, 250));
+        assert_eq!(ts.utc_offset(), Some(120));
+        let t = UNIX_EPOCH + Duration::from_millis(1_563_104_263_250);
+        assert_eq!(ts.to_system_time(), Some(t));
+
+        /* without the valid bit the offset is ignored */
+        let ts = Timestamp::from_raw(dos, 125, 8);
+        assert_eq!(ts.utc_offset(), None);
+        assert_eq!(ts.to_system_time(), Some(t + Duration::from_secs(7200)));
+
+        let west = Timestamp::from_raw(dos, 125, 0x80 | (-20i8 as u8 & 0x7f));
+        assert_eq!(west.utc_offset(), Some(-300));
+
+        assert_eq!(Timestamp::default().to_system_time(), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        for &ms in &[315_619_200_000u64, 951_868_799_990, 1_563_104_263_250, 4_354_732_799_990] {
+            let t = UNIX_EPOCH + Duration::from_millis(ms);
+            for &o in &[None, Some(0), Some(-600), Some(345), Some(-960), Some(945)] {
+                let ts = Timestamp::from_system_time(t, o).unwrap();
+                assert_eq!(ts.utc_offset(), o);
+                assert_eq!(ts.to_system_time(), Some(t), "{} {:?}", ms, o);
+                let raw = Timestamp::from_raw(ts.dos(), ts.increment_10ms(), ts.utc_offset_raw());
+                assert_eq!(raw, ts);
+            }
+        }
+
+        let t = UNIX_EPOCH + Duration::from_millis(1_563_104_263_257);
+        let ts = Timestamp::from_system_time(t, Some(0)).unwrap();
+        assert_eq!(ts.to_system_time(), Some(t - Duration::from_millis(7)));
+
+        assert!(Timestamp::from_system_time(UNIX_EPOCH, None).is_err());
+        assert!(matches!(Timestamp::from_system_time(UNIX_EPOCH - Duration::from_secs(1), None),
+                         Err(Error::InvalidInput("time is outside of the years 1980 to 2107"))));
+        assert!(Timestamp::from_system_time(t, Some(10)).is_err());
+        assert!(Timestamp::from_system_time(t, Some(16 * 60)).is_err());
+    }
+
+    #[test]
+    fn set_times() {
+        let mut img = Image::new();
+        img.add_file("f", b"abc", false, 3);
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let old = fs.lookup("f").unwrap();
+        let t = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_millis(1_563_104_263_250), Some(120))
+            .unwrap();
+
+        fs.set_times("f", None, Some(t), Some(t)).unwrap();
+        let set = fs.lookup("f").unwrap();
+        assert_eq!(set.created(), old.created());
+        assert_eq!(set.modified(), t);
+        assert_eq!(set.accessed(), t.without_increment());
+        assert_eq!(set.set_checksum(), set.compute_checksum());
+        assert!(fs.set_times("missing", Some(t), None, None).is_err());
+    }
+}
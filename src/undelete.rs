@@ -0,0 +1,367 @@
+/*
+ * Recovering deleted files & directories
+ *
+ * Removing a file clears the InUse bit of each entry in it's entry set, and frees it's clusters
+ * in the allocation bitmap. Everything else (the name, size, first cluster, and the FAT entries
+ * of a chained file) is left alone until it is reused.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use ::std::collections::HashSet;
+use ::std::io::Write;
+use super::{entry_type,Dir,DirEntry,EntrySet,Error,Fs,Result,Stream};
+use super::entry_set::validate_name;
+use super::fs::Metadata;
+use super::ops::split_path;
+
+/// How much of a deleted file's data `Fs::deleted()` expects to be intact
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum Confidence {
+    /// The clusters are known exactly (the file was contiguous, or empty), and none have been
+    /// reused
+    Certain,
+    /// The clusters were found by following the FAT, and none have been reused. Deleting a file
+    /// doesn't change the FAT, but a file created & deleted since may have.
+    Likely,
+    /// Some of the clusters have been reused, or the chain of clusters is incomplete, so at least
+    /// part of the data has been lost
+    Damaged,
+}
+
+/// A deleted file or directory found by `Fs::deleted()`
+#[derive(Clone,Debug)]
+pub struct DeletedFile {
+    path: String,
+    /// The directory containing the entry set
+    dir: Stream,
+    /// The entry set, with it's InUse bits set again
+    set: EntrySet,
+    clusters: Vec<u32>,
+    confidence: Confidence,
+}
+
+impl DeletedFile {
+    /// The path the file had. Several deleted files may have had the same path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn name(&self) -> String {
+        self.set.name()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.set.is_dir()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.set.data_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::from_set(self.set.clone())
+    }
+
+    /// The entry set, as it was before being deleted
+    pub fn entry_set(&self) -> &EntrySet {
+        &self.set
+    }
+
+    /// The clusters that held the data, as far as they could be found
+    pub fn clusters(&self) -> &[u32] {
+        &self.clusters
+    }
+
+    pub fn confidence(&self) -> Confidence {
+        self.confidence
+    }
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// Find the deleted files & directories in every directory, including deleted directories
+    /// whose clusters haven't been reused. Directories which can't be read are skipped.
+    ///
+    /// Only entry sets which are whole, with a matching checksum once their InUse bits are set,
+    /// are returned. Directories are returned before their contents.
+    pub fn deleted(&self) -> Result<Vec<DeletedFile>> {
+        let mut found = Vec::new();
+        let root = self.root_dir()?.stream();
+        let mut pending = vec![("".to_owned(), root)];
+        let mut visited = HashSet::new();
+        while let Some((path, dir)) = pending.pop() {
+            if !visited.insert(dir.first_cluster) {
+                continue;
+            }
+
+            let d = Dir::from_stream(self, dir);
+            let mut entries = Vec::new();
+            for e in d.entries() {
+                match e {
+                    Ok((_, e)) => entries.push(e),
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(_) => break,
+                }
+            }
+
+            let mut subdirs = Vec::new();
+            for set in d.entry_sets() {
+                match set {
+                    Ok(ref set) if set.is_dir() => subdirs.push((format!("{}/{}", path, set.name()), set.stream())),
+                    Ok(_) => {},
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(_) => {},
+                }
+            }
+
+            let mut i = 0;
+            while i < entries.len() {
+                let set = match entry_set_at(i as u32, &entries[i..], false) {
+                    Some(set) => set,
+                    None => {
+                        i += 1;
+                        continue;
+                    },
+                };
+                i += set.entries().len();
+
+                let (clusters, confidence) = self.find_clusters(&set.stream());
+                let p = format!("{}/{}", path, set.name());
+                if set.is_dir() && confidence != Confidence::Damaged {
+                    subdirs.push((p.clone(), set.stream()));
+                }
+                found.push(DeletedFile { path: p, dir, set, clusters, confidence });
+            }
+
+            /* reversed, so they're visited in order */
+            pending.extend(subdirs.into_iter().rev());
+        }
+        Ok(found)
+    }
+
+    /// The clusters of `stream`, as far as they can be followed through the FAT (or, for a
+    /// contiguous stream, until they run off the end of the heap)
+    pub(crate) fn stream_clusters(&self, stream: &Stream) -> Vec<u32> {
+        let bs = self.boot_sector();
+        let n = stream.data_len.div_ceil(bs.bytes_per_cluster());
+        if stream.no_fat_chain {
+            (0..n)
+                .map(|i| stream.first_cluster as u64 + i)
+                .take_while(|c| *c <= u32::MAX as u64 && bs.is_valid_cluster(*c as u32))
+                .map(|c| c as u32)
+                .collect()
+        } else {
+            self.fat().chain(stream.first_cluster)
+                .take(n as usize)
+                .take_while(|c| c.is_ok())
+                .filter_map(|c| c.ok().map(|c| c.val()))
+                .collect()
+        }
+    }
+
+    /// The clusters of a deleted `stream`, and how sure we are of them
+    fn find_clusters(&self, stream: &Stream) -> (Vec<u32>, Confidence) {
+        let n = stream.data_len.div_ceil(self.boot_sector().bytes_per_cluster());
+        if n == 0 {
+            return (Vec::new(), Confidence::Certain);
+        }
+
+        let clusters = self.stream_clusters(stream);
+        let bitmap = self.bitmap();
+        if clusters.len() as u64 != n || clusters.iter().any(|c| bitmap.is_allocated(*c)) {
+            (clusters, Confidence::Damaged)
+        } else if stream.no_fat_chain {
+            (clusters, Confidence::Certain)
+        } else {
+            (clusters, Confidence::Likely)
+        }
+    }
+
+    /// Write the recovered data of `f` to `out`, returning the number of bytes written. As much
+    /// of a `Damaged` file as can be found is written, though reused clusters hold other data.
+    pub fn read_deleted<W: Write>(&self, f: &DeletedFile, out: &mut W) -> Result<u64> {
+        if f.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+
+        let bs = self.boot_sector();
+        let cs = bs.bytes_per_cluster();
+        let valid = f.set.valid_data_len().min(f.len());
+        let mut buf = vec![0u8; cs as usize];
+        let mut written = 0;
+        for c in &f.clusters {
+            if written >= valid {
+                break;
+            }
+            let n = (valid - written).min(cs) as usize;
+            self.read_exact_at(&mut buf[..n], bs.cluster_offs(*c))?;
+            out.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+
+        /* beyond the valid data length reads as zeros, as it would have before */
+        if written == valid {
+            let zeros = vec![0u8; cs as usize];
+            while written < f.len() {
+                let n = (f.len() - written).min(cs) as usize;
+                out.write_all(&zeros[..n])?;
+                written += n as u64;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Restore `f` in place: mark it's clusters as allocated, and it's entry set as in use.
+    ///
+    /// This fails if `f` is `Damaged`, if it's entries or clusters have been reused since it was
+    /// found, if the directory containing it is itself deleted (restore that first), or if it's
+    /// name has been taken. A restored directory is empty, as deleting a directory first deletes
+    /// everything in it.
+    pub fn undelete(&self, f: &DeletedFile) -> Result<()> {
+        self.check_writable()?;
+        if f.confidence == Confidence::Damaged {
+            return Err(Error::InvalidInput("the file's data has been lost, and can only be extracted"));
+        }
+
+        let (parent, _) = split_path(&f.path)?;
+        let parent_deleted = Error::InvalidInput("the directory containing the file is deleted");
+        let dir = match self.open_dir(parent) {
+            Err(Error::NotFound) => return Err(parent_deleted),
+            v => v?,
+        };
+        if dir.stream().first_cluster != f.dir.first_cluster {
+            return Err(parent_deleted);
+        }
+
+        /* the entries must still be unused & unchanged */
+        let n = f.set.entries().len();
+        let now: Vec<DirEntry> = dir.entries().skip(f.set.index() as usize).take(n)
+            .map(|e| e.map(|(_, e)| e))
+            .collect::<Result<_>>()?;
+        let was = f.set.entries().iter().map(|e| {
+            let mut e = *e;
+            e.raw_mut()[0] &= !(1 << 7);
+            e
+        });
+        if now.len() != n || !now.iter().cloned().eq(was) {
+            return Err(Error::InvalidInput("the file's directory entries have been reused"));
+        }
+
+        if dir.find(&f.name())?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        if f.clusters.iter().any(|c| self.bitmap().is_allocated(*c)) {
+            return Err(Error::InvalidInput("the file's clusters have been reused"));
+        }
+
+        for c in &f.clusters {
+            self.mark_cluster(*c, true)?;
+        }
+        dir.write_set(&f.set)
+    }
+}
+
+/// The File entry set at the start of `entries` (the first of which is at index `index`), if
+/// it is whole, it's checksum matches, every entry in it has the InUse bit set to `in_use`, and
+/// it's name is one `validate_name()` accepts (so it can't be "..", or contain a "/").
+/// The entries of a deleted set are returned with InUse set, as they were before the removal.
+pub(crate) fn entry_set_at(index: u32, entries: &[DirEntry], in_use: bool) -> Option<EntrySet> {
+    let p = entries[0];
+    if p.entry_type() | (1 << 7) != entry_type::FILE {
+        return None;
+    }
+
+    let n = p.secondary_count() as usize;
+    if n < 2 || entries.len() < n + 1 {
+        return None;
+    }
+    let mut set = Vec::with_capacity(n + 1);
+    for (i, e) in entries[..(n + 1)].iter().enumerate() {
+        let k = e.kind();
+        if k.in_use() != in_use || e.entry_type() == entry_type::END_OF_DIRECTORY || k.is_primary() != (i == 0) {
+            return None;
+        }
+        let mut e = *e;
+        e.raw_mut()[0] |= 1 << 7;
+        set.push(e);
+    }
+
+    let set = EntrySet::from_entries(index, set).ok()?;
+    validate_name(&set.name()).ok()?;
+    Some(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::fs as xfs;
+    use ::testutil::Image;
+
+    #[test]
+    fn undelete() {
+        let mut img = Image::new();
+        let cs = img.cluster_size() as usize;
+        let data: Vec<u8> = (0..(3 * cs + 100)).map(|i| i as u8).collect();
+        img.add_file("chained", &data, false, data.len() as u64);
+        img.add_file("contig", &data[..1000], true, 1000);
+        {
+            let fs = Fs::from_rw(&mut img.data).unwrap();
+            xfs::create_dir_all(&fs, "d").unwrap();
+            xfs::write(&fs, "d/inner", b"inner").unwrap();
+            xfs::write(&fs, "keep", b"keep").unwrap();
+            fs.remove_file("chained").unwrap();
+            fs.remove_file("contig").unwrap();
+            xfs::remove_dir_all(&fs, "d").unwrap();
+        }
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let found = fs.deleted().unwrap();
+        let summary: Vec<(&str, Confidence)> = found.iter().map(|f| (f.path(), f.confidence())).collect();
+        assert_eq!(summary, vec![("/chained", Confidence::Likely), ("/contig", Confidence::Certain),
+                                 ("/d", Confidence::Certain), ("/d/inner", Confidence::Certain)]);
+
+        let mut out = Vec::new();
+        assert_eq!(fs.read_deleted(&found[0], &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+
+        /* the contents of a deleted directory need it restored first */
+        assert!(fs.undelete(&found[3]).is_err());
+        for f in &found {
+            fs.undelete(f).unwrap();
+        }
+        let r = fs.check().unwrap();
+        assert!(r.is_clean(), "{:?}", r.findings());
+        assert_eq!(xfs::read(&fs, "chained").unwrap(), data);
+        assert_eq!(xfs::read(&fs, "contig").unwrap(), &data[..1000]);
+        assert_eq!(xfs::read(&fs, "d/inner").unwrap(), b"inner");
+        assert!(fs.deleted().unwrap().is_empty());
+
+        /* a reused cluster */
+        fs.remove_file("contig").unwrap();
+        fs.mark_cluster(found[1].clusters()[0], true).unwrap();
+        let found = fs.deleted().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].confidence(), Confidence::Damaged);
+        assert!(fs.undelete(&found[0]).is_err());
+    }
+
+    #[test]
+    fn unusable_names() {
+        let mut img = Image::new();
+        for name in &["..", "a/b", "ok"] {
+            let mut set = Image::file_set(name, 0x20, 0b01, 0, 0, 0);
+            for e in &mut set {
+                e[0] &= !(1 << 7);
+            }
+            img.push_root(&set);
+        }
+
+        let fs = Fs::from_ro(&img.data).unwrap();
+        let found: Vec<String> = fs.deleted().unwrap().iter().map(|f| f.path().to_owned()).collect();
+        assert_eq!(found, vec!["/ok"]);
+    }
+}
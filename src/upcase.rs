@@ -0,0 +1,141 @@
+/**
+ * The up-case table maps each UTF-16 code unit to its upper case form. File names are compared,
+ * and name hashes computed, using the up-cased form of each name.
+ *
+ * On disk, the table is an array of little-endian u16 values, where index `i` holds the up-cased
+ * form of `i`. Runs of identity mappings may be compressed as `0xFFFF` followed by the run
+ * length. Code units past the end of the table map to themselves.
+ */
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct UpcaseTable {
+    map: Vec<u16>,
+}
+
+impl UpcaseTable {
+    /// The largest a table can be on disk: a mapping for every code unit, uncompressed
+    pub const MAX_RAW_LEN: u64 = 0x10000 * 2;
+
+    /// A table which maps every code unit to itself
+    pub fn identity() -> Self {
+        UpcaseTable { map: Vec::new() }
+    }
+
+    /// Build a table from the upper case mappings the rust standard library knows about.
+    ///
+    /// Only simple (one code unit to one code unit) mappings within the basic multilingual plane
+    /// can be represented.
+    pub fn generate() -> Self {
+        let map = (0..0x10000u32).map(|i| {
+            let c = match ::std::char::from_u32(i) {
+                Some(c) => c,
+                /* surrogates */
+                None => return i as u16,
+            };
+
+            let mut u = c.to_uppercase();
+            match (u.next(), u.next()) {
+                (Some(u), None) if (u as u32) < 0x10000 => u as u32 as u16,
+                _ => i as u16,
+            }
+        }).collect();
+
+        UpcaseTable { map }
+    }
+
+    /// Decode a table as it is stored on disk (compressed or not)
+    pub fn from_raw(raw: &[u8]) -> Self {
+        let mut map = Vec::with_capacity(0x10000);
+        let mut vals = raw.chunks(2).filter(|c| c.len() == 2).map(|c| read_num_bytes!(u16, 2, c));
+        while let Some(v) = vals.next() {
+            if map.len() >= 0x10000 {
+                break;
+            }
+
+            if v == 0xFFFF {
+                if let Some(n) = vals.next() {
+                    for _ in 0..n {
+                        let i = map.len();
+                        map.push(i as u16);
+                    }
+                    continue;
+                }
+            }
+
+            map.push(v);
+        }
+
+        map.truncate(0x10000);
+        UpcaseTable { map }
+    }
+
+    /// Encode the table in the compressed on-disk form
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < self.map.len() {
+            let mut j = i;
+            while j < self.map.len() && j - i < 0xFFFF && self.map[j] == j as u16 {
+                j += 1;
+            }
+
+            /* a literal 0xFFFF would be mistaken for the start of a run */
+            if j - i > 2 || (j > i && self.map[i] == 0xFFFF) {
+                out.extend_from_slice(&[0xFF, 0xFF, (j - i) as u8, ((j - i) >> 8) as u8]);
+                i = j;
+            } else {
+                let v = self.map[i];
+                out.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    /// The up-cased form of `c`
+    pub fn upcase(&self, c: u16) -> u16 {
+        self.map.get(c as usize).cloned().unwrap_or(c)
+    }
+
+    /// Hash of a file name, as stored in the Stream Extension directory entry
+    pub fn name_hash(&self, name: &[u16]) -> u16 {
+        let mut h = 0u16;
+        for c in name {
+            let u = self.upcase(*c);
+            for b in &[u as u8, (u >> 8) as u8] {
+                h = h.rotate_right(1).wrapping_add(*b as u16);
+            }
+        }
+        h
+    }
+
+    /// True if `a` and `b` are the same name once up-cased
+    pub fn names_eq(&self, a: &[u16], b: &[u16]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| self.upcase(*x) == self.upcase(*y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_round_trip() {
+        let t = UpcaseTable::generate();
+        assert_eq!(t.upcase('a' as u16), 'A' as u16);
+        assert_eq!(t.upcase('A' as u16), 'A' as u16);
+        assert_eq!(t.upcase(0xE9), 0xC9);
+
+        let raw = t.to_raw();
+        assert!(raw.len() < 0x10000 * 2);
+        assert_eq!(UpcaseTable::from_raw(&raw), t);
+    }
+
+    #[test]
+    fn hash_ignores_case() {
+        let t = UpcaseTable::generate();
+        let a: Vec<u16> = "Footage.MP4".encode_utf16().collect();
+        let b: Vec<u16> = "FOOTAGE.mp4".encode_utf16().collect();
+        assert!(t.names_eq(&a, &b));
+        assert_eq!(t.name_hash(&a), t.name_hash(&b));
+    }
+}
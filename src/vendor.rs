@@ -0,0 +1,308 @@
+/*
+ * Vendor Extension & Vendor Allocation secondary entries, which let vendors attach their own data
+ * (identified by a GUID) to a File entry set.
+ */
+use ::io_at::{ReadAt,WriteAt};
+use super::{entry_type,DirEntry,EntrySet,Fs,Guid,Result,Stream};
+
+/// A Vendor Extension entry: 14 bytes of vendor defined data stored directly in the entry set
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct VendorExtension {
+    /// Identifies the vendor (and the meaning of `data`)
+    ///
+    /// Vendor Extension entry offset: 2, size: 16
+    pub guid: Guid,
+    /// Vendor Extension entry offset: 18, size: 14
+    pub data: [u8;14],
+}
+
+impl VendorExtension {
+    fn from_entry(e: &DirEntry) -> Self {
+        VendorExtension {
+            guid: Guid(*index_fixed!(&e.raw()[2..]; .. 16)),
+            data: *index_fixed!(&e.raw()[18..]; .. 14),
+        }
+    }
+
+    fn to_entry(self) -> DirEntry {
+        let mut e = [0u8;32];
+        e[0] = entry_type::VENDOR_EXTENSION;
+        e[2..18].copy_from_slice(self.guid.bytes());
+        e[18..].copy_from_slice(&self.data);
+        DirEntry::from(e)
+    }
+}
+
+/// A Vendor Allocation entry: vendor defined data stored in clusters, which may be read with
+/// `Fs::read_vendor_allocation()`
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct VendorAllocation {
+    /// Vendor Allocation entry offset: 2, size: 16
+    pub guid: Guid,
+    /// Vendor Allocation entry offset: 18, size: 2
+    pub vendor_defined: u16,
+    stream: Stream,
+}
+
+impl VendorAllocation {
+    fn from_entry(e: &DirEntry) -> Self {
+        VendorAllocation {
+            guid: Guid(*index_fixed!(&e.raw()[2..]; .. 16)),
+            vendor_defined: read_num_bytes!(u16, 2, &e.raw()[18..]),
+            stream: Stream {
+                first_cluster: e.first_cluster(),
+                no_fat_chain: e.raw()[1] & (1 << 1) != 0,
+                data_len: e.data_len(),
+                valid_data_len: e.data_len(),
+            },
+        }
+    }
+
+    fn to_entry(self) -> DirEntry {
+        let mut e = [0u8;32];
+        e[0] = entry_type::VENDOR_ALLOCATION;
+        /* AllocationPossible, and NoFatChain if the clusters are contiguous */
+        e[1] = if self.stream.no_fat_chain { 0b11 } else { 0b01 };
+        e[2..18].copy_from_slice(self.guid.bytes());
+        write_num_bytes!(u16, 2, &mut e[18..], self.vendor_defined);
+        write_num_bytes!(u32, 4, &mut e[20..], self.stream.first_cluster);
+        write_num_bytes!(u64, 8, &mut e[24..], self.stream.data_len);
+        DirEntry::from(e)
+    }
+
+    /// Length of the allocation's data in bytes
+    ///
+    /// Vendor Allocation entry offset: 24, size: 8
+    pub fn len(&self) -> u64 {
+        self.stream.data_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Vendor Allocation entry offset: 20, size: 4
+    pub fn first_cluster(&self) -> u32 {
+        self.stream.first_cluster
+    }
+}
+
+impl EntrySet {
+    /// Position within the set of the first secondary entry of type `ty` with the given GUID
+    fn find_vendor_entry(&self, ty: u8, guid: &Guid) -> Option<usize> {
+        self.entries().iter().position(|e| e.entry_type() == ty && &e.raw()[2..18] == guid.bytes())
+    }
+
+    /// Every Vendor Extension entry in the set
+    pub fn vendor_extensions(&self) -> Vec<VendorExtension> {
+        self.entries()[1..].iter()
+            .filter(|e| e.entry_type() == entry_type::VENDOR_EXTENSION)
+            .map(VendorExtension::from_entry)
+            .collect()
+    }
+
+    /// Every Vendor Allocation entry in the set
+    pub fn vendor_allocations(&self) -> Vec<VendorAllocation> {
+        self.entries()[1..].iter()
+            .filter(|e| e.entry_type() == entry_type::VENDOR_ALLOCATION)
+            .map(VendorAllocation::from_entry)
+            .collect()
+    }
+
+    /// Add `e`, replacing the first existing entry of the same type and GUID
+    fn set_vendor_entry(&mut self, e: DirEntry) -> Result<()> {
+        let guid = Guid(*index_fixed!(&e.raw()[2..]; .. 16));
+        match self.find_vendor_entry(e.entry_type(), &guid) {
+            Some(i) => {
+                self.replace_secondary(i, e);
+                Ok(())
+            },
+            None => self.push_secondary(e),
+        }
+    }
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// Read the data of the Vendor Allocation entry with the given GUID in the entry set at `path`.
+    /// A DataLength longer than the allocation's cluster chain is `Error::Corrupt`.
+    pub fn read_vendor_allocation(&self, path: &str, guid: &Guid) -> Result<Option<Vec<u8>>> {
+        let set = self.lookup(path)?;
+        match set.find_vendor_entry(entry_type::VENDOR_ALLOCATION, guid) {
+            Some(i) => {
+                let a = VendorAllocation::from_entry(&set.entries()[i]);
+                self.read_stream(&a.stream).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S: ReadAt + WriteAt> Fs<S> {
+    /// Apply `f` to the File entry set at `path` and write the result back, moving the set if it
+    /// no longer fits in place
+    fn update_set<F, T>(&self, path: &str, f: F) -> Result<T>
+        where F: FnOnce(&mut EntrySet) -> Result<T>
+    {
+        self.check_writable()?;
+        let (mut dir, set) = self.locate(path)?;
+        let mut new = set.clone();
+        let r = f(&mut new)?;
+        if new != set {
+            dir.replace_set(&set, &mut new)?;
+        }
+        Ok(r)
+    }
+
+    /// Add a Vendor Extension entry to the entry set at `path`, replacing any with the same GUID
+    pub fn set_vendor_extension(&self, path: &str, ext: &VendorExtension) -> Result<()> {
+        self.update_set(path, |set| set.set_vendor_entry(ext.to_entry()))
+    }
+
+    /// Remove the Vendor Extension entry with the given GUID, returning it if there was one
+    pub fn remove_vendor_extension(&self, path: &str, guid: &Guid) -> Result<Option<VendorExtension>> {
+        self.update_set(path, |set| {
+            Ok(set.find_vendor_entry(entry_type::VENDOR_EXTENSION, guid)
+                .map(|i| VendorExtension::from_entry(&set.remove_secondary(i))))
+        })
+    }
+
+    /// Store `data` in a Vendor Allocation entry in the entry set at `path`, replacing (and freeing
+    /// the clusters of) any with the same GUID
+    pub fn set_vendor_allocation(&self, path: &str, guid: &Guid, vendor_defined: u16, data: &[u8])
+        -> Result<()>
+    {
+        self.check_writable()?;
+        let stream = self.alloc_stream(data.len() as u64)?;
+        let a = VendorAllocation { guid: *guid, vendor_defined, stream };
+        let r = self.write_stream(&stream, data).and_then(|_| {
+            self.update_set(path, |set| {
+                let old = set.find_vendor_entry(entry_type::VENDOR_ALLOCATION, guid)
+                    .map(|i| VendorAllocation::from_entry(&set.entries()[i]));
+                if let Some(ref old) = old {
+                    self.check_stream(&old.stream)?;
+                }
+                set.set_vendor_entry(a.to_entry())?;
+                Ok(old)
+            })
+        });
+
+        match r {
+            Ok(Some(old)) => self.free_clusters(&old.stream, 0),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                self.free_clusters(&stream, 0)?;
+                Err(e)
+            },
+        }
+    }
+
+    /// Remove the Vendor Allocation entry with the given GUID and free it's clusters. Returns true
+    /// if there was one.
+    pub fn remove_vendor_allocation(&self, path: &str, guid: &Guid) -> Result<bool> {
+        let old = self.update_set(path, |set| {
+            let i = match set.find_vendor_entry(entry_type::VENDOR_ALLOCATION, guid) {
+                Some(i) => i,
+                None => return Ok(None),
+            };
+            self.check_stream(&VendorAllocation::from_entry(&set.entries()[i]).stream)?;
+            Ok(Some(VendorAllocation::from_entry(&set.remove_secondary(i))))
+        })?;
+        match old {
+            Some(a) => self.free_clusters(&a.stream, 0).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+    use ::std::io::Write;
+
+    fn ext(n: u8) -> VendorExtension {
+        VendorExtension { guid: Guid([n; 16]), data: [n + 1; 14] }
+    }
+
+    #[test]
+    fn extensions_survive_rename_and_writes() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        fs.create_file("a").unwrap();
+        /* keep the slots after "a" in use, so growing the set must move it */
+        fs.create_file("b").unwrap();
+
+        fs.set_vendor_extension("a", &ext(1)).unwrap();
+        fs.set_vendor_extension("a", &ext(2)).unwrap();
+        let moved = fs.lookup("a").unwrap();
+        assert!(moved.index() > fs.lookup("b").unwrap().index());
+        assert_eq!(moved.vendor_extensions(), vec![ext(1), ext(2)]);
+
+        let mut replaced = ext(1);
+        replaced.data = [9; 14];
+        fs.set_vendor_extension("a", &replaced).unwrap();
+
+        fs.rename("a", "a name which needs two file name entries").unwrap();
+        let mut f = fs.open("a name which needs two file name entries").unwrap();
+        f.write_all(&[1; 3000]).unwrap();
+        let set = fs.lookup("a name which needs two file name entries").unwrap();
+        assert_eq!(set.vendor_extensions(), vec![replaced, ext(2)]);
+        assert_eq!(set.data_len(), 3000);
+
+        assert_eq!(fs.remove_vendor_extension("/a name which needs two file name entries",
+                                              &ext(1).guid).unwrap(), Some(replaced));
+        let set = fs.lookup("a name which needs two file name entries").unwrap();
+        assert_eq!(set.vendor_extensions(), vec![ext(2)]);
+        assert_eq!(set.set_checksum(), set.compute_checksum());
+    }
+
+    #[test]
+    fn allocations() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let free = fs.bitmap().free_count();
+        fs.create_file("f").unwrap();
+        let g = Guid([7; 16]);
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        fs.set_vendor_allocation("f", &g, 0x1234, &data).unwrap();
+        assert_eq!(fs.bitmap().free_count(), free - 3);
+        assert_eq!(fs.read_vendor_allocation("f", &g).unwrap(), Some(data));
+
+        let a = fs.lookup("f").unwrap().vendor_allocations();
+        assert_eq!(a.len(), 1);
+        assert_eq!((a[0].guid, a[0].vendor_defined, a[0].len()), (g, 0x1234, 1500));
+
+        /* replacing frees the old clusters */
+        fs.set_vendor_allocation("f", &g, 0, b"small").unwrap();
+        assert_eq!(fs.bitmap().free_count(), free - 1);
+        assert_eq!(fs.read_vendor_allocation("f", &g).unwrap(), Some(b"small".to_vec()));
+        assert_eq!(fs.read_vendor_allocation("f", &Guid([8; 16])).unwrap(), None);
+
+        assert!(fs.remove_vendor_allocation("f", &g).unwrap());
+        assert!(!fs.remove_vendor_allocation("f", &g).unwrap());
+        assert_eq!(fs.bitmap().free_count(), free);
+
+        /* removing the file frees them too */
+        fs.set_vendor_allocation("f", &g, 0, &[1; 600]).unwrap();
+        fs.remove_file("f").unwrap();
+        assert_eq!(fs.bitmap().free_count(), free);
+
+        /* a DataLength far past the end of the chain */
+        fs.create_file("f").unwrap();
+        fs.set_vendor_allocation("f", &g, 0, &[1; 600]).unwrap();
+        let (dir, mut set) = fs.locate("f").unwrap();
+        let i = set.find_vendor_entry(entry_type::VENDOR_ALLOCATION, &g).unwrap();
+        let mut e = set.entries()[i];
+        write_num_bytes!(u64, 8, &mut e.raw_mut()[24..], u64::MAX);
+        set.replace_secondary(i, e);
+        set.update_checksum();
+        dir.write_set(&set).unwrap();
+        assert!(matches!(fs.read_vendor_allocation("f", &g), Err(::Error::Corrupt { .. })));
+        /* which isn't freed (or removed) by replacing or removing it */
+        let free = fs.bitmap().free_count();
+        assert!(matches!(fs.set_vendor_allocation("f", &g, 0, b"new"), Err(::Error::Corrupt { .. })));
+        assert!(matches!(fs.remove_vendor_allocation("f", &g), Err(::Error::Corrupt { .. })));
+        assert_eq!(fs.bitmap().free_count(), free);
+        assert_eq!(fs.lookup("f").unwrap().vendor_allocations().len(), 1);
+    }
+}
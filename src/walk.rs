@@ -0,0 +1,231 @@
+use ::io_at::ReadAt;
+use ::std::collections::{HashSet,VecDeque};
+use super::{Dir,EntrySet,Error,Fs,Location,Result,Stream};
+use super::dir::MAX_DIR_LEN;
+use super::fs::{FileType,Metadata};
+
+/// The order in which `Walk` visits entries
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum WalkOrder {
+    /// Each directory's contents immediately follow the directory
+    DepthFirst,
+    /// Every entry at one depth is visited before any deeper entry
+    BreadthFirst,
+}
+
+/// An entry found by `Fs::walk()`
+#[derive(Clone,Debug)]
+pub struct WalkEntry {
+    path: String,
+    depth: usize,
+    metadata: Metadata,
+}
+
+impl WalkEntry {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Number of directories between this entry and the starting directory. Entries in the
+    /// starting directory have depth 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.metadata.file_type()
+    }
+}
+
+/// A recursive traversal of a directory tree. See `Fs::walk()`.
+///
+/// Problems reading a directory (including a directory that is reachable more than once, which
+/// would otherwise cause a cycle) are returned as errors naming the directory, and the walk
+/// carries on with the next entry.
+pub struct Walk<'a, S: ReadAt + 'a> {
+    fs: &'a Fs<S>,
+    order: WalkOrder,
+    /// Entries not yet returned, each with it's path, depth, and the directory containing it
+    pending: VecDeque<Result<(String, usize, Stream, EntrySet)>>,
+    /// Every cluster belonging to a directory that has been (or is being) walked
+    visited: HashSet<u32>,
+}
+
+impl<'a, S: ReadAt + 'a> Walk<'a, S> {
+    /// Visit entries in `order` instead of the default `DepthFirst`
+    pub fn order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Record the clusters of the directory at `stream`, failing if any of them belong to a
+    /// directory that was already visited or the cluster chain is broken
+    fn visit(&mut self, stream: &Stream) -> Result<()> {
+        let cs = self.fs.boot_sector().bytes_per_cluster();
+        if stream.data_len > MAX_DIR_LEN {
+            return Err(Error::corrupt_at("directory is larger than the maximum directory size",
+                                         Location::Cluster(stream.first_cluster)));
+        }
+
+        let n = stream.data_len.div_ceil(cs) as u32;
+        let clusters: Vec<u32> = if stream.no_fat_chain {
+            (0..n).map(|i| stream.first_cluster.wrapping_add(i)).collect()
+        } else {
+            let mut v = Vec::with_capacity(n as usize);
+            for c in self.fs.fat().chain(stream.first_cluster).take(n as usize) {
+                match c {
+                    Ok(c) => v.push(c.val()),
+                    Err(_) => break,
+                }
+            }
+            v
+        };
+
+        if clusters.len() < n as usize
+            || clusters.iter().any(|c| !self.fs.boot_sector().is_valid_cluster(*c)) {
+            return Err(Error::corrupt_at("directory cluster chain is broken",
+                                         Location::Cluster(stream.first_cluster)));
+        }
+        for c in clusters {
+            if !self.visited.insert(c) {
+                return Err(Error::corrupt_at("directory cycle: a cluster is used by more than one directory",
+                                             Location::Cluster(c)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue the contents of `dir`, whose path is `path` and depth is `depth`
+    fn push_dir(&mut self, path: &str, depth: usize, dir: Dir<'a, S>) {
+        let stream = dir.stream();
+        let items: Vec<_> = dir.entry_sets().filter_map(|set| match set {
+            Ok(set) => {
+                /* only File entry sets have names, the others (bitmap, label, ...) are skipped */
+                if set.is_file() {
+                    let p = format!("{}/{}", path.trim_end_matches('/'), set.name());
+                    Some(Ok((p, depth + 1, stream, set)))
+                } else {
+                    None
+                }
+            },
+            Err(e) => Some(Err(with_path(path, e))),
+        }).collect();
+
+        self.queue(items);
+    }
+
+    /// Queue the contents of a directory (or errors reading it) according to the walk order
+    fn queue(&mut self, mut items: Vec<Result<(String, usize, Stream, EntrySet)>>) {
+        match self.order {
+            WalkOrder::DepthFirst => {
+                while let Some(i) = items.pop() {
+                    self.pending.push_front(i);
+                }
+            },
+            WalkOrder::BreadthFirst => self.pending.extend(items),
+        }
+    }
+}
+
+fn with_path(path: &str, e: Error) -> Error {
+    Error::Path { path: path.to_owned(), error: Box::new(e) }
+}
+
+impl<'a, S: ReadAt + 'a> Iterator for Walk<'a, S> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, depth, parent, set) = match self.pending.pop_front()? {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if set.is_dir() {
+            /* the directory itself is still returned if it's contents can't be read */
+            let r = Dir::from_set(self.fs, parent, set.clone())
+                .and_then(|d| self.visit(&d.stream()).map(|_| d));
+            match r {
+                Ok(dir) => self.push_dir(&path, depth, dir),
+                Err(e) => self.queue(vec![Err(with_path(&path, e))]),
+            }
+        }
+
+        Some(Ok(WalkEntry { path, depth, metadata: Metadata::from_set(set) }))
+    }
+}
+
+impl<S: ReadAt> Fs<S> {
+    /// Walk the tree of files & directories below the directory at `path` (which itself is not
+    /// returned), depth first unless changed with `Walk::order()`.
+    pub fn walk(&self, path: &str) -> Result<Walk<'_, S>> {
+        let dir = self.open_dir(path)?;
+        let mut w = Walk {
+            fs: self,
+            order: WalkOrder::DepthFirst,
+            pending: VecDeque::new(),
+            visited: HashSet::new(),
+        };
+        w.visit(&dir.stream())?;
+        w.push_dir(path, 0, dir);
+        Ok(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::testutil::Image;
+
+    fn paths(w: Walk<'_, &mut Vec<u8>>) -> Vec<String> {
+        w.map(|e| e.map(|e| e.path().to_owned()).unwrap_or_else(|e| format!("error {}", e)))
+            .collect()
+    }
+
+    #[test]
+    fn orders() {
+        let mut img = Image::new();
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        ::fs::create_dir_all(&fs, "a/b").unwrap();
+        ::fs::create_dir_all(&fs, "c").unwrap();
+        ::fs::write(&fs, "a/f", b"").unwrap();
+        ::fs::write(&fs, "a/b/g", b"").unwrap();
+
+        assert_eq!(paths(fs.walk("/").unwrap()), vec!["/a", "/a/b", "/a/b/g", "/a/f", "/c"]);
+        assert_eq!(paths(fs.walk("").unwrap().order(WalkOrder::BreadthFirst)),
+                   vec!["/a", "/c", "/a/b", "/a/f", "/a/b/g"]);
+        let w: Vec<_> = fs.walk("a").unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(w.iter().map(|e| (e.path(), e.depth())).collect::<Vec<_>>(),
+                   vec![("a/b", 1), ("a/b/g", 2), ("a/f", 1)]);
+        assert!(w[0].file_type().is_dir() && w[1].file_type().is_file());
+    }
+
+    #[test]
+    fn cycles_and_corruption() {
+        let mut img = Image::new();
+        let cs = img.cluster_size();
+        let a = img.alloc(cs, true);
+        let set = Image::file_set("a", 0x10, 0b11, a[0], cs, cs);
+        img.push_root(&set);
+        img.push_root(&Image::file_set("bad", 0x10, 0b11, 50_000, cs, cs));
+        img.push_root(&Image::file_set("z", 0x20, 0, 0, 0, 0));
+
+        /* "a" contains a directory which points back at "a" */
+        let mut inner = Image::file_set("loop", 0x10, 0b11, a[0], cs, cs);
+        inner.extend(Image::file_set("file", 0x20, 0, 0, 0, 0));
+        let raw: Vec<u8> = inner.iter().flat_map(|e| e.iter().cloned()).collect();
+        img.write_clusters(&a, &raw);
+
+        let fs = Fs::from_rw(&mut img.data).unwrap();
+        let p = paths(fs.walk("").unwrap());
+        assert_eq!(p.len(), 7);
+        assert_eq!(&p[..2], &["/a", "/a/loop"]);
+        assert!(p[2].starts_with("error /a/loop: ") && p[2].contains("cycle"), "{}", p[2]);
+        assert_eq!(&p[3..5], &["/a/file", "/bad"]);
+        assert!(p[5].starts_with("error /bad: "), "{}", p[5]);
+        assert_eq!(p[6], "/z");
+    }
+}